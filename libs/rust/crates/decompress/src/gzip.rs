@@ -0,0 +1,135 @@
+use alloc::{boxed::Box, vec::Vec};
+
+use miniz_oxide::{
+    inflate::stream::{inflate, InflateState},
+    DataFormat, MZError, MZFlush, MZStatus,
+};
+
+use crate::DecodeError;
+
+const MAGIC: [u8; 2] = [0x1F, 0x8B];
+const METHOD_DEFLATE: u8 = 8;
+const FIXED_HEADER_LEN: usize = 10;
+/// Room for a file name and comment, which is all gzip tools put in.
+const MAX_HEADER_LEN: usize = 1024;
+/// The CRC-32 and size of the uncompressed data.
+const TRAILER_LEN: usize = 8;
+
+const FLAG_HEADER_CRC: u8 = 1 << 1;
+const FLAG_EXTRA: u8 = 1 << 2;
+const FLAG_NAME: u8 = 1 << 3;
+const FLAG_COMMENT: u8 = 1 << 4;
+
+/// Decodes a single gzip member. The trailer's CRC-32 isn't checked, uploads
+/// are checked as they come in.
+pub struct GzipDecoder {
+    state: State,
+}
+
+enum State {
+    Header(Vec<u8>),
+    /// Boxed, as it holds the 32 KiB window.
+    Deflate(Box<InflateState>),
+    Trailer(usize),
+    Done,
+}
+
+impl GzipDecoder {
+    pub fn new() -> Self {
+        Self {
+            state: State::Header(Vec::new()),
+        }
+    }
+
+    pub fn decode(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+    ) -> Result<(usize, usize), DecodeError> {
+        let mut consumed = 0;
+        let mut produced = 0;
+        loop {
+            let input = &input[consumed..];
+            match &mut self.state {
+                State::Header(header) => {
+                    // Taken a byte at a time, so none of the deflate stream is.
+                    let Some(&byte) = input.first() else {
+                        break;
+                    };
+                    header.push(byte);
+                    consumed += 1;
+                    if header_len(header)?.is_some() {
+                        self.state = State::Deflate(InflateState::new_boxed(DataFormat::Raw));
+                    }
+                }
+                State::Deflate(inflater) => {
+                    let result = inflate(inflater, input, &mut output[produced..], MZFlush::None);
+                    consumed += result.bytes_consumed;
+                    produced += result.bytes_written;
+                    match result.status {
+                        Ok(MZStatus::StreamEnd) => self.state = State::Trailer(TRAILER_LEN),
+                        Ok(_) | Err(MZError::Buf) => break,
+                        Err(_) => return Err(DecodeError::Corrupt),
+                    }
+                }
+                State::Trailer(remaining) => {
+                    let len = input.len().min(*remaining);
+                    consumed += len;
+                    *remaining -= len;
+                    if *remaining > 0 {
+                        break;
+                    }
+                    self.state = State::Done;
+                }
+                State::Done => break,
+            }
+        }
+        Ok((consumed, produced))
+    }
+}
+
+impl Default for GzipDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The length of `header` once it holds all of it.
+fn header_len(header: &[u8]) -> Result<Option<usize>, DecodeError> {
+    if header.len() > MAX_HEADER_LEN {
+        return Err(DecodeError::HeaderTooLong);
+    }
+    if header.len() >= MAGIC.len() && header[..MAGIC.len()] != MAGIC {
+        return Err(DecodeError::NotGzip);
+    }
+    if header.len() > 2 && header[2] != METHOD_DEFLATE {
+        return Err(DecodeError::UnsupportedMethod);
+    }
+    if header.len() < FIXED_HEADER_LEN {
+        return Ok(None);
+    }
+
+    let flags = header[3];
+    let mut len = FIXED_HEADER_LEN;
+    if flags & FLAG_EXTRA != 0 {
+        let Some(extra_len) = header.get(len..len + 2) else {
+            return Ok(None);
+        };
+        len += 2 + usize::from(u16::from_le_bytes([extra_len[0], extra_len[1]]));
+    }
+    for flag in [FLAG_NAME, FLAG_COMMENT] {
+        if flags & flag != 0 {
+            let Some(end) = header
+                .get(len..)
+                .and_then(|rest| rest.iter().position(|&byte| byte == 0))
+            else {
+                return Ok(None);
+            };
+            len += end + 1;
+        }
+    }
+    if flags & FLAG_HEADER_CRC != 0 {
+        len += 2;
+    }
+    Ok((header.len() >= len).then_some(len))
+}
@@ -0,0 +1,120 @@
+use alloc::{boxed::Box, vec};
+
+/// The window and lookahead heatshrink's own tools default to. The stream
+/// doesn't say which ones it was encoded with, so uploads have to use these.
+pub const HEATSHRINK_WINDOW_BITS: u8 = 11;
+pub const HEATSHRINK_LOOKAHEAD_BITS: u8 = 4;
+
+const WINDOW_LEN: usize = 1 << HEATSHRINK_WINDOW_BITS;
+
+/// Decodes heatshrink's LZSS: a `1` bit followed by a literal byte, or a `0`
+/// bit followed by how far back and how many bytes to repeat, each one less
+/// than it is. Bits are read most significant first.
+pub struct HeatshrinkDecoder {
+    /// The last bytes decoded, starting out as zeros.
+    window: Box<[u8]>,
+    head: usize,
+    /// Bits read but not used yet, the oldest ones highest.
+    bits: u32,
+    bit_count: u8,
+    state: State,
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Tag,
+    Literal,
+    Index,
+    Count { distance: usize },
+    Copy { distance: usize, remaining: usize },
+}
+
+impl HeatshrinkDecoder {
+    pub fn new() -> Self {
+        Self {
+            window: vec![0; WINDOW_LEN].into_boxed_slice(),
+            head: 0,
+            bits: 0,
+            bit_count: 0,
+            state: State::Tag,
+        }
+    }
+
+    /// Decodes as much of `input` as fits into `output`, returning how many
+    /// bytes it took from `input` and how many it put into `output`.
+    pub fn decode(&mut self, input: &[u8], output: &mut [u8]) -> (usize, usize) {
+        let mut consumed = 0;
+        let mut produced = 0;
+        loop {
+            if let State::Copy {
+                distance,
+                remaining,
+            } = &mut self.state
+            {
+                while *remaining > 0 && produced < output.len() {
+                    let byte = self.window[(self.head + WINDOW_LEN - *distance) % WINDOW_LEN];
+                    output[produced] = byte;
+                    produced += 1;
+                    self.window[self.head] = byte;
+                    self.head = (self.head + 1) % WINDOW_LEN;
+                    *remaining -= 1;
+                }
+                if *remaining > 0 {
+                    break;
+                }
+                self.state = State::Tag;
+                continue;
+            }
+            // A literal is only read once there is room for it.
+            if matches!(self.state, State::Literal) && produced == output.len() {
+                break;
+            }
+
+            let wanted = match self.state {
+                State::Tag => 1,
+                State::Literal => 8,
+                State::Index => HEATSHRINK_WINDOW_BITS,
+                State::Count { .. } => HEATSHRINK_LOOKAHEAD_BITS,
+                State::Copy { .. } => unreachable!(),
+            };
+            while self.bit_count < wanted {
+                let Some(&byte) = input.get(consumed) else {
+                    return (consumed, produced);
+                };
+                consumed += 1;
+                self.bits = (self.bits << 8) | u32::from(byte);
+                self.bit_count += 8;
+            }
+            self.bit_count -= wanted;
+            let value = (self.bits >> self.bit_count) as usize & ((1 << wanted) - 1);
+
+            self.state = match self.state {
+                State::Tag if value == 1 => State::Literal,
+                State::Tag => State::Index,
+                State::Literal => {
+                    let byte = value as u8;
+                    output[produced] = byte;
+                    produced += 1;
+                    self.window[self.head] = byte;
+                    self.head = (self.head + 1) % WINDOW_LEN;
+                    State::Tag
+                }
+                State::Index => State::Count {
+                    distance: value + 1,
+                },
+                State::Count { distance } => State::Copy {
+                    distance,
+                    remaining: value + 1,
+                },
+                State::Copy { .. } => unreachable!(),
+            };
+        }
+        (consumed, produced)
+    }
+}
+
+impl Default for HeatshrinkDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,99 @@
+//! Decoders for G-code uploaded compressed, fed the stored bytes in pieces of
+//! any size and holding no more than their window in memory: gzip's 32 KiB
+//! and heatshrink's `2^HEATSHRINK_WINDOW_BITS` bytes.
+
+#![no_std]
+
+extern crate alloc;
+
+mod gzip;
+mod heatshrink;
+
+pub use gzip::GzipDecoder;
+pub use heatshrink::{HeatshrinkDecoder, HEATSHRINK_LOOKAHEAD_BITS, HEATSHRINK_WINDOW_BITS};
+
+/// How a file is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Heatshrink,
+}
+
+impl Encoding {
+    /// From a `Content-Encoding` header, `None` for encodings there is no
+    /// decoder for.
+    pub fn from_content_encoding(value: &str) -> Option<Self> {
+        match value.trim() {
+            value if value.eq_ignore_ascii_case("identity") => Some(Self::Identity),
+            value if value.eq_ignore_ascii_case("gzip") => Some(Self::Gzip),
+            value if value.eq_ignore_ascii_case("x-gzip") => Some(Self::Gzip),
+            value if value.eq_ignore_ascii_case("heatshrink") => Some(Self::Heatshrink),
+            _ => None,
+        }
+    }
+
+    /// From the extension of a file name, `.gz` or `.hs`.
+    pub fn from_file_name(name: &str) -> Self {
+        match name.rsplit_once('.') {
+            Some((_, extension)) if extension.eq_ignore_ascii_case("gz") => Self::Gzip,
+            Some((_, extension)) if extension.eq_ignore_ascii_case("hs") => Self::Heatshrink,
+            _ => Self::Identity,
+        }
+    }
+
+    /// The extension a file stored this way is given, with its `.`.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Identity => "",
+            Self::Gzip => ".gz",
+            Self::Heatshrink => ".hs",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    NotGzip,
+    UnsupportedMethod,
+    HeaderTooLong,
+    Corrupt,
+}
+
+/// Decodes a stream of any [`Encoding`].
+pub enum Decoder {
+    Identity,
+    Gzip(GzipDecoder),
+    Heatshrink(HeatshrinkDecoder),
+}
+
+impl Decoder {
+    pub fn new(encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::Identity => Self::Identity,
+            Encoding::Gzip => Self::Gzip(GzipDecoder::new()),
+            Encoding::Heatshrink => Self::Heatshrink(HeatshrinkDecoder::new()),
+        }
+    }
+
+    /// Decodes as much of `input` as fits into `output`, returning how many
+    /// bytes it took from `input` and how many it put into `output`.
+    ///
+    /// Taking and putting nothing while there is room in `output` means the
+    /// stream ended, or that `input` was empty.
+    pub fn decode(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+    ) -> Result<(usize, usize), DecodeError> {
+        match self {
+            Self::Identity => {
+                let len = input.len().min(output.len());
+                output[..len].copy_from_slice(&input[..len]);
+                Ok((len, len))
+            }
+            Self::Gzip(decoder) => decoder.decode(input, output),
+            Self::Heatshrink(decoder) => Ok(decoder.decode(input, output)),
+        }
+    }
+}
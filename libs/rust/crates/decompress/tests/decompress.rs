@@ -0,0 +1,195 @@
+use decompress::{
+    DecodeError, Decoder, Encoding, HEATSHRINK_LOOKAHEAD_BITS, HEATSHRINK_WINDOW_BITS,
+};
+use miniz_oxide::deflate::compress_to_vec;
+
+/// Feeds `encoded` in pieces of `input_len` and takes the output in pieces
+/// of `output_len`, as reading a file block by block does.
+fn decode(
+    encoding: Encoding,
+    encoded: &[u8],
+    input_len: usize,
+    output_len: usize,
+) -> Result<Vec<u8>, DecodeError> {
+    let mut decoder = Decoder::new(encoding);
+    let mut decoded = Vec::new();
+    let mut output = vec![0; output_len];
+    for mut input in encoded.chunks(input_len) {
+        loop {
+            let (consumed, produced) = decoder.decode(input, &mut output)?;
+            decoded.extend_from_slice(&output[..produced]);
+            input = &input[consumed..];
+            if consumed == 0 && produced < output_len {
+                break;
+            }
+        }
+    }
+    Ok(decoded)
+}
+
+fn gcode() -> Vec<u8> {
+    (0..3000)
+        .map(|index| {
+            format!(
+                "G1 X{} Y{} E{:.4}\n",
+                index % 220,
+                index % 37,
+                index as f32 * 0.03
+            )
+        })
+        .collect::<String>()
+        .into_bytes()
+}
+
+fn gzip(data: &[u8], header: &[u8]) -> Vec<u8> {
+    let mut encoded = header.to_vec();
+    encoded.extend(compress_to_vec(data, 9));
+    // The CRC-32 isn't checked.
+    encoded.extend([0; 4]);
+    encoded.extend((data.len() as u32).to_le_bytes());
+    encoded
+}
+
+/// A greedy encoder, to have something to decode.
+fn heatshrink(data: &[u8]) -> Vec<u8> {
+    let window = 1 << HEATSHRINK_WINDOW_BITS;
+    let lookahead = 1 << HEATSHRINK_LOOKAHEAD_BITS;
+    let mut bits = Vec::new();
+    let mut push = |value: usize, count: u8| {
+        for bit in (0..count).rev() {
+            bits.push((value >> bit) & 1 == 1);
+        }
+    };
+
+    let mut position = 0;
+    while position < data.len() {
+        let (distance, len) = (1..=window.min(position))
+            .map(|distance| {
+                let len = (0..lookahead.min(data.len() - position))
+                    .take_while(|&index| {
+                        data[position + index] == data[position + index - distance]
+                    })
+                    .count();
+                (distance, len)
+            })
+            .max_by_key(|&(_, len)| len)
+            .unwrap_or((0, 0));
+        if len >= 2 {
+            push(0, 1);
+            push(distance - 1, HEATSHRINK_WINDOW_BITS);
+            push(len - 1, HEATSHRINK_LOOKAHEAD_BITS);
+            position += len;
+        } else {
+            push(1, 1);
+            push(usize::from(data[position]), 8);
+            position += 1;
+        }
+    }
+
+    bits.chunks(8)
+        .map(|byte| {
+            (0..8).fold(0, |value, index| {
+                value << 1 | u8::from(byte.get(index).copied().unwrap_or(false))
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn tells_encodings_from_headers_and_names() {
+    assert_eq!(
+        Encoding::from_content_encoding("gzip"),
+        Some(Encoding::Gzip)
+    );
+    assert_eq!(
+        Encoding::from_content_encoding(" GZIP"),
+        Some(Encoding::Gzip)
+    );
+    assert_eq!(
+        Encoding::from_content_encoding("heatshrink"),
+        Some(Encoding::Heatshrink)
+    );
+    assert_eq!(Encoding::from_content_encoding("br"), None);
+
+    assert_eq!(Encoding::from_file_name("benchy.gcode.gz"), Encoding::Gzip);
+    assert_eq!(
+        Encoding::from_file_name("benchy.gcode.HS"),
+        Encoding::Heatshrink
+    );
+    assert_eq!(Encoding::from_file_name("benchy.gcode"), Encoding::Identity);
+    assert_eq!(Encoding::from_file_name("gz"), Encoding::Identity);
+}
+
+#[test]
+fn decodes_gzip_in_pieces() {
+    let data = gcode();
+    let encoded = gzip(&data, &[0x1F, 0x8B, 8, 0, 0, 0, 0, 0, 0, 0xFF]);
+    assert!(encoded.len() < data.len() / 3);
+
+    for (input_len, output_len) in [(encoded.len(), data.len()), (7, 13), (1, 4096), (512, 1)] {
+        assert_eq!(
+            decode(Encoding::Gzip, &encoded, input_len, output_len),
+            Ok(data.clone())
+        );
+    }
+}
+
+#[test]
+fn skips_gzip_names_comments_and_extra_fields() {
+    let data = gcode();
+    let mut header = vec![0x1F, 0x8B, 8, 0b11110, 0, 0, 0, 0, 0, 3, 3, 0, 1, 2, 3];
+    header.extend(b"benchy.gcode\0a comment\0\x12\x34");
+
+    let encoded = gzip(&data, &header);
+
+    assert_eq!(decode(Encoding::Gzip, &encoded, 5, 100), Ok(data));
+}
+
+#[test]
+fn rejects_what_is_not_gzip() {
+    assert_eq!(
+        decode(Encoding::Gzip, b"G28\nG1 X10\n", 100, 100),
+        Err(DecodeError::NotGzip)
+    );
+    assert_eq!(
+        decode(
+            Encoding::Gzip,
+            &[0x1F, 0x8B, 7, 0, 0, 0, 0, 0, 0, 0],
+            100,
+            100
+        ),
+        Err(DecodeError::UnsupportedMethod)
+    );
+}
+
+#[test]
+fn decodes_heatshrink_literals_and_backreferences() {
+    // `a`, then 3 bytes from 1 back.
+    let encoded = [0b1011_0000, 0b1000_0000, 0b0000_0001, 0b0000_0000];
+
+    assert_eq!(
+        decode(Encoding::Heatshrink, &encoded, 1, 1),
+        Ok(b"aaaa".to_vec())
+    );
+}
+
+#[test]
+fn decodes_heatshrink_in_pieces() {
+    let data = gcode();
+    let encoded = heatshrink(&data);
+    assert!(encoded.len() < data.len() / 2);
+
+    for (input_len, output_len) in [(encoded.len(), data.len()), (7, 13), (1, 4096), (512, 1)] {
+        assert_eq!(
+            decode(Encoding::Heatshrink, &encoded, input_len, output_len),
+            Ok(data.clone())
+        );
+    }
+}
+
+#[test]
+fn passes_identity_through() {
+    let data = gcode();
+
+    assert_eq!(decode(Encoding::Identity, &data, 100, 33), Ok(data));
+}
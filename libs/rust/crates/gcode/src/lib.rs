@@ -0,0 +1,16 @@
+//! A zero-alloc G-code tokenizer, covering what slicers and hosts send to
+//! Marlin: words, line numbers, checksums, `;` and `()` comments, the
+//! string parameters of commands like `M117` and the thumbnails slicers
+//! embed in comments.
+
+#![no_std]
+
+mod line;
+mod thumbnail;
+mod tokenizer;
+
+pub use line::{Executable, Line};
+pub use thumbnail::{
+    is_thumbnail_end, thumbnail_data, Base64Decoder, InvalidBase64, ThumbnailBegin, ThumbnailFormat,
+};
+pub use tokenizer::{checksum, Checksum, Error, Token, Tokenizer, Word};
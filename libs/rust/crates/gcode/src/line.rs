@@ -0,0 +1,103 @@
+use core::fmt;
+
+use crate::{Checksum, Error, Token, Tokenizer, Word};
+
+/// A line of G-code that is known to tokenize without errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Line<'a> {
+    raw: &'a str,
+}
+
+impl<'a> Line<'a> {
+    pub fn parse(raw: &'a str) -> Result<Self, Error> {
+        for token in Tokenizer::new(raw) {
+            token?;
+        }
+        Ok(Self { raw })
+    }
+
+    pub fn raw(&self) -> &'a str {
+        self.raw
+    }
+
+    pub fn tokens(&self) -> impl Iterator<Item = Token<'a>> {
+        Tokenizer::new(self.raw).map_while(Result::ok)
+    }
+
+    pub fn line_number(&self) -> Option<u32> {
+        self.tokens().find_map(|token| match token {
+            Token::LineNumber(number) => Some(number),
+            _ => None,
+        })
+    }
+
+    pub fn checksum(&self) -> Option<Checksum> {
+        self.tokens().find_map(|token| match token {
+            Token::Checksum(checksum) => Some(checksum),
+            _ => None,
+        })
+    }
+
+    /// The command followed by its parameters.
+    pub fn words(&self) -> impl Iterator<Item = Word<'a>> {
+        self.tokens().filter_map(|token| match token {
+            Token::Word(word) => Some(word),
+            _ => None,
+        })
+    }
+
+    pub fn command(&self) -> Option<Word<'a>> {
+        self.words().next()
+    }
+
+    pub fn parameter(&self, letter: char) -> Option<Word<'a>> {
+        let letter = letter.to_ascii_uppercase();
+        self.words().skip(1).find(|word| word.letter == letter)
+    }
+
+    /// The text parameter of commands like `M117`.
+    pub fn string(&self) -> Option<&'a str> {
+        self.tokens().find_map(|token| match token {
+            Token::String(string) => Some(string),
+            _ => None,
+        })
+    }
+
+    pub fn comments(&self) -> impl Iterator<Item = &'a str> {
+        self.tokens().filter_map(|token| match token {
+            Token::Comment(comment) => Some(comment),
+            _ => None,
+        })
+    }
+
+    /// Whether there is nothing to execute, as on blank and comment-only
+    /// lines.
+    pub fn is_empty(&self) -> bool {
+        self.command().is_none()
+    }
+
+    /// The line without its line number, checksum and comments.
+    pub fn executable(&self) -> Executable<'a> {
+        Executable(*self)
+    }
+}
+
+/// Displays what the printer executes of a [`Line`], with single spaces
+/// between words.
+#[derive(Debug, Clone, Copy)]
+pub struct Executable<'a>(Line<'a>);
+
+impl<'a> fmt::Display for Executable<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut separator = "";
+        for token in self.0.tokens() {
+            match token {
+                Token::Word(word) => write!(f, "{separator}{word}")?,
+                Token::String(string) => write!(f, "{separator}{string}")?,
+                _ => continue,
+            }
+            separator = " ";
+        }
+        Ok(())
+    }
+}
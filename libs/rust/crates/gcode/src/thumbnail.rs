@@ -0,0 +1,121 @@
+/// The image formats slicers embed previews in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThumbnailFormat {
+    Png,
+    Jpg,
+    Qoi,
+}
+
+/// The line that starts a thumbnail block, like PrusaSlicer's
+/// `; thumbnail begin 300x300 12564` or `; thumbnail_QOI begin 16x16 412`.
+/// The lines up to `; thumbnail end` hold the image in base64.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThumbnailBegin {
+    pub format: ThumbnailFormat,
+    pub width: u32,
+    pub height: u32,
+    /// The length of the base64 text.
+    pub encoded_len: usize,
+}
+
+impl ThumbnailBegin {
+    pub fn parse(line: &str) -> Option<Self> {
+        let (format, rest) = thumbnail_keyword(line)?;
+        let mut words = rest.split_whitespace();
+        if words.next() != Some("begin") {
+            return None;
+        }
+        let (width, height) = words.next()?.split_once('x')?;
+        Some(Self {
+            format,
+            width: width.parse().ok()?,
+            height: height.parse().ok()?,
+            encoded_len: words.next()?.parse().ok()?,
+        })
+    }
+
+    /// How long the image is at most once decoded.
+    pub fn decoded_len(&self) -> usize {
+        self.encoded_len / 4 * 3
+    }
+}
+
+/// Whether `line` ends a thumbnail block.
+pub fn is_thumbnail_end(line: &str) -> bool {
+    thumbnail_keyword(line).is_some_and(|(_, rest)| rest.trim() == "end")
+}
+
+/// The base64 text of a line inside a thumbnail block.
+pub fn thumbnail_data(line: &str) -> Option<&str> {
+    Some(line.trim().strip_prefix(';')?.trim())
+}
+
+/// Splits `; thumbnail_JPG begin ...` into its format and `begin ...`.
+fn thumbnail_keyword(line: &str) -> Option<(ThumbnailFormat, &str)> {
+    let comment = line.trim().strip_prefix(';')?.trim_start();
+    let rest = comment.strip_prefix("thumbnail")?;
+    let (format, rest) = match rest.strip_prefix('_') {
+        Some(rest) => {
+            let (format, rest) = rest.split_once(char::is_whitespace)?;
+            let format = match format {
+                "PNG" => ThumbnailFormat::Png,
+                "JPG" => ThumbnailFormat::Jpg,
+                "QOI" => ThumbnailFormat::Qoi,
+                _ => return None,
+            };
+            (format, rest)
+        }
+        None => (
+            ThumbnailFormat::Png,
+            rest.strip_prefix(char::is_whitespace)?,
+        ),
+    };
+    Some((format, rest.trim_start()))
+}
+
+/// Decodes base64 fed in pieces of any length, like the lines of a
+/// thumbnail block.
+#[derive(Debug, Default)]
+pub struct Base64Decoder {
+    bits: u32,
+    bit_count: u8,
+    padded: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidBase64;
+
+impl Base64Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hands each decoded byte to `output`.
+    pub fn push(&mut self, text: &str, mut output: impl FnMut(u8)) -> Result<(), InvalidBase64> {
+        for byte in text.bytes() {
+            let value = match byte {
+                b'A'..=b'Z' => byte - b'A',
+                b'a'..=b'z' => byte - b'a' + 26,
+                b'0'..=b'9' => byte - b'0' + 52,
+                b'+' => 62,
+                b'/' => 63,
+                b'=' => {
+                    self.padded = true;
+                    continue;
+                }
+                byte if byte.is_ascii_whitespace() => continue,
+                _ => return Err(InvalidBase64),
+            };
+            if self.padded {
+                return Err(InvalidBase64);
+            }
+            self.bits = (self.bits << 6) | u32::from(value);
+            self.bit_count += 6;
+            if self.bit_count >= 8 {
+                self.bit_count -= 8;
+                output((self.bits >> self.bit_count) as u8);
+            }
+        }
+        Ok(())
+    }
+}
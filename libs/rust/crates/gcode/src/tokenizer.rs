@@ -0,0 +1,189 @@
+use core::{fmt, str::FromStr};
+
+/// Commands whose parameter is the rest of the line, like the message of
+/// `M117` or the file name of `M23`.
+const STRING_COMMANDS: [u32; 8] = [23, 28, 30, 32, 33, 117, 118, 928];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Token<'a> {
+    /// The `N` number a host puts in front of a line.
+    LineNumber(u32),
+    Word(Word<'a>),
+    /// The text parameter of a command like `M117`.
+    String(&'a str),
+    /// A `;` or `()` comment, without its delimiters.
+    Comment(&'a str),
+    Checksum(Checksum),
+}
+
+/// A letter and the value right after it, like `G1` or `X10.5`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Word<'a> {
+    /// Always upper case.
+    pub letter: char,
+    /// Empty for flags like the `X` of `G28 X`.
+    pub value: &'a str,
+}
+
+impl<'a> Word<'a> {
+    pub fn value<T: FromStr>(&self) -> Option<T> {
+        self.value.parse().ok()
+    }
+
+    /// Whether this is `command`, so that `G01` is `G1`.
+    pub fn is(&self, command: &str) -> bool {
+        let mut chars = command.chars();
+        let Some(letter) = chars.next() else {
+            return false;
+        };
+        let value = chars.as_str();
+        letter.eq_ignore_ascii_case(&self.letter)
+            && (self.value == value
+                || matches!(
+                    (self.value::<f32>(), value.parse::<f32>()),
+                    (Some(a), Ok(b)) if a == b
+                ))
+    }
+
+    fn takes_string(&self) -> bool {
+        self.letter == 'M'
+            && self
+                .value::<u32>()
+                .is_some_and(|number| STRING_COMMANDS.contains(&number))
+    }
+}
+
+impl<'a> fmt::Display for Word<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.letter, self.value)
+    }
+}
+
+/// The checksum after the `*` of a line and the one its content adds up to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checksum {
+    pub received: u8,
+    pub computed: u8,
+}
+
+impl Checksum {
+    pub fn is_valid(&self) -> bool {
+        self.received == self.computed
+    }
+}
+
+/// Marlin's checksum, the XOR of every byte before the `*`.
+pub fn checksum(line: &str) -> u8 {
+    line.bytes().fold(0, |checksum, byte| checksum ^ byte)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    InvalidLineNumber,
+    InvalidChecksum,
+    UnterminatedComment,
+    UnexpectedCharacter(char),
+}
+
+/// Splits a single line of G-code into [`Token`]s, stopping at the first
+/// error.
+pub struct Tokenizer<'a> {
+    line: &'a str,
+    position: usize,
+    seen_word: bool,
+    expects_string: bool,
+    failed: bool,
+}
+
+impl<'a> Tokenizer<'a> {
+    pub fn new(line: &'a str) -> Self {
+        Self {
+            line,
+            position: 0,
+            seen_word: false,
+            expects_string: false,
+            failed: false,
+        }
+    }
+
+    fn next_token(&mut self) -> Option<Result<Token<'a>, Error>> {
+        let rest = &self.line[self.position..];
+        let trimmed = rest.trim_start();
+        self.position += rest.len() - trimmed.len();
+        let start = self.position;
+        let first = trimmed.chars().next()?;
+
+        if core::mem::take(&mut self.expects_string) && !matches!(first, ';' | '*') {
+            let end = trimmed.find([';', '*']).unwrap_or(trimmed.len());
+            self.position += end;
+            return Some(Ok(Token::String(trimmed[..end].trim_end())));
+        }
+
+        let token = match first {
+            ';' => {
+                self.position = self.line.len();
+                Ok(Token::Comment(trimmed[1..].trim_end()))
+            }
+            '(' => {
+                let end = trimmed.find(')')?;
+                self.position += end + 1;
+                Ok(Token::Comment(&trimmed[1..end]))
+            }
+            '*' => {
+                let digits = &trimmed[1..];
+                let end = digits
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(digits.len());
+                self.position += 1 + end;
+                digits[..end]
+                    .parse()
+                    .map(|received| {
+                        Token::Checksum(Checksum {
+                            received,
+                            computed: checksum(&self.line[..start]),
+                        })
+                    })
+                    .map_err(|_| Error::InvalidChecksum)
+            }
+            letter if letter.is_ascii_alphabetic() => {
+                let end = trimmed
+                    .find(|c: char| c.is_whitespace() || matches!(c, ';' | '(' | '*'))
+                    .unwrap_or(trimmed.len());
+                self.position += end;
+                let word = Word {
+                    letter: letter.to_ascii_uppercase(),
+                    value: &trimmed[1..end],
+                };
+
+                if word.letter == 'N' && !self.seen_word {
+                    word.value()
+                        .map(Token::LineNumber)
+                        .ok_or(Error::InvalidLineNumber)
+                } else {
+                    self.expects_string = !self.seen_word && word.takes_string();
+                    self.seen_word = true;
+                    Ok(Token::Word(word))
+                }
+            }
+            other => Err(Error::UnexpectedCharacter(other)),
+        };
+        Some(token)
+    }
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = Result<Token<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let token = match self.next_token() {
+            // Only an unterminated `(` leaves text behind.
+            None if self.position < self.line.len() => Some(Err(Error::UnterminatedComment)),
+            token => token,
+        };
+        self.failed = matches!(token, Some(Err(_)));
+        token
+    }
+}
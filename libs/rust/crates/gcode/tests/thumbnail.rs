@@ -0,0 +1,76 @@
+use gcode::{
+    is_thumbnail_end, thumbnail_data, Base64Decoder, InvalidBase64, ThumbnailBegin, ThumbnailFormat,
+};
+
+fn decode(pieces: &[&str]) -> Result<Vec<u8>, InvalidBase64> {
+    let mut decoder = Base64Decoder::new();
+    let mut decoded = Vec::new();
+    for piece in pieces {
+        decoder.push(piece, |byte| decoded.push(byte))?;
+    }
+    Ok(decoded)
+}
+
+#[test]
+fn reads_where_thumbnails_begin() {
+    assert_eq!(
+        ThumbnailBegin::parse("; thumbnail begin 300x300 12564"),
+        Some(ThumbnailBegin {
+            format: ThumbnailFormat::Png,
+            width: 300,
+            height: 300,
+            encoded_len: 12564,
+        })
+    );
+    assert_eq!(
+        ThumbnailBegin::parse(";thumbnail_QOI begin 16x12 412\n"),
+        Some(ThumbnailBegin {
+            format: ThumbnailFormat::Qoi,
+            width: 16,
+            height: 12,
+            encoded_len: 412,
+        })
+    );
+    assert_eq!(
+        ThumbnailBegin::parse("; thumbnail_JPG begin 220x124 5000").map(|begin| begin.format),
+        Some(ThumbnailFormat::Jpg)
+    );
+}
+
+#[test]
+fn ignores_other_comments() {
+    for line in [
+        "; thumbnails begin 300x300 12564",
+        "; thumbnail_BMP begin 300x300 12564",
+        "; thumbnail begin 300 12564",
+        "; thumbnail end",
+        "G1 X10 ; thumbnail begin 300x300 12564",
+    ] {
+        assert_eq!(ThumbnailBegin::parse(line), None, "{line}");
+    }
+}
+
+#[test]
+fn reads_where_thumbnails_end() {
+    assert!(is_thumbnail_end("; thumbnail end\n"));
+    assert!(is_thumbnail_end("; thumbnail_QOI end"));
+    assert!(!is_thumbnail_end("; thumbnail begin 16x16 400"));
+    assert!(!is_thumbnail_end("M84"));
+}
+
+#[test]
+fn decodes_base64_split_anywhere() {
+    let data = thumbnail_data("; iVBORw0KGgo=\n").unwrap();
+    assert_eq!(data, "iVBORw0KGgo=");
+
+    let png_magic = b"\x89PNG\r\n\x1a\n".to_vec();
+    assert_eq!(decode(&[data]), Ok(png_magic.clone()));
+    assert_eq!(decode(&["iVBO", "Rw0K", "Ggo="]), Ok(png_magic.clone()));
+    assert_eq!(decode(&["iVB", "ORw0KG", "go", "="]), Ok(png_magic));
+}
+
+#[test]
+fn rejects_invalid_base64() {
+    assert_eq!(decode(&["iVB*"]), Err(InvalidBase64));
+    assert_eq!(decode(&["iV==", "BO"]), Err(InvalidBase64));
+}
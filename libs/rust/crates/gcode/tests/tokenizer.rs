@@ -0,0 +1,104 @@
+use gcode::{checksum, Error, Line, Token, Tokenizer, Word};
+
+fn tokens(line: &str) -> Vec<Result<Token<'_>, Error>> {
+    Tokenizer::new(line).collect()
+}
+
+fn word<'a>(letter: char, value: &'a str) -> Token<'a> {
+    Token::Word(Word { letter, value })
+}
+
+#[test]
+fn splits_words() {
+    assert_eq!(
+        tokens("G1 X10.5 y-2 E.3 F1500"),
+        [
+            Ok(word('G', "1")),
+            Ok(word('X', "10.5")),
+            Ok(word('Y', "-2")),
+            Ok(word('E', ".3")),
+            Ok(word('F', "1500")),
+        ]
+    );
+}
+
+#[test]
+fn keeps_flags_without_values() {
+    assert_eq!(
+        tokens("G28 X Y"),
+        [Ok(word('G', "28")), Ok(word('X', "")), Ok(word('Y', ""))]
+    );
+}
+
+#[test]
+fn reads_line_numbers_and_checksums() {
+    let line = Line::parse("N12 G1 X10*99").unwrap();
+    assert_eq!(line.line_number(), Some(12));
+    let checksum = line.checksum().unwrap();
+    assert_eq!(checksum.received, 99);
+    assert_eq!(checksum.computed, gcode::checksum("N12 G1 X10"));
+    assert!(!checksum.is_valid());
+
+    let framed = format!("N12 G1 X10*{}", checksum.computed);
+    assert!(Line::parse(&framed).unwrap().checksum().unwrap().is_valid());
+}
+
+#[test]
+fn separates_both_kinds_of_comments() {
+    let line = Line::parse("G1 (move) X10 ; to the side").unwrap();
+    assert_eq!(
+        line.comments().collect::<Vec<_>>(),
+        ["move", " to the side"]
+    );
+    assert_eq!(line.executable().to_string(), "G1 X10");
+}
+
+#[test]
+fn comment_only_lines_are_empty() {
+    for line in ["", "   ", "; generated by a slicer", "(just a comment)"] {
+        assert!(Line::parse(line).unwrap().is_empty(), "{line:?}");
+    }
+}
+
+#[test]
+fn reads_the_rest_of_the_line_as_a_string_parameter() {
+    let line = Line::parse("N3 M117 Printing: 10% (layer 2)*55").unwrap();
+    assert_eq!(line.string(), Some("Printing: 10% (layer 2)"));
+    assert_eq!(
+        line.executable().to_string(),
+        "M117 Printing: 10% (layer 2)"
+    );
+
+    let line = Line::parse("M23 model.gco ; select").unwrap();
+    assert_eq!(line.string(), Some("model.gco"));
+    assert_eq!(line.comments().collect::<Vec<_>>(), [" select"]);
+}
+
+#[test]
+fn finds_parameters() {
+    let line = Line::parse("M104 S210 T0").unwrap();
+    assert!(line.command().unwrap().is("M104"));
+    assert_eq!(line.parameter('s').unwrap().value::<f32>(), Some(210.0));
+    assert_eq!(line.parameter('T').unwrap().value::<u8>(), Some(0));
+    assert!(line.parameter('X').is_none());
+}
+
+#[test]
+fn compares_commands_numerically() {
+    let line = Line::parse("G01 X1").unwrap();
+    assert!(line.command().unwrap().is("G1"));
+    assert!(!line.command().unwrap().is("G10"));
+}
+
+#[test]
+fn reports_errors() {
+    assert_eq!(Line::parse("G1 (oops"), Err(Error::UnterminatedComment));
+    assert_eq!(Line::parse("N1x G1"), Err(Error::InvalidLineNumber));
+    assert_eq!(Line::parse("G1 X1*abc"), Err(Error::InvalidChecksum));
+    assert_eq!(Line::parse("G1 #X1"), Err(Error::UnexpectedCharacter('#')));
+}
+
+#[test]
+fn checksum_is_the_xor_of_all_bytes() {
+    assert_eq!(checksum("N0 M110 N0"), 125);
+}
@@ -0,0 +1,25 @@
+//! The library of G-code files kept on a FAT volume: long names mapped to
+//! 8.3 ones, uploads that only replace a file once they are complete, and
+//! reading and writing in whole sectors on a thread of their own. Files
+//! uploaded gzip or heatshrink compressed are decompressed as they are read,
+//! and the thumbnails slicers embed are kept next to the files they came in.
+//!
+//! [`StorageWrapper`] works over any `embedded-sdmmc` block device, so it can
+//! be tested against a [`ram_disk::RamDisk`] on the host.
+
+pub mod ram_disk;
+mod read_ahead;
+mod storage;
+mod thumbnail;
+mod write_behind;
+
+pub use decompress::Encoding;
+pub use gcode::ThumbnailFormat;
+pub use read_ahead::{read_ahead, Block, LineReader, Prefetcher, BLOCK_COUNT, BLOCK_SIZE};
+pub use storage::{
+    format_timestamp, is_short_file_name, open_storage, BlockDev, LibraryFile, Reader,
+    StorageDeleteError, StorageLibraryError, StorageLineReaderError, StorageLineWriterError,
+    StorageWrapper, Upload, WrappedReaderWriter, Writer, LIBRARY_DIR_NAME,
+};
+pub use thumbnail::{Thumbnail, ThumbnailExtractor, MAX_THUMBNAIL_LEN};
+pub use write_behind::{write_behind, BlockWriter, Flusher, FlusherStopped};
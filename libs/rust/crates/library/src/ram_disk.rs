@@ -0,0 +1,159 @@
+//! A FAT16 formatted disk in memory, for trying the library out on the host.
+
+use std::{cell::RefCell, collections::BTreeMap};
+
+use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx};
+
+use crate::LIBRARY_DIR_NAME;
+
+/// The volume's size in blocks, which has to leave more than 4085 clusters
+/// for it to be FAT16 rather than FAT12, which `embedded-sdmmc` can't read.
+const VOLUME_BLOCKS: u32 = 8192;
+/// The volume starts after the MBR.
+const VOLUME_START: u32 = 1;
+const RESERVED_BLOCKS: u16 = 1;
+const FAT_COUNT: u8 = 2;
+const FAT_BLOCKS: u16 = 32;
+const ROOT_ENTRIES: u16 = 512;
+const DIR_ENTRY_LEN: usize = 32;
+const PARTITION_ID_FAT16: u8 = 0x06;
+const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+
+/// Only the blocks written so far are kept, the others read as zeros.
+#[derive(Debug)]
+pub struct RamDisk {
+    blocks: RefCell<BTreeMap<u32, Block>>,
+}
+
+#[derive(Debug)]
+pub enum RamDiskError {
+    OutOfRange,
+}
+
+impl RamDisk {
+    /// A freshly formatted disk with an empty library directory.
+    pub fn formatted() -> Self {
+        let disk = Self {
+            blocks: RefCell::new(BTreeMap::new()),
+        };
+        disk.set(0, master_boot_record());
+        disk.set(VOLUME_START, boot_sector());
+
+        let fat = fat();
+        for copy in 0..u32::from(FAT_COUNT) {
+            disk.set(
+                VOLUME_START + u32::from(RESERVED_BLOCKS) + copy * u32::from(FAT_BLOCKS),
+                fat.clone(),
+            );
+        }
+
+        let root_dir_start = VOLUME_START
+            + u32::from(RESERVED_BLOCKS)
+            + u32::from(FAT_COUNT) * u32::from(FAT_BLOCKS);
+        let mut root_dir = Block::new();
+        dir_entry(&mut root_dir, 0, LIBRARY_DIR_NAME, 2);
+        disk.set(root_dir_start, root_dir);
+
+        // Cluster 2, the first one, holds the library directory.
+        let root_dir_blocks = u32::from(ROOT_ENTRIES) * DIR_ENTRY_LEN as u32 / Block::LEN as u32;
+        let mut library_dir = Block::new();
+        dir_entry(&mut library_dir, 0, ".", 2);
+        dir_entry(&mut library_dir, 1, "..", 0);
+        disk.set(root_dir_start + root_dir_blocks, library_dir);
+
+        disk
+    }
+
+    fn set(&self, index: u32, block: Block) {
+        self.blocks.borrow_mut().insert(index, block);
+    }
+}
+
+impl BlockDevice for RamDisk {
+    type Error = RamDiskError;
+
+    fn read(
+        &self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        _reason: &str,
+    ) -> Result<(), Self::Error> {
+        let stored = self.blocks.borrow();
+        for (block, index) in blocks.iter_mut().zip(start_block_idx.0..) {
+            if index >= VOLUME_START + VOLUME_BLOCKS {
+                return Err(RamDiskError::OutOfRange);
+            }
+            *block = stored.get(&index).cloned().unwrap_or_else(Block::new);
+        }
+        Ok(())
+    }
+
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        for (block, index) in blocks.iter().zip(start_block_idx.0..) {
+            if index >= VOLUME_START + VOLUME_BLOCKS {
+                return Err(RamDiskError::OutOfRange);
+            }
+            self.set(index, block.clone());
+        }
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        Ok(BlockCount(VOLUME_START + VOLUME_BLOCKS))
+    }
+}
+
+fn master_boot_record() -> Block {
+    let mut block = Block::new();
+    let partition = &mut block.contents[446..462];
+    partition[4] = PARTITION_ID_FAT16;
+    partition[8..12].copy_from_slice(&VOLUME_START.to_le_bytes());
+    partition[12..16].copy_from_slice(&VOLUME_BLOCKS.to_le_bytes());
+    block.contents[510..].copy_from_slice(&[0x55, 0xAA]);
+    block
+}
+
+fn boot_sector() -> Block {
+    let mut block = Block::new();
+    let contents = &mut block.contents;
+    contents[..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    contents[3..11].copy_from_slice(b"MSDOS5.0");
+    contents[11..13].copy_from_slice(&(Block::LEN as u16).to_le_bytes());
+    // One block per cluster.
+    contents[13] = 1;
+    contents[14..16].copy_from_slice(&RESERVED_BLOCKS.to_le_bytes());
+    contents[16] = FAT_COUNT;
+    contents[17..19].copy_from_slice(&ROOT_ENTRIES.to_le_bytes());
+    contents[19..21].copy_from_slice(&(VOLUME_BLOCKS as u16).to_le_bytes());
+    // A fixed disk.
+    contents[21] = 0xF8;
+    contents[22..24].copy_from_slice(&FAT_BLOCKS.to_le_bytes());
+    contents[24..26].copy_from_slice(&32u16.to_le_bytes());
+    contents[26..28].copy_from_slice(&64u16.to_le_bytes());
+    contents[28..32].copy_from_slice(&VOLUME_START.to_le_bytes());
+    contents[36] = 0x80;
+    contents[38] = 0x29;
+    contents[39..43].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+    contents[43..54].copy_from_slice(b"LIBRARY    ");
+    contents[54..62].copy_from_slice(b"FAT16   ");
+    contents[510..].copy_from_slice(&[0x55, 0xAA]);
+    block
+}
+
+/// The first block of a FAT, with the two reserved entries and the library
+/// directory's cluster taken.
+fn fat() -> Block {
+    let mut block = Block::new();
+    for (index, entry) in [0xFFF8u16, 0xFFFF, 0xFFFF].into_iter().enumerate() {
+        block.contents[index * 2..index * 2 + 2].copy_from_slice(&entry.to_le_bytes());
+    }
+    block
+}
+
+fn dir_entry(block: &mut Block, index: usize, name: &str, cluster: u16) {
+    let entry = &mut block.contents[index * DIR_ENTRY_LEN..(index + 1) * DIR_ENTRY_LEN];
+    entry[..11].fill(b' ');
+    entry[..name.len()].copy_from_slice(name.as_bytes());
+    entry[11] = ATTRIBUTE_DIRECTORY;
+    entry[26..28].copy_from_slice(&cluster.to_le_bytes());
+}
@@ -0,0 +1,274 @@
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+
+use decompress::{Decoder, Encoding};
+use embedded_sdmmc::{BlockDevice, TimeSource};
+use log::error;
+
+use crate::storage::{Reader, StorageLineReaderError, WrappedReaderWriter};
+
+/// Bytes read from the card in one go, a multiple of its 512 byte sectors.
+pub const BLOCK_SIZE: usize = 8 * 512;
+/// One block is read or written while the other one is used.
+pub const BLOCK_COUNT: usize = 2;
+
+/// A buffer passed back and forth between two threads.
+pub struct Block {
+    pub bytes: Box<[u8]>,
+    pub len: usize,
+    /// Where in the file the bytes were read from. A compressed file's
+    /// bytes span more or less of it than `len`.
+    pub source_start: u32,
+    pub source_end: u32,
+}
+
+impl Block {
+    pub fn new() -> Self {
+        Self {
+            bytes: vec![0; BLOCK_SIZE].into_boxed_slice(),
+            len: 0,
+            source_start: 0,
+            source_end: 0,
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == self.bytes.len()
+    }
+}
+
+impl Default for Block {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+type Filled = Result<Block, StorageLineReaderError>;
+
+/// Reads blocks of a file ahead of the [`LineReader`] it was created with,
+/// decompressing them if need be, meant to be run on a thread of its own.
+pub struct Prefetcher {
+    filled: SyncSender<Filled>,
+    empty: Receiver<Block>,
+    decoder: Decoder,
+    /// What was read of a compressed file but not decoded yet.
+    input: Vec<u8>,
+    input_start: usize,
+    input_end: usize,
+    source_offset: u32,
+}
+
+/// Hands out the lines of the blocks a [`Prefetcher`] read, without copying
+/// them unless they span two blocks.
+pub struct LineReader {
+    filled: Receiver<Filled>,
+    empty: SyncSender<Block>,
+    block: Option<Block>,
+    position: usize,
+    /// The start of a line that continues in the next block.
+    carry: Vec<u8>,
+    /// Whether `carry` was handed out as a line and can be cleared.
+    carry_taken: bool,
+    offset: u32,
+    /// Where the last block handed back ended in the file.
+    source_offset: u32,
+}
+
+/// `offset` is where the file was seeked to before it is handed to
+/// [`Prefetcher::run`]. Compressed files can't be seeked into, they are read
+/// from the start and the lines before the offset skipped instead.
+pub fn read_ahead(offset: u32, encoding: Encoding) -> (Prefetcher, LineReader) {
+    let (filled_sender, filled_receiver) = sync_channel(BLOCK_COUNT);
+    let (empty_sender, empty_receiver) = sync_channel(BLOCK_COUNT);
+    for _ in 0..BLOCK_COUNT {
+        empty_sender.send(Block::new()).unwrap();
+    }
+
+    let input = match encoding {
+        Encoding::Identity => Vec::new(),
+        _ => vec![0; BLOCK_SIZE],
+    };
+    let prefetcher = Prefetcher {
+        filled: filled_sender,
+        empty: empty_receiver,
+        decoder: Decoder::new(encoding),
+        input,
+        input_start: 0,
+        input_end: 0,
+        source_offset: offset,
+    };
+    let line_reader = LineReader {
+        filled: filled_receiver,
+        empty: empty_sender,
+        block: None,
+        position: 0,
+        carry: Vec::new(),
+        carry_taken: false,
+        offset,
+        source_offset: offset,
+    };
+    (prefetcher, line_reader)
+}
+
+impl Prefetcher {
+    /// Reads until the file ends, reading fails or the [`LineReader`] is
+    /// dropped.
+    pub fn run<D: BlockDevice, C: TimeSource>(
+        mut self,
+        mut reader: WrappedReaderWriter<Reader, D, C>,
+    ) {
+        while let Ok(mut block) = self.empty.recv() {
+            block.source_start = self.source_offset;
+            let result = match self.decoder {
+                // Read straight into the block, as there is nothing to decode.
+                Decoder::Identity => fill(&mut reader, &mut block.bytes).map(|len| {
+                    block.len = len;
+                    self.source_offset += len as u32;
+                }),
+                _ => self.decode(&mut reader, &mut block),
+            }
+            .map(|()| {
+                block.source_end = self.source_offset;
+                block
+            });
+            let is_last = !matches!(&result, Ok(block) if block.len > 0);
+            if self.filled.send(result).is_err() || is_last {
+                break;
+            }
+        }
+    }
+
+    /// Decodes until `block` is full or the file ends.
+    fn decode<D: BlockDevice, C: TimeSource>(
+        &mut self,
+        reader: &mut WrappedReaderWriter<Reader, D, C>,
+        block: &mut Block,
+    ) -> Result<(), StorageLineReaderError> {
+        block.len = 0;
+        while !block.is_full() {
+            if self.input_start == self.input_end {
+                self.input_end = fill(reader, &mut self.input)?;
+                self.input_start = 0;
+            }
+            let (consumed, produced) = self
+                .decoder
+                .decode(
+                    &self.input[self.input_start..self.input_end],
+                    &mut block.bytes[block.len..],
+                )
+                .map_err(|err| {
+                    error!("{err:#?}");
+                    StorageLineReaderError::Decode
+                })?;
+            self.input_start += consumed;
+            self.source_offset += consumed as u32;
+            block.len += produced;
+            // With room left, the file or the compressed stream ended.
+            if consumed == 0 && produced == 0 {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads until `buffer` is full or the file ends.
+fn fill<D: BlockDevice, C: TimeSource>(
+    reader: &mut WrappedReaderWriter<Reader, D, C>,
+    buffer: &mut [u8],
+) -> Result<usize, StorageLineReaderError> {
+    let mut len = 0;
+    while len < buffer.len() {
+        match reader.read_chunk(&mut buffer[len..])? {
+            0 => break,
+            num_read => len += num_read,
+        }
+    }
+    Ok(len)
+}
+
+impl LineReader {
+    /// The next line, with its `\n`, or `None` at the end of the file.
+    pub fn read_line(&mut self) -> Result<Option<&str>, StorageLineReaderError> {
+        if self.carry_taken {
+            self.carry.clear();
+            self.carry_taken = false;
+        }
+
+        let line_end = loop {
+            let Some(block) = &self.block else {
+                if self.next_block()? {
+                    continue;
+                }
+                break None;
+            };
+            let rest = &block.bytes[self.position..block.len];
+            if let Some(newline) = rest.iter().position(|&byte| byte == b'\n') {
+                break Some(self.position + newline + 1);
+            }
+            self.carry.extend_from_slice(rest);
+            let block = self.block.take().unwrap();
+            self.source_offset = block.source_end;
+            // Can only fail once the prefetcher stopped.
+            let _ = self.empty.send(block);
+        };
+
+        let bytes = match line_end {
+            Some(end) => {
+                let start = self.position;
+                self.position = end;
+                let bytes = &self.block.as_ref().unwrap().bytes[start..end];
+                if self.carry.is_empty() {
+                    bytes
+                } else {
+                    self.carry.extend_from_slice(bytes);
+                    self.carry_taken = true;
+                    &self.carry
+                }
+            }
+            None if self.carry.is_empty() => return Ok(None),
+            // The last line has no `\n`.
+            None => {
+                self.carry_taken = true;
+                &self.carry
+            }
+        };
+        self.offset += bytes.len() as u32;
+
+        core::str::from_utf8(bytes).map(Some).map_err(|err| {
+            error!("{err:#?}");
+            StorageLineReaderError::Utf8Error
+        })
+    }
+
+    /// Where the line returned by the next [`Self::read_line`] starts.
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    /// How far into the file the lines handed out so far reach. For a
+    /// compressed file this is estimated from the part of the file the
+    /// current block was decoded from.
+    pub fn source_offset(&self) -> u32 {
+        match &self.block {
+            Some(block) => {
+                let span = u64::from(block.source_end - block.source_start);
+                let done = span * self.position as u64 / block.len as u64;
+                block.source_start + done as u32
+            }
+            None => self.source_offset,
+        }
+    }
+
+    /// Waits for the next block, returning whether there was one.
+    fn next_block(&mut self) -> Result<bool, StorageLineReaderError> {
+        match self.filled.recv() {
+            Ok(Ok(block)) if block.len > 0 => {
+                self.block = Some(block);
+                self.position = 0;
+                Ok(true)
+            }
+            Ok(Ok(_)) | Err(_) => Ok(false),
+            Ok(Err(err)) => Err(err),
+        }
+    }
+}
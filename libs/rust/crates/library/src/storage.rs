@@ -0,0 +1,807 @@
+use std::{fmt, marker::PhantomData, thread, time::Duration};
+
+use embedded_sdmmc::{
+    BlockDevice, Cluster, DirEntry, Directory, File, Mode, TimeSource, Timestamp, Volume,
+    VolumeIdx, VolumeManager,
+};
+use log::{error, warn};
+use serde::Serialize;
+
+/// Where the library lives, the root directory is used if the card has no
+/// such directory, as `embedded-sdmmc` can't create one.
+pub const LIBRARY_DIR_NAME: &str = "GCODE";
+const INDEX_FILE_NAME: &str = "INDEX.TXT";
+/// The index is written here in full before `INDEX.TXT` is rewritten, so a
+/// write that is cut short leaves one of them whole.
+const INDEX_TEMP_FILE_NAME: &str = "INDEX.TMP";
+/// Ends a complete `INDEX.TMP`. It has no tab, so it isn't read as an entry.
+const INDEX_END: &str = "# end\n";
+/// A file's thumbnail is kept in a file of its own, indexed under the file's
+/// name with this appended. Files can't be named like that themselves.
+const THUMBNAIL_SUFFIX: &str = ".thumbnail";
+/// Uploads and their thumbnails are written to files with this extension,
+/// which they keep once committed, as files can't be renamed. One that isn't
+/// in the index is what is left of an upload that never finished.
+const UPLOAD_EXTENSION: &str = "UPL";
+
+/// A failed write is retried after 10, 20, 40, 80 and 160 ms, giving a card
+/// that is busy erasing time to catch up.
+const WRITE_RETRIES: u32 = 5;
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+pub trait BlockDev
+where
+    Self: BlockDevice + Send + 'static,
+{
+}
+impl<T: BlockDevice + Send + 'static> BlockDev for T {}
+
+pub struct WrappedReaderWriter<'a, T, D: BlockDevice, C: TimeSource> {
+    storage: &'a mut StorageWrapper<D, C>,
+    file: Option<File>,
+    /// Set for a file opened for writing before it had a cluster, see
+    /// [`StorageWrapper::forget_open_files`].
+    forget_open_files: bool,
+    _phantom: PhantomData<T>,
+}
+
+pub struct Writer;
+pub struct Reader;
+
+impl<'a, D: BlockDevice, C: TimeSource> WrappedReaderWriter<'a, Writer, D, C> {
+    pub fn write(&mut self, line: &str) -> Result<(), StorageLineWriterError> {
+        self.write_bytes(line.as_bytes())
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), StorageLineWriterError> {
+        let mut num_written = 0;
+        while let Some(bytes) = bytes.get(num_written..) {
+            if bytes.is_empty() {
+                break;
+            }
+
+            let mut retries = 0;
+            loop {
+                let volume_manager = self.storage.volume_manager.as_mut().unwrap();
+                match volume_manager.write(
+                    &mut self.storage.volume,
+                    self.file.as_mut().unwrap(),
+                    bytes,
+                ) {
+                    Ok(value) => {
+                        num_written += value;
+                        break;
+                    }
+                    Err(err) if retries < WRITE_RETRIES => {
+                        let delay = WRITE_RETRY_DELAY * (1 << retries);
+                        warn!("{err:?}, retrying in {delay:?}");
+                        thread::sleep(delay);
+                        retries += 1;
+                    }
+                    Err(err) => {
+                        error!("{err:#?}");
+                        return Err(StorageLineWriterError::Write);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a, T, D: BlockDevice, C: TimeSource> Drop for WrappedReaderWriter<'a, T, D, C> {
+    fn drop(&mut self) {
+        for _ in 0..3 {
+            let volume_manager = self.storage.volume_manager.as_mut().unwrap();
+            match volume_manager.close_file(&self.storage.volume, self.file.take().unwrap()) {
+                Ok(_) => break,
+                Err(err) => error!("{err:#?}"),
+            }
+        }
+        if self.forget_open_files {
+            self.storage.forget_open_files();
+        }
+    }
+}
+
+impl<'a, T, D: BlockDevice, C: TimeSource> WrappedReaderWriter<'a, T, D, C> {
+    /// For a writer, everything written so far.
+    pub fn file_size_in_bytes(&mut self) -> u32 {
+        self.file.as_ref().unwrap().length()
+    }
+}
+
+impl<'a, D: BlockDevice, C: TimeSource> WrappedReaderWriter<'a, Reader, D, C> {
+    pub fn remaining_bytes_in_file(&mut self) -> u32 {
+        self.file.as_ref().unwrap().left()
+    }
+
+    /// Reads the file as it is. Reads stay within a sector, so this returns
+    /// less than `buffer` holds at each sector boundary.
+    pub fn read_chunk(&mut self, buffer: &mut [u8]) -> Result<usize, StorageLineReaderError> {
+        if self.file.as_ref().unwrap().eof() {
+            return Ok(0);
+        }
+        let volume_manager = self.storage.volume_manager.as_mut().unwrap();
+        volume_manager
+            .read(&self.storage.volume, self.file.as_mut().unwrap(), buffer)
+            .map_err(|err| {
+                error!("{err:#?}");
+                StorageLineReaderError::Read
+            })
+    }
+
+    /// Continues reading at `offset`.
+    pub fn seek(&mut self, offset: u32) -> Result<(), StorageLineReaderError> {
+        self.file
+            .as_mut()
+            .unwrap()
+            .seek_from_start(offset)
+            .map_err(|err| {
+                error!("{err:#?}");
+                StorageLineReaderError::Seek
+            })
+    }
+}
+
+/// The G-code library, kept in a directory of a FAT volume.
+pub struct StorageWrapper<D: BlockDevice, C: TimeSource> {
+    /// Only `None` while [`Self::forget_open_files`] replaces it.
+    volume_manager: Option<VolumeManager<D, C>>,
+    volume: Volume,
+    dir: Directory,
+}
+
+/// A file being uploaded, from [`StorageWrapper::begin_upload`].
+#[derive(Debug, Clone)]
+pub struct Upload {
+    name: String,
+    short_name: String,
+    thumbnail: Option<String>,
+}
+
+impl Upload {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// A G-code file in the library.
+#[derive(Debug, Clone, Serialize)]
+pub struct LibraryFile {
+    pub name: String,
+    /// The 8.3 name the file has on the card.
+    pub short_name: String,
+    pub size: u32,
+    pub created: String,
+    pub modified: String,
+    /// Whether [`StorageWrapper::get_thumbnail`] has an image for the file.
+    pub thumbnail: bool,
+}
+
+impl<D: BlockDevice, C: TimeSource> StorageWrapper<D, C> {
+    pub fn list(&mut self) -> Result<Vec<LibraryFile>, StorageLibraryError> {
+        let index = self.read_index()?;
+        let mut files = Vec::new();
+        for entry in self.entries()? {
+            let short_name = entry.name.to_string();
+            if short_name == INDEX_FILE_NAME
+                || short_name == INDEX_TEMP_FILE_NAME
+                || is_upload_name(&short_name) && index.long_name(&short_name).is_none()
+            {
+                continue;
+            }
+            let name = index
+                .long_name(&short_name)
+                .unwrap_or(&short_name)
+                .to_string();
+            if name.ends_with(THUMBNAIL_SUFFIX) {
+                continue;
+            }
+            files.push(LibraryFile {
+                thumbnail: index.short_name(&thumbnail_name(&name)).is_some(),
+                name,
+                short_name,
+                size: entry.size,
+                created: format_timestamp(&entry.ctime),
+                modified: format_timestamp(&entry.mtime),
+            });
+        }
+        Ok(files)
+    }
+
+    /// Creates a new file for `name` next to the one already in the library,
+    /// which stays untouched until [`Self::commit_upload`].
+    pub fn begin_upload(
+        &mut self,
+        name: &str,
+    ) -> Result<(Upload, WrappedReaderWriter<'_, Writer, D, C>), StorageLibraryError> {
+        if !is_valid_name(name) {
+            return Err(StorageLibraryError::InvalidName);
+        }
+
+        let upload = Upload {
+            name: name.to_string(),
+            short_name: self.fresh_upload_name(name)?,
+            thumbnail: None,
+        };
+        let writer = self.create_wrapper(&upload.short_name, Mode::ReadWriteCreateOrTruncate)?;
+        Ok((upload, writer))
+    }
+
+    /// Opens what was received of `upload` so far, to go on writing at its
+    /// end.
+    pub fn resume_upload(
+        &mut self,
+        upload: &Upload,
+    ) -> Result<WrappedReaderWriter<'_, Writer, D, C>, StorageLibraryError> {
+        self.create_wrapper(&upload.short_name, Mode::ReadWriteAppend)
+    }
+
+    /// Stores `image` as the thumbnail of `upload`, which replaces the one of
+    /// the file it replaces along with it.
+    pub fn attach_thumbnail(
+        &mut self,
+        upload: &mut Upload,
+        image: &[u8],
+    ) -> Result<(), StorageLibraryError> {
+        let short_name = self.fresh_upload_name(&thumbnail_name(&upload.name))?;
+        let mut writer =
+            self.create_wrapper::<Writer>(&short_name, Mode::ReadWriteCreateOrTruncate)?;
+        let written = writer.write_bytes(image);
+        drop(writer);
+        if written.is_err() {
+            if let Err(err) = self.volume_manager.as_mut().unwrap().delete_file_in_dir(
+                &self.volume,
+                &self.dir,
+                &short_name,
+            ) {
+                error!("{err:#?}");
+            }
+            return Err(StorageLibraryError::Write);
+        }
+        upload.thumbnail = Some(short_name);
+        Ok(())
+    }
+
+    /// Puts the uploaded file in place of the one it replaces.
+    ///
+    /// Files can't be renamed, so the switch happens in the index, which is
+    /// rewritten before the old file is deleted.
+    pub fn commit_upload(&mut self, upload: Upload) -> Result<(), StorageLibraryError> {
+        let mut index = self.read_index()?;
+        let replaced = self.find_short_name(&index, &upload.name)?;
+        let thumbnail_name = thumbnail_name(&upload.name);
+        let replaced_thumbnail = index.short_name(&thumbnail_name).map(str::to_string);
+
+        index.entries.retain(|(short, long)| {
+            *long != upload.name && *long != thumbnail_name && Some(short) != replaced.as_ref()
+        });
+        index.entries.push((upload.short_name, upload.name));
+        if let Some(thumbnail) = upload.thumbnail {
+            index.entries.push((thumbnail, thumbnail_name));
+        }
+        self.write_index(&index)?;
+
+        for replaced in [replaced, replaced_thumbnail].into_iter().flatten() {
+            // Only leaves a stray file behind if it fails.
+            if let Err(err) = self.volume_manager.as_mut().unwrap().delete_file_in_dir(
+                &self.volume,
+                &self.dir,
+                &replaced,
+            ) {
+                error!("{err:#?}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes what was received of a failed upload.
+    pub fn abort_upload(&mut self, upload: Upload) -> Result<(), StorageDeleteError> {
+        if let Some(thumbnail) = &upload.thumbnail {
+            if let Err(err) = self.volume_manager.as_mut().unwrap().delete_file_in_dir(
+                &self.volume,
+                &self.dir,
+                thumbnail,
+            ) {
+                error!("{err:#?}");
+            }
+        }
+        self.volume_manager
+            .as_mut()
+            .unwrap()
+            .delete_file_in_dir(&self.volume, &self.dir, &upload.short_name)
+            .map_err(|err| {
+                error!("{err:#?}");
+                StorageDeleteError::DeleteFileInDir
+            })
+    }
+
+    pub fn get_reader(
+        &mut self,
+        name: &str,
+    ) -> Result<WrappedReaderWriter<'_, Reader, D, C>, StorageLibraryError> {
+        let index = self.read_index()?;
+        let short_name = self
+            .find_short_name(&index, name)?
+            .ok_or(StorageLibraryError::NotFound)?;
+        self.create_wrapper(&short_name, Mode::ReadOnly)
+    }
+
+    /// The image the slicer embedded in `name`, as a PNG, JPEG or QOI file.
+    pub fn get_thumbnail(
+        &mut self,
+        name: &str,
+    ) -> Result<WrappedReaderWriter<'_, Reader, D, C>, StorageLibraryError> {
+        let index = self.read_index()?;
+        let short_name = index
+            .short_name(&thumbnail_name(name))
+            .ok_or(StorageLibraryError::NotFound)?
+            .to_string();
+        self.create_wrapper(&short_name, Mode::ReadOnly)
+    }
+
+    pub fn delete(&mut self, name: &str) -> Result<(), StorageDeleteError> {
+        let mut index = self.read_index().map_err(|_| StorageDeleteError::Index)?;
+        let short_name = self
+            .find_short_name(&index, name)
+            .map_err(|_| StorageDeleteError::Index)?
+            .ok_or(StorageDeleteError::NotFound)?;
+
+        self.volume_manager
+            .as_mut()
+            .unwrap()
+            .delete_file_in_dir(&self.volume, &self.dir, &short_name)
+            .map_err(|err| {
+                error!("{err:#?}");
+                StorageDeleteError::DeleteFileInDir
+            })?;
+
+        let thumbnail_name = thumbnail_name(name);
+        if let Some(thumbnail) = index.short_name(&thumbnail_name) {
+            if let Err(err) = self.volume_manager.as_mut().unwrap().delete_file_in_dir(
+                &self.volume,
+                &self.dir,
+                thumbnail,
+            ) {
+                error!("{err:#?}");
+            }
+        }
+
+        let len = index.entries.len();
+        index
+            .entries
+            .retain(|(short, long)| *short != short_name && *long != thumbnail_name);
+        if index.entries.len() != len {
+            self.write_index(&index)
+                .map_err(|_| StorageDeleteError::Index)?;
+        }
+        Ok(())
+    }
+
+    /// Only the name in the index changes, as FAT renames aren't supported by
+    /// `embedded-sdmmc`.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), StorageLibraryError> {
+        if !is_valid_name(to) {
+            return Err(StorageLibraryError::InvalidName);
+        }
+        let mut index = self.read_index()?;
+        if self.find_short_name(&index, to)?.is_some() {
+            return Err(StorageLibraryError::AlreadyExists);
+        }
+        let short_name = self
+            .find_short_name(&index, from)?
+            .ok_or(StorageLibraryError::NotFound)?;
+
+        index.entries.retain(|(short, _)| *short != short_name);
+        index.entries.push((short_name, to.to_string()));
+        let from_thumbnail = thumbnail_name(from);
+        for (_, long) in &mut index.entries {
+            if *long == from_thumbnail {
+                *long = thumbnail_name(to);
+            }
+        }
+        self.write_index(&index)
+    }
+
+    /// Closes the library, handing back what it was opened with.
+    pub fn free(self) -> (D, C) {
+        let mut volume_manager = self.volume_manager.unwrap();
+        volume_manager.close_dir(&self.volume, self.dir);
+        volume_manager.free()
+    }
+
+    /// An 8.3 name for `name` that neither a file on the card nor one in the
+    /// index has.
+    fn fresh_short_name(&mut self, name: &str) -> Result<String, StorageLibraryError> {
+        let index = self.read_index()?;
+        let taken: Vec<String> = self
+            .entries()?
+            .iter()
+            .map(|entry| entry.name.to_string())
+            .collect();
+        Ok(assign_short_name(name, |short_name| {
+            short_name == INDEX_FILE_NAME
+                || short_name == INDEX_TEMP_FILE_NAME
+                || taken.iter().any(|taken| taken == short_name)
+                || index.long_name(short_name).is_some()
+        }))
+    }
+
+    /// A fresh 8.3 name with [`UPLOAD_EXTENSION`] for the upload of `name`.
+    fn fresh_upload_name(&mut self, name: &str) -> Result<String, StorageLibraryError> {
+        let stem = name.rsplit_once('.').map_or(name, |(stem, _)| stem);
+        self.fresh_short_name(&format!("{stem}.{UPLOAD_EXTENSION}"))
+    }
+
+    /// Deletes the files of uploads that were neither committed nor aborted,
+    /// because the power went or the firmware restarted meanwhile.
+    fn delete_abandoned_uploads(&mut self) -> Result<(), StorageLibraryError> {
+        let index = self.read_index()?;
+        for entry in self.entries()? {
+            let short_name = entry.name.to_string();
+            if !is_upload_name(&short_name) || index.long_name(&short_name).is_some() {
+                continue;
+            }
+            warn!("deleting {short_name}, left from an unfinished upload");
+            if let Err(err) = self.volume_manager.as_mut().unwrap().delete_file_in_dir(
+                &self.volume,
+                &self.dir,
+                &short_name,
+            ) {
+                error!("{err:#?}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Looks `name` up in the index, then among the files on the card, which
+    /// may have been copied there without going through the library.
+    fn find_short_name(
+        &mut self,
+        index: &Index,
+        name: &str,
+    ) -> Result<Option<String>, StorageLibraryError> {
+        if let Some(short_name) = index.short_name(name) {
+            return Ok(Some(short_name.to_string()));
+        }
+        if !is_short_file_name(name) || index.long_name(&name.to_ascii_uppercase()).is_some() {
+            return Ok(None);
+        }
+        let short_name = name.to_ascii_uppercase();
+        let exists = self
+            .entries()?
+            .iter()
+            .any(|entry| entry.name.to_string() == short_name);
+        Ok(exists.then_some(short_name))
+    }
+
+    fn entries(&mut self) -> Result<Vec<DirEntry>, StorageLibraryError> {
+        let mut entries = Vec::new();
+        self.volume_manager
+            .as_mut()
+            .unwrap()
+            .iterate_dir(&self.volume, &self.dir, |entry| {
+                if !entry.attributes.is_directory() && !entry.attributes.is_volume() {
+                    entries.push(entry.clone());
+                }
+            })
+            .map_err(|err| {
+                error!("{err:#?}");
+                StorageLibraryError::ListDir
+            })?;
+        Ok(entries)
+    }
+
+    /// Reads `INDEX.TXT`, unless a complete `INDEX.TMP` shows that it was
+    /// being rewritten when the power went, in which case it is restored.
+    fn read_index(&mut self) -> Result<Index, StorageLibraryError> {
+        if let Some(contents) = self.read_file(INDEX_TEMP_FILE_NAME)? {
+            if let Some(contents) = contents.strip_suffix(INDEX_END) {
+                warn!("restoring {INDEX_FILE_NAME} from {INDEX_TEMP_FILE_NAME}");
+                let index = Index::parse(contents);
+                self.write_index(&index)?;
+                return Ok(index);
+            }
+            // Writing it was cut short, `INDEX.TXT` is still as it was.
+            self.delete_index_temp_file();
+        }
+        let contents = self.read_file(INDEX_FILE_NAME)?.unwrap_or_default();
+        Ok(Index::parse(&contents))
+    }
+
+    /// Writes `INDEX.TMP`, then `INDEX.TXT`, then deletes `INDEX.TMP`.
+    /// Files can't be renamed, so `INDEX.TXT` is rewritten rather than
+    /// replaced, truncating it so that it keeps its cluster.
+    fn write_index(&mut self, index: &Index) -> Result<(), StorageLibraryError> {
+        let contents = index.to_string();
+        self.write_file(INDEX_TEMP_FILE_NAME, &format!("{contents}{INDEX_END}"))?;
+        self.write_file(INDEX_FILE_NAME, &contents)?;
+        self.delete_index_temp_file();
+        Ok(())
+    }
+
+    /// Only leaves it behind if it fails, to be restored from, which does no
+    /// harm as it is the same as `INDEX.TXT`.
+    fn delete_index_temp_file(&mut self) {
+        if let Err(err) = self.volume_manager.as_mut().unwrap().delete_file_in_dir(
+            &self.volume,
+            &self.dir,
+            INDEX_TEMP_FILE_NAME,
+        ) {
+            error!("{err:#?}");
+        }
+    }
+
+    /// The whole of a small file like the index, or `None` if there is none.
+    fn read_file(&mut self, short_name: &str) -> Result<Option<String>, StorageLibraryError> {
+        let volume_manager = self.volume_manager.as_mut().unwrap();
+        let mut file = match volume_manager.open_file_in_dir(
+            &mut self.volume,
+            &self.dir,
+            short_name,
+            Mode::ReadOnly,
+        ) {
+            Ok(file) => file,
+            Err(embedded_sdmmc::Error::FileNotFound) => return Ok(None),
+            Err(err) => {
+                error!("{err:#?}");
+                return Err(StorageLibraryError::Index);
+            }
+        };
+
+        let mut contents = Vec::new();
+        let mut buffer = [0u8; 128];
+        let result = loop {
+            match volume_manager.read(&self.volume, &mut file, &mut buffer) {
+                Ok(0) => break Ok(()),
+                Ok(num_read) => contents.extend_from_slice(&buffer[..num_read]),
+                Err(err) => break Err(err),
+            }
+            if file.eof() {
+                break Ok(());
+            }
+        };
+        let closed = volume_manager.close_file(&self.volume, file);
+        result.and(closed).map_err(|err| {
+            error!("{err:#?}");
+            StorageLibraryError::Index
+        })?;
+
+        Ok(Some(String::from_utf8_lossy(&contents).into_owned()))
+    }
+
+    fn write_file(&mut self, short_name: &str, contents: &str) -> Result<(), StorageLibraryError> {
+        let mut writer =
+            self.create_wrapper::<Writer>(short_name, Mode::ReadWriteCreateOrTruncate)?;
+        writer.write(contents).map_err(|err| {
+            error!("{err:#?}");
+            StorageLibraryError::Index
+        })
+    }
+
+    fn create_wrapper<T>(
+        &mut self,
+        short_name: &str,
+        mode: Mode,
+    ) -> Result<WrappedReaderWriter<'_, T, D, C>, StorageLibraryError> {
+        let volume_manager = self.volume_manager.as_mut().unwrap();
+        // A file is only given a cluster once something is written to it, an
+        // `INDEX.TXT` that is rewritten in place keeps the one it has.
+        let forget_open_files = mode != Mode::ReadOnly
+            && volume_manager
+                .find_directory_entry(&self.volume, &self.dir, short_name)
+                .map_or(true, |entry| entry.cluster == Cluster::EMPTY);
+        let file = volume_manager
+            .open_file_in_dir(&mut self.volume, &self.dir, short_name, mode)
+            .map_err(|err| match err {
+                embedded_sdmmc::Error::FileNotFound => StorageLibraryError::NotFound,
+                err => {
+                    error!("{err:#?}");
+                    StorageLibraryError::OpenFile
+                }
+            })?;
+
+        Ok(WrappedReaderWriter {
+            storage: self,
+            file: Some(file),
+            forget_open_files,
+            _phantom: PhantomData,
+        })
+    }
+
+    /// `embedded-sdmmc` keeps a file that was given its first cluster while
+    /// open in its table of open files, which leaves room for fewer of them
+    /// and has empty files taken for open, so they can't be opened or
+    /// deleted. A new volume manager starts with no files open, the volume
+    /// and directory stay as they are.
+    fn forget_open_files(&mut self) {
+        let (block_device, time_source) = self.volume_manager.take().unwrap().free();
+        self.volume_manager = Some(VolumeManager::new(block_device, time_source));
+    }
+}
+
+/// Maps the names files were uploaded with to their 8.3 names on the card,
+/// one `SHORT.UPL<tab>long name` per line.
+#[derive(Debug, Default)]
+struct Index {
+    entries: Vec<(String, String)>,
+}
+
+impl Index {
+    fn parse(contents: &str) -> Self {
+        let entries = contents
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .map(|(short, long)| (short.to_string(), long.to_string()))
+            .collect();
+        Self { entries }
+    }
+
+    fn short_name(&self, long_name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(_, long)| long == long_name)
+            .map(|(short, _)| short.as_str())
+    }
+
+    fn long_name(&self, short_name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(short, _)| short == short_name)
+            .map(|(_, long)| long.as_str())
+    }
+}
+
+impl fmt::Display for Index {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (short, long) in &self.entries {
+            writeln!(f, "{short}\t{long}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether `name` can go in the index.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(['\t', '\n']) && !name.ends_with(THUMBNAIL_SUFFIX)
+}
+
+fn is_upload_name(short_name: &str) -> bool {
+    short_name
+        .rsplit_once('.')
+        .is_some_and(|(_, extension)| extension == UPLOAD_EXTENSION)
+}
+
+fn thumbnail_name(name: &str) -> String {
+    format!("{name}{THUMBNAIL_SUFFIX}")
+}
+
+/// Whether `name` fits FAT's 8.3 names, like `MODEL.GCO`.
+pub fn is_short_file_name(name: &str) -> bool {
+    let (stem, extension) = name.split_once('.').unwrap_or((name, ""));
+    let is_valid_part = |part: &str, max_len| {
+        part.len() <= max_len
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '~'))
+    };
+    !stem.is_empty() && is_valid_part(stem, 8) && is_valid_part(extension, 3)
+}
+
+/// Picks an 8.3 name for `name` the way Windows does, `My Benchy.gcode`
+/// becoming `MYBENC~1.GCO`.
+fn assign_short_name(name: &str, is_taken: impl Fn(&str) -> bool) -> String {
+    if is_short_file_name(name) && !is_taken(&name.to_ascii_uppercase()) {
+        return name.to_ascii_uppercase();
+    }
+
+    let (stem, extension) = name.rsplit_once('.').unwrap_or((name, "gco"));
+    let clean = |part: &str, max_len| -> String {
+        part.chars()
+            .filter(char::is_ascii_alphanumeric)
+            .take(max_len)
+            .collect::<String>()
+            .to_ascii_uppercase()
+    };
+    let stem = match clean(stem, 6) {
+        stem if stem.is_empty() => "FILE".to_string(),
+        stem => stem,
+    };
+    let extension = clean(extension, 3);
+
+    (1..)
+        .map(|number| {
+            let suffix = format!("~{number}");
+            let stem = &stem[..stem.len().min(8 - suffix.len())];
+            match extension.as_str() {
+                "" => format!("{stem}{suffix}"),
+                extension => format!("{stem}{suffix}.{extension}"),
+            }
+        })
+        .find(|short_name| !is_taken(short_name))
+        .expect("Should find a free short name")
+}
+
+pub fn format_timestamp(timestamp: &Timestamp) -> String {
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        1970 + u32::from(timestamp.year_since_1970),
+        timestamp.zero_indexed_month + 1,
+        timestamp.zero_indexed_day + 1,
+        timestamp.hours,
+        timestamp.minutes,
+        timestamp.seconds,
+    )
+}
+
+#[derive(Debug)]
+pub enum StorageLineReaderError {
+    Read,
+    Utf8Error,
+    Seek,
+    Decode,
+}
+
+#[derive(Debug)]
+pub enum StorageLineWriterError {
+    Write,
+}
+
+#[derive(Debug)]
+pub enum StorageDeleteError {
+    GetVolume,
+    OpenRootDir,
+    DeleteFileInDir,
+    NotFound,
+    Index,
+}
+
+#[derive(Debug)]
+pub enum StorageLibraryError {
+    NotFound,
+    AlreadyExists,
+    InvalidName,
+    ListDir,
+    OpenFile,
+    Index,
+    Write,
+}
+
+/// Opens the library on the first FAT volume of `block_device`.
+pub fn open_storage<D: BlockDevice, C: TimeSource>(
+    block_device: D,
+    time_source: C,
+) -> Result<StorageWrapper<D, C>, StorageDeleteError> {
+    let mut volume_manager = VolumeManager::new(block_device, time_source);
+
+    let volume = volume_manager.get_volume(VolumeIdx(0)).map_err(|err| {
+        error!("{err:#?}");
+        StorageDeleteError::GetVolume
+    })?;
+    let root_dir = volume_manager.open_root_dir(&volume).map_err(|err| {
+        error!("{err:#?}");
+        StorageDeleteError::OpenRootDir
+    })?;
+    let dir = match volume_manager.open_dir(&volume, &root_dir, LIBRARY_DIR_NAME) {
+        Ok(dir) => {
+            volume_manager.close_dir(&volume, root_dir);
+            dir
+        }
+        Err(err) => {
+            warn!("no /{LIBRARY_DIR_NAME} directory, keeping files in the root: {err:?}");
+            root_dir
+        }
+    };
+
+    let mut storage = StorageWrapper {
+        volume_manager: Some(volume_manager),
+        volume,
+        dir,
+    };
+    // Only leaves the files on the card if it fails.
+    if let Err(err) = storage.delete_abandoned_uploads() {
+        error!("{err:#?}");
+    }
+    Ok(storage)
+}
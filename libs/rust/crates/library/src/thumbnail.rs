@@ -0,0 +1,151 @@
+use decompress::{Decoder, Encoding};
+use gcode::{is_thumbnail_end, thumbnail_data, Base64Decoder, ThumbnailBegin, ThumbnailFormat};
+use log::warn;
+
+/// The largest image kept, as slicers also embed ones too big for the ESP
+/// to hold on to.
+pub const MAX_THUMBNAIL_LEN: usize = 32 * 1024;
+/// Longer lines are cut short, the ones of a thumbnail block are around 80
+/// characters.
+const MAX_LINE_LEN: usize = 256;
+/// How much is decompressed at a time.
+const SCAN_BUFFER_LEN: usize = 512;
+
+#[derive(Debug, Clone)]
+pub struct Thumbnail {
+    pub format: ThumbnailFormat,
+    pub width: u32,
+    pub height: u32,
+    pub image: Vec<u8>,
+}
+
+/// Picks a thumbnail out of G-code as it is uploaded: the largest one that
+/// fits [`MAX_THUMBNAIL_LEN`], preferring the formats browsers show over
+/// QOI.
+///
+/// Slicers put thumbnails before the first command, which is where looking
+/// for them stops, so the rest of a large upload costs next to nothing.
+pub struct ThumbnailExtractor {
+    decoder: Decoder,
+    line: Vec<u8>,
+    current: Option<Partial>,
+    best: Option<Thumbnail>,
+    done: bool,
+}
+
+struct Partial {
+    begin: ThumbnailBegin,
+    base64: Base64Decoder,
+    image: Vec<u8>,
+}
+
+impl ThumbnailExtractor {
+    /// `encoding` is the one the upload is stored with.
+    pub fn new(encoding: Encoding) -> Self {
+        Self {
+            decoder: Decoder::new(encoding),
+            line: Vec::new(),
+            current: None,
+            best: None,
+            done: false,
+        }
+    }
+
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        let mut buffer = [0u8; SCAN_BUFFER_LEN];
+        while !self.done {
+            let (consumed, produced) = match self.decoder.decode(bytes, &mut buffer) {
+                Ok(progress) => progress,
+                Err(err) => {
+                    warn!("Not looking for thumbnails: {err:?}");
+                    self.done = true;
+                    break;
+                }
+            };
+            bytes = &bytes[consumed..];
+            for &byte in &buffer[..produced] {
+                match byte {
+                    b'\n' => self.end_line(),
+                    _ if self.line.len() < MAX_LINE_LEN => self.line.push(byte),
+                    _ => {}
+                }
+            }
+            if consumed == 0 && produced == 0 {
+                break;
+            }
+        }
+    }
+
+    pub fn finish(self) -> Option<Thumbnail> {
+        self.best
+    }
+
+    fn end_line(&mut self) {
+        let line = core::mem::take(&mut self.line);
+        if let Ok(line) = core::str::from_utf8(&line) {
+            self.scan_line(line);
+        }
+        self.line = line;
+        self.line.clear();
+    }
+
+    fn scan_line(&mut self, line: &str) {
+        if self.done {
+            return;
+        }
+        let Some(mut partial) = self.current.take() else {
+            if let Some(begin) = ThumbnailBegin::parse(line) {
+                self.begin(begin);
+            } else if !(line.trim().is_empty() || line.trim_start().starts_with(';')) {
+                self.done = true;
+            }
+            return;
+        };
+
+        if is_thumbnail_end(line) {
+            self.best = Some(Thumbnail {
+                format: partial.begin.format,
+                width: partial.begin.width,
+                height: partial.begin.height,
+                image: partial.image,
+            });
+            return;
+        }
+        let Some(data) = thumbnail_data(line) else {
+            // The block broke off at a command.
+            self.done = true;
+            return;
+        };
+        let image = &mut partial.image;
+        let decoded = partial.base64.push(data, |byte| image.push(byte));
+        if decoded.is_ok() && partial.image.len() <= MAX_THUMBNAIL_LEN {
+            self.current = Some(partial);
+        }
+    }
+
+    /// Only starts on a thumbnail better than the best one so far, which
+    /// is dropped right away so that only one is held at a time.
+    fn begin(&mut self, begin: ThumbnailBegin) {
+        let rank = |format, width: u32, height: u32| {
+            (
+                format != ThumbnailFormat::Qoi,
+                u64::from(width) * u64::from(height),
+            )
+        };
+        let is_better = match &self.best {
+            Some(best) => {
+                rank(begin.format, begin.width, begin.height)
+                    > rank(best.format, best.width, best.height)
+            }
+            None => true,
+        };
+        if is_better && begin.decoded_len() <= MAX_THUMBNAIL_LEN {
+            self.best = None;
+            self.current = Some(Partial {
+                begin,
+                base64: Base64Decoder::new(),
+                image: Vec::with_capacity(begin.decoded_len()),
+            });
+        }
+    }
+}
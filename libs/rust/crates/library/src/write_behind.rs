@@ -0,0 +1,96 @@
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+
+use embedded_sdmmc::{BlockDevice, TimeSource};
+
+use crate::{
+    read_ahead::{Block, BLOCK_COUNT},
+    storage::{StorageLineWriterError, WrappedReaderWriter, Writer},
+};
+
+/// Collects what is written into blocks for a [`Flusher`], so that the card
+/// is written a whole number of sectors at a time.
+pub struct BlockWriter {
+    filled: SyncSender<Block>,
+    empty: Receiver<Block>,
+    block: Option<Block>,
+}
+
+/// Writes the blocks of a [`BlockWriter`] to the card, meant to be run on a
+/// thread of its own while the next block is being filled.
+pub struct Flusher {
+    filled: Receiver<Block>,
+    empty: SyncSender<Block>,
+}
+
+pub fn write_behind() -> (BlockWriter, Flusher) {
+    let (filled_sender, filled_receiver) = sync_channel(BLOCK_COUNT);
+    let (empty_sender, empty_receiver) = sync_channel(BLOCK_COUNT);
+    for _ in 0..BLOCK_COUNT {
+        empty_sender.send(Block::new()).unwrap();
+    }
+
+    let block_writer = BlockWriter {
+        filled: filled_sender,
+        empty: empty_receiver,
+        block: None,
+    };
+    let flusher = Flusher {
+        filled: filled_receiver,
+        empty: empty_sender,
+    };
+    (block_writer, flusher)
+}
+
+impl BlockWriter {
+    /// Fails once the [`Flusher`] stopped, which then tells why.
+    pub fn write(&mut self, mut bytes: &[u8]) -> Result<(), FlusherStopped> {
+        while !bytes.is_empty() {
+            let block = match &mut self.block {
+                Some(block) => block,
+                None => self
+                    .block
+                    .insert(self.empty.recv().map_err(|_| FlusherStopped)?),
+            };
+            let len = bytes.len().min(block.bytes.len() - block.len);
+            block.bytes[block.len..block.len + len].copy_from_slice(&bytes[..len]);
+            block.len += len;
+            bytes = &bytes[len..];
+
+            if block.is_full() {
+                self.send()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Hands over what is left, the [`Flusher`] returns once it is written.
+    pub fn finish(mut self) -> Result<(), FlusherStopped> {
+        self.send()
+    }
+
+    fn send(&mut self) -> Result<(), FlusherStopped> {
+        match self.block.take() {
+            Some(block) => self.filled.send(block).map_err(|_| FlusherStopped),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FlusherStopped;
+
+impl Flusher {
+    /// Writes blocks until the [`BlockWriter`] is finished or dropped.
+    pub fn run<D: BlockDevice, C: TimeSource>(
+        self,
+        mut writer: WrappedReaderWriter<Writer, D, C>,
+    ) -> Result<(), StorageLineWriterError> {
+        while let Ok(mut block) = self.filled.recv() {
+            writer.write_bytes(&block.bytes[..block.len])?;
+            block.len = 0;
+            // Can only fail once the block writer is gone.
+            let _ = self.empty.send(block);
+        }
+        Ok(())
+    }
+}
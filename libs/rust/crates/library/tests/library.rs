@@ -0,0 +1,520 @@
+use std::{sync::mpsc, thread, time::Duration};
+
+use embedded_sdmmc::{Mode, TimeSource, Timestamp, VolumeIdx, VolumeManager};
+use library::{
+    open_storage, ram_disk::RamDisk, read_ahead, write_behind, Encoding, StorageDeleteError,
+    StorageLibraryError, StorageWrapper, BLOCK_SIZE, LIBRARY_DIR_NAME,
+};
+use miniz_oxide::deflate::compress_to_vec;
+
+struct FixedTime;
+
+impl TimeSource for FixedTime {
+    fn get_timestamp(&self) -> Timestamp {
+        Timestamp {
+            year_since_1970: 56,
+            zero_indexed_month: 9,
+            zero_indexed_day: 17,
+            hours: 12,
+            minutes: 30,
+            seconds: 0,
+        }
+    }
+}
+
+type Library = StorageWrapper<RamDisk, FixedTime>;
+
+fn library() -> Library {
+    open_storage(RamDisk::formatted(), FixedTime).unwrap()
+}
+
+fn upload(library: &mut Library, name: &str, contents: &[u8]) {
+    let (upload, mut writer) = library.begin_upload(name).unwrap();
+    writer.write_bytes(contents).unwrap();
+    drop(writer);
+    library.commit_upload(upload).unwrap();
+}
+
+/// Writes files to the library's directory behind its back, like the
+/// firmware would have left them when the power went, and opens it again.
+fn write_behind_the_library(library: Library, files: &[(&str, &[u8])]) -> Library {
+    let (block_device, time_source) = library.free();
+    let mut volume_manager = VolumeManager::new(block_device, time_source);
+    let mut volume = volume_manager.get_volume(VolumeIdx(0)).unwrap();
+    let root_dir = volume_manager.open_root_dir(&volume).unwrap();
+    let dir = volume_manager
+        .open_dir(&volume, &root_dir, LIBRARY_DIR_NAME)
+        .unwrap();
+    for (short_name, contents) in files {
+        let mut file = volume_manager
+            .open_file_in_dir(
+                &mut volume,
+                &dir,
+                short_name,
+                Mode::ReadWriteCreateOrTruncate,
+            )
+            .unwrap();
+        volume_manager
+            .write(&mut volume, &mut file, contents)
+            .unwrap();
+        volume_manager.close_file(&volume, file).unwrap();
+    }
+    volume_manager.close_dir(&volume, dir);
+    volume_manager.close_dir(&volume, root_dir);
+
+    let (block_device, time_source) = volume_manager.free();
+    open_storage(block_device, time_source).unwrap()
+}
+
+fn read_all(library: &mut Library, name: &str) -> Vec<u8> {
+    let mut reader = library.get_reader(name).unwrap();
+    let mut contents = Vec::new();
+    let mut buffer = [0u8; 100];
+    loop {
+        match reader.read_chunk(&mut buffer).unwrap() {
+            0 => return contents,
+            num_read => contents.extend_from_slice(&buffer[..num_read]),
+        }
+    }
+}
+
+/// Reads the lines of `name` from `offset` on, with where each one ends.
+fn read_lines(library: &mut Library, name: &str, offset: u32) -> Vec<(String, u32)> {
+    let mut reader = library.get_reader(name).unwrap();
+    reader.seek(offset).unwrap();
+    let (prefetcher, mut lines) = read_ahead(offset, Encoding::from_file_name(name));
+    thread::scope(|scope| {
+        scope.spawn(move || prefetcher.run(reader));
+        let mut read = Vec::new();
+        while let Some(line) = lines.read_line().unwrap() {
+            let line = line.to_string();
+            read.push((line, lines.offset()));
+        }
+        read
+    })
+}
+
+/// A gzip member with the smallest header there is.
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![0x1F, 0x8B, 8, 0, 0, 0, 0, 0, 0, 0xFF];
+    encoded.extend(compress_to_vec(data, 6));
+    encoded.extend(crc32(data).to_le_bytes());
+    encoded.extend((data.len() as u32).to_le_bytes());
+    encoded
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte), |crc, _| {
+            (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+/// Lines of all sorts of lengths, so that some of them cross the blocks
+/// they are read in.
+fn many_lines() -> String {
+    (0..2000)
+        .map(|index| format!("G1 X{index} Y{}{}\n", index % 7, ";".repeat(index % 13)))
+        .collect()
+}
+
+#[test]
+fn reads_back_what_was_uploaded() {
+    let mut library = library();
+    let contents: Vec<u8> = (0..3000u32).map(|index| (index * 31 % 256) as u8).collect();
+    upload(&mut library, "model.gcode", &contents);
+
+    assert_eq!(read_all(&mut library, "model.gcode"), contents);
+}
+
+#[test]
+fn lists_files_by_their_long_names() {
+    let mut library = library();
+    upload(&mut library, "My Benchy.gcode", b"G28\n");
+
+    let files = library.list().unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].name, "My Benchy.gcode");
+    assert_eq!(files[0].short_name, "MYBENC~1.UPL");
+    assert_eq!(files[0].size, 4);
+    assert_eq!(files[0].modified, "2026-10-18T12:30:00");
+}
+
+#[test]
+fn keeps_the_old_file_until_an_upload_is_committed() {
+    let mut library = library();
+    upload(&mut library, "model.gcode", b"old\n");
+
+    let (pending, mut writer) = library.begin_upload("model.gcode").unwrap();
+    writer.write_bytes(b"new\n").unwrap();
+    drop(writer);
+    assert_eq!(read_all(&mut library, "model.gcode"), b"old\n");
+    library.abort_upload(pending).unwrap();
+    assert_eq!(read_all(&mut library, "model.gcode"), b"old\n");
+
+    upload(&mut library, "model.gcode", b"new\n");
+    assert_eq!(read_all(&mut library, "model.gcode"), b"new\n");
+    assert_eq!(library.list().unwrap().len(), 1);
+}
+
+#[test]
+fn leaves_uploads_out_of_the_list_until_committed() {
+    let mut library = library();
+    let (mut pending, mut writer) = library.begin_upload("model.gcode").unwrap();
+    writer.write_bytes(b"G28\n").unwrap();
+    drop(writer);
+    library.attach_thumbnail(&mut pending, b"\x89PNG").unwrap();
+
+    assert!(library.list().unwrap().is_empty());
+    library.commit_upload(pending).unwrap();
+    assert_eq!(library.list().unwrap().len(), 1);
+}
+
+#[test]
+fn deletes_unfinished_uploads_when_opened() {
+    let mut library = library();
+    let (mut pending, mut writer) = library.begin_upload("model.gcode").unwrap();
+    writer.write_bytes(b"G28\n").unwrap();
+    drop(writer);
+    library.attach_thumbnail(&mut pending, b"\x89PNG").unwrap();
+
+    let (block_device, time_source) = library.free();
+    let mut library = open_storage(block_device, time_source).unwrap();
+    // The names of the deleted files are free again.
+    upload(&mut library, "model.gcode", b"G1 X10\n");
+    let files = library.list().unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].short_name, "MODEL.UPL");
+    assert_eq!(read_all(&mut library, "model.gcode"), b"G1 X10\n");
+}
+
+#[test]
+fn resumes_an_upload_where_it_stopped() {
+    let mut library = library();
+    let (pending, mut writer) = library.begin_upload("model.gcode").unwrap();
+    writer.write_bytes(b"G28\n").unwrap();
+    drop(writer);
+
+    let mut writer = library.resume_upload(&pending).unwrap();
+    assert_eq!(writer.file_size_in_bytes(), 4);
+    writer.write_bytes(b"G1 X10\n").unwrap();
+    assert_eq!(writer.file_size_in_bytes(), 11);
+    drop(writer);
+    library.commit_upload(pending).unwrap();
+
+    assert_eq!(read_all(&mut library, "model.gcode"), b"G28\nG1 X10\n");
+}
+
+#[test]
+fn keeps_thumbnails_next_to_their_files() {
+    let mut library = library();
+    let (mut pending, mut writer) = library.begin_upload("model.gcode").unwrap();
+    writer.write_bytes(b"G28\n").unwrap();
+    drop(writer);
+    library.attach_thumbnail(&mut pending, b"\x89PNG").unwrap();
+    library.commit_upload(pending).unwrap();
+
+    let files = library.list().unwrap();
+    assert_eq!(files.len(), 1);
+    assert!(files[0].thumbnail);
+    let mut thumbnail = Vec::new();
+    let mut reader = library.get_thumbnail("model.gcode").unwrap();
+    let mut buffer = [0u8; 16];
+    let num_read = reader.read_chunk(&mut buffer).unwrap();
+    thumbnail.extend_from_slice(&buffer[..num_read]);
+    drop(reader);
+    assert_eq!(thumbnail, b"\x89PNG");
+
+    library.rename("model.gcode", "benchy.gcode").unwrap();
+    assert!(library.get_thumbnail("benchy.gcode").is_ok());
+
+    // The file it is replaced with has none.
+    upload(&mut library, "benchy.gcode", b"G28\n");
+    assert!(matches!(
+        library.get_thumbnail("benchy.gcode"),
+        Err(StorageLibraryError::NotFound)
+    ));
+    assert_eq!(library.list().unwrap().len(), 1);
+}
+
+#[test]
+fn deletes_thumbnails_with_their_files() {
+    let mut library = library();
+    let (mut pending, writer) = library.begin_upload("model.gcode").unwrap();
+    drop(writer);
+    library.attach_thumbnail(&mut pending, b"\x89PNG").unwrap();
+    library.commit_upload(pending).unwrap();
+
+    library.delete("model.gcode").unwrap();
+
+    assert!(matches!(
+        library.get_thumbnail("model.gcode"),
+        Err(StorageLibraryError::NotFound)
+    ));
+    assert!(library.list().unwrap().is_empty());
+}
+
+#[test]
+fn aborts_uploads_with_their_thumbnails() {
+    let mut library = library();
+    for _ in 0..5 {
+        let (mut pending, writer) = library.begin_upload("model.gcode").unwrap();
+        drop(writer);
+        library.attach_thumbnail(&mut pending, b"\x89PNG").unwrap();
+        library.abort_upload(pending).unwrap();
+    }
+
+    assert!(library.list().unwrap().is_empty());
+    upload(&mut library, "model.gcode", b"G28\n");
+    assert_eq!(read_all(&mut library, "model.gcode"), b"G28\n");
+}
+
+#[test]
+fn deletes_files() {
+    let mut library = library();
+    upload(&mut library, "model.gcode", b"G28\n");
+
+    library.delete("model.gcode").unwrap();
+
+    assert!(matches!(
+        library.get_reader("model.gcode"),
+        Err(StorageLibraryError::NotFound)
+    ));
+    assert!(matches!(
+        library.delete("model.gcode"),
+        Err(StorageDeleteError::NotFound)
+    ));
+    assert!(library.list().unwrap().is_empty());
+}
+
+/// `embedded-sdmmc` only has room for a few open files, each upload and
+/// rewrite of the index has to leave it as it was.
+#[test]
+fn uploads_more_files_than_can_be_open_at_once() {
+    let mut library = library();
+    for index in 0..10 {
+        upload(&mut library, &format!("{index}.gcode"), b"G28\n");
+    }
+    upload(&mut library, "empty.gcode", b"");
+
+    assert_eq!(library.list().unwrap().len(), 11);
+    assert_eq!(read_all(&mut library, "9.gcode"), b"G28\n");
+    assert!(read_all(&mut library, "empty.gcode").is_empty());
+    library.delete("empty.gcode").unwrap();
+}
+
+#[test]
+fn renames_files() {
+    let mut library = library();
+    upload(&mut library, "a.gcode", b"a\n");
+    upload(&mut library, "b.gcode", b"b\n");
+
+    assert!(matches!(
+        library.rename("a.gcode", "b.gcode"),
+        Err(StorageLibraryError::AlreadyExists)
+    ));
+    library.rename("a.gcode", "c.gcode").unwrap();
+
+    assert!(matches!(
+        library.get_reader("a.gcode"),
+        Err(StorageLibraryError::NotFound)
+    ));
+    assert_eq!(read_all(&mut library, "c.gcode"), b"a\n");
+}
+
+#[test]
+fn restores_an_index_whose_rewrite_was_cut_short() {
+    let mut library = library();
+    upload(&mut library, "My Benchy.gcode", b"G28\n");
+    let mut library = write_behind_the_library(
+        library,
+        &[
+            ("INDEX.TMP", b"MYBENC~1.UPL\tMy Benchy.gcode\n# end\n"),
+            ("INDEX.TXT", b"MYBEN"),
+        ],
+    );
+
+    let files = library.list().unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].name, "My Benchy.gcode");
+    assert_eq!(read_all(&mut library, "My Benchy.gcode"), b"G28\n");
+}
+
+#[test]
+fn ignores_an_index_copy_that_was_cut_short() {
+    let mut library = library();
+    upload(&mut library, "My Benchy.gcode", b"G28\n");
+    let mut library = write_behind_the_library(library, &[("INDEX.TMP", b"MYBENC~1.UPL\tOther")]);
+
+    let files = library.list().unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].name, "My Benchy.gcode");
+}
+
+#[test]
+fn rejects_names_the_index_cannot_hold() {
+    let mut library = library();
+    for name in ["", "a\tb", "a\nb", "a.gcode.thumbnail"] {
+        assert!(matches!(
+            library.begin_upload(name),
+            Err(StorageLibraryError::InvalidName)
+        ));
+    }
+}
+
+#[test]
+fn splits_lines_crossing_blocks() {
+    let mut library = library();
+    let contents = many_lines();
+    assert!(contents.len() > 2 * BLOCK_SIZE);
+    upload(&mut library, "model.gcode", contents.as_bytes());
+
+    let lines = read_lines(&mut library, "model.gcode", 0);
+
+    let expected: Vec<&str> = contents.split_inclusive('\n').collect();
+    assert_eq!(lines.len(), expected.len());
+    let mut end = 0;
+    for ((line, offset), expected) in lines.iter().zip(expected) {
+        end += expected.len() as u32;
+        assert_eq!(line, expected);
+        assert_eq!(*offset, end);
+    }
+}
+
+#[test]
+fn continues_at_a_line_offset() {
+    let mut library = library();
+    let contents = many_lines();
+    upload(&mut library, "model.gcode", contents.as_bytes());
+    let offset = contents[..BLOCK_SIZE + 100].rfind('\n').unwrap() + 1;
+
+    let lines = read_lines(&mut library, "model.gcode", offset as u32);
+
+    let expected: Vec<&str> = contents[offset..].split_inclusive('\n').collect();
+    let lines: Vec<&str> = lines.iter().map(|(line, _)| line.as_str()).collect();
+    assert_eq!(lines, expected);
+}
+
+#[test]
+fn reads_a_last_line_without_newline() {
+    let mut library = library();
+    upload(&mut library, "model.gcode", b"G28\nM84");
+
+    let lines = read_lines(&mut library, "model.gcode", 0);
+
+    assert_eq!(
+        lines,
+        [("G28\n".to_string(), 4), ("M84".to_string(), 7)].to_vec()
+    );
+}
+
+#[test]
+fn reads_no_lines_from_an_empty_file() {
+    let mut library = library();
+    upload(&mut library, "empty.gcode", b"");
+
+    assert!(read_lines(&mut library, "empty.gcode", 0).is_empty());
+}
+
+/// A print that is cancelled stops reading lines half way through the file,
+/// the prefetcher has to give up then rather than wait for an empty block.
+#[test]
+fn stops_prefetching_once_the_lines_are_dropped() {
+    let (done_sender, done) = mpsc::channel();
+    thread::spawn(move || {
+        let mut library = library();
+        upload(&mut library, "model.gcode", many_lines().as_bytes());
+        let reader = library.get_reader("model.gcode").unwrap();
+        let (prefetcher, mut lines) = read_ahead(0, Encoding::Identity);
+        thread::scope(|scope| {
+            scope.spawn(move || prefetcher.run(reader));
+            assert!(lines.read_line().unwrap().is_some());
+            drop(lines);
+        });
+        done_sender.send(()).unwrap();
+    });
+
+    assert!(done.recv_timeout(Duration::from_secs(10)).is_ok());
+}
+
+#[test]
+fn writes_behind_in_blocks() {
+    let mut library = library();
+    let contents = many_lines();
+
+    let (upload, writer) = library.begin_upload("model.gcode").unwrap();
+    let (mut block_writer, flusher) = write_behind();
+    thread::scope(|scope| {
+        let flushing = scope.spawn(move || flusher.run(writer));
+        for chunk in contents.as_bytes().chunks(1000) {
+            block_writer.write(chunk).unwrap();
+        }
+        block_writer.finish().unwrap();
+        flushing.join().unwrap().unwrap();
+    });
+    library.commit_upload(upload).unwrap();
+
+    assert_eq!(read_all(&mut library, "model.gcode"), contents.as_bytes());
+}
+
+#[test]
+fn decompresses_gzip_files_as_they_are_read() {
+    let mut library = library();
+    let contents = many_lines();
+    let compressed = gzip(contents.as_bytes());
+    upload(&mut library, "model.gcode.gz", &compressed);
+
+    let lines = read_lines(&mut library, "model.gcode.gz", 0);
+
+    let expected: Vec<&str> = contents.split_inclusive('\n').collect();
+    let lines: Vec<&str> = lines.iter().map(|(line, _)| line.as_str()).collect();
+    assert_eq!(lines, expected);
+    assert_eq!(library.list().unwrap()[0].size, compressed.len() as u32);
+}
+
+#[test]
+fn follows_how_far_into_a_compressed_file_lines_reach() {
+    let mut library = library();
+    let contents = many_lines();
+    let compressed = gzip(contents.as_bytes());
+    upload(&mut library, "model.gcode.gz", &compressed);
+    let reader = library.get_reader("model.gcode.gz").unwrap();
+
+    let (prefetcher, mut lines) = read_ahead(0, Encoding::Gzip);
+    thread::scope(|scope| {
+        scope.spawn(move || prefetcher.run(reader));
+        let mut source_offset = 0;
+        while lines.read_line().unwrap().is_some() {
+            assert!(lines.source_offset() >= source_offset);
+            source_offset = lines.source_offset();
+        }
+        assert_eq!(source_offset, compressed.len() as u32);
+    });
+}
+
+#[test]
+fn skips_to_a_line_offset_in_a_compressed_file() {
+    let mut library = library();
+    let contents = many_lines();
+    upload(&mut library, "model.gcode.gz", &gzip(contents.as_bytes()));
+    let offset = contents[..BLOCK_SIZE + 100].rfind('\n').unwrap() + 1;
+    let reader = library.get_reader("model.gcode.gz").unwrap();
+
+    let (prefetcher, lines) = read_ahead(0, Encoding::Gzip);
+    let next_line = thread::scope(|scope| {
+        scope.spawn(move || prefetcher.run(reader));
+        // Dropped before the prefetcher is waited for, which stops it.
+        let mut lines = lines;
+        while lines.offset() < offset as u32 {
+            lines.read_line().unwrap();
+        }
+        assert_eq!(lines.offset(), offset as u32);
+        lines.read_line().unwrap().unwrap().to_string()
+    });
+
+    assert_eq!(
+        next_line,
+        contents[offset..].split_inclusive('\n').next().unwrap()
+    );
+}
@@ -0,0 +1,96 @@
+use library::{Encoding, ThumbnailExtractor, ThumbnailFormat, MAX_THUMBNAIL_LEN};
+use miniz_oxide::deflate::compress_to_vec;
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (index, &byte)| {
+            bits | u32::from(byte) << (16 - 8 * index)
+        });
+        for index in 0..4 {
+            if index <= chunk.len() {
+                text.push(ALPHABET[(bits >> (18 - 6 * index) & 63) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+/// A block the way PrusaSlicer writes it, in lines of 78 characters.
+fn block(keyword: &str, width: u32, height: u32, image: &[u8]) -> String {
+    let text = base64(image);
+    let mut block = format!("; {keyword} begin {width}x{height} {}\n", text.len());
+    for line in text.as_bytes().chunks(78) {
+        block += &format!("; {}\n", std::str::from_utf8(line).unwrap());
+    }
+    block + &format!("; {keyword} end\n;\n\n")
+}
+
+fn extract(encoding: Encoding, gcode: &[u8]) -> Option<library::Thumbnail> {
+    let mut extractor = ThumbnailExtractor::new(encoding);
+    for chunk in gcode.chunks(100) {
+        extractor.update(chunk);
+    }
+    extractor.finish()
+}
+
+fn image(len: usize) -> Vec<u8> {
+    (0..len).map(|index| (index * 7 % 251) as u8).collect()
+}
+
+#[test]
+fn keeps_the_largest_thumbnail() {
+    let gcode = [
+        "; generated by PrusaSlicer\n\n".to_string(),
+        block("thumbnail", 16, 16, &image(300)),
+        block("thumbnail", 220, 124, &image(5000)),
+        block("thumbnail_QOI", 300, 300, &image(9000)),
+        "M73 P0 R12\nG28\n".to_string(),
+    ]
+    .concat();
+
+    let thumbnail = extract(Encoding::Identity, gcode.as_bytes()).unwrap();
+
+    assert_eq!(thumbnail.format, ThumbnailFormat::Png);
+    assert_eq!((thumbnail.width, thumbnail.height), (220, 124));
+    assert_eq!(thumbnail.image, image(5000));
+}
+
+#[test]
+fn skips_thumbnails_too_large_to_keep() {
+    let gcode = [
+        block("thumbnail", 16, 16, &image(300)),
+        block("thumbnail", 640, 480, &image(MAX_THUMBNAIL_LEN + 1)),
+    ]
+    .concat();
+
+    let thumbnail = extract(Encoding::Identity, gcode.as_bytes()).unwrap();
+
+    assert_eq!((thumbnail.width, thumbnail.height), (16, 16));
+}
+
+#[test]
+fn stops_looking_at_the_first_command() {
+    let gcode = ["G28\n".to_string(), block("thumbnail", 16, 16, &image(300))].concat();
+
+    assert!(extract(Encoding::Identity, gcode.as_bytes()).is_none());
+}
+
+#[test]
+fn finds_thumbnails_in_compressed_uploads() {
+    let gcode = [
+        block("thumbnail", 16, 16, &image(300)),
+        "G28\n".repeat(1000),
+    ]
+    .concat();
+    let mut compressed = vec![0x1F, 0x8B, 8, 0, 0, 0, 0, 0, 0, 0xFF];
+    compressed.extend(compress_to_vec(gcode.as_bytes(), 6));
+    compressed.extend([0; 8]);
+
+    let thumbnail = extract(Encoding::Gzip, &compressed).unwrap();
+
+    assert_eq!(thumbnail.image, image(300));
+}
@@ -0,0 +1,241 @@
+//! A scripted stand-in for a printer running Marlin, to exercise
+//! [`SerialWrapper`](crate::SerialWrapper) without hardware.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+};
+
+use embedded_hal::serial;
+
+/// Speaks enough of Marlin's serial protocol to test a host against.
+///
+/// Lines are checked for their number and checksum the way Marlin does,
+/// answering with `Error:`/`Resend:` and flushing what is still in flight.
+/// Commands are only executed while the host is reading, so lines pile up in
+/// the command buffer just like on a real printer.
+pub struct FakeMarlin {
+    buffer_size: usize,
+    advanced_ok: bool,
+    receiving: Vec<u8>,
+    received: VecDeque<String>,
+    queue: VecDeque<String>,
+    output: VecDeque<u8>,
+    last_line_number: u32,
+    corrupt_lines: Vec<u32>,
+    busy: HashMap<String, usize>,
+    replies: HashMap<String, Vec<String>>,
+    executed: Vec<String>,
+    max_in_flight: usize,
+    restarts_on: Option<String>,
+}
+
+impl FakeMarlin {
+    pub fn new() -> Self {
+        Self {
+            buffer_size: 4,
+            advanced_ok: false,
+            receiving: Vec::new(),
+            received: VecDeque::new(),
+            queue: VecDeque::new(),
+            output: VecDeque::new(),
+            last_line_number: 0,
+            corrupt_lines: Vec::new(),
+            busy: HashMap::new(),
+            replies: HashMap::new(),
+            executed: Vec::new(),
+            max_in_flight: 0,
+            restarts_on: None,
+        }
+    }
+
+    /// Size of the command buffer, `BUFSIZE` in Marlin's configuration.
+    pub fn with_buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    /// Adds the `N`, `P` and `B` fields to every `ok`.
+    pub fn with_advanced_ok(mut self) -> Self {
+        self.advanced_ok = true;
+        self
+    }
+
+    /// Garbles the next transmission of line `number` on its way in.
+    pub fn corrupt_line(mut self, number: u32) -> Self {
+        self.corrupt_lines.push(number);
+        self
+    }
+
+    /// Reports `echo:busy: processing` `count` times before acknowledging
+    /// `command`.
+    pub fn busy(mut self, command: &str, count: usize) -> Self {
+        self.busy.insert(command.to_string(), count);
+        self
+    }
+
+    /// Restarts instead of executing `command`, like a printer that is reset
+    /// while lines are in flight: they are lost without an `ok`, and line
+    /// numbers start over.
+    pub fn restarts_on(mut self, command: &str) -> Self {
+        self.restarts_on = Some(command.to_string());
+        self
+    }
+
+    /// Answers `command` with `lines` before its `ok`.
+    pub fn reply(mut self, command: &str, lines: &[&str]) -> Self {
+        self.replies.insert(
+            command.to_string(),
+            lines.iter().map(ToString::to_string).collect(),
+        );
+        self
+    }
+
+    /// Sends `line` unprompted, like an auto-report.
+    pub fn send(&mut self, line: &str) {
+        self.output.extend(line.bytes());
+        self.output.push_back(b'\n');
+    }
+
+    /// Every command executed so far, without line number and checksum.
+    pub fn executed(&self) -> &[String] {
+        &self.executed
+    }
+
+    /// The most lines that were ever waiting on the printer at once.
+    pub fn max_in_flight(&self) -> usize {
+        self.max_in_flight
+    }
+
+    fn receive(&mut self, mut line: String) {
+        let number = line_number(&line);
+        if let Some(index) = self.corrupt_lines.iter().position(|n| Some(*n) == number) {
+            self.corrupt_lines.swap_remove(index);
+            line = line.replacen('G', "W", 1).replacen('M', "Q", 1);
+        }
+
+        self.received.push_back(line);
+        self.max_in_flight = self
+            .max_in_flight
+            .max(self.received.len() + self.queue.len());
+    }
+
+    /// Moves received lines into the command buffer, then executes the oldest
+    /// buffered command.
+    fn tick(&mut self) {
+        while self.queue.len() < self.buffer_size {
+            let Some(line) = self.received.pop_front() else {
+                break;
+            };
+            match self.validate(&line) {
+                Ok(command) => self.queue.push_back(command),
+                Err(error) => {
+                    self.send(&format!(
+                        "Error:{error}, Last Line: {}",
+                        self.last_line_number
+                    ));
+                    self.send(&format!("Resend: {}", self.last_line_number + 1));
+                    self.send("ok");
+                    self.received.clear();
+                    return;
+                }
+            }
+        }
+
+        let Some(command) = self.queue.pop_front() else {
+            return;
+        };
+        let word = command.split_whitespace().next().unwrap_or_default();
+        if self.restarts_on.as_deref() == Some(word) {
+            self.restarts_on = None;
+            self.received.clear();
+            self.queue.clear();
+            self.output.clear();
+            self.last_line_number = 0;
+            return;
+        }
+
+        for _ in 0..self.busy.get(word).copied().unwrap_or_default() {
+            self.send("echo:busy: processing");
+        }
+        for reply in self.replies.get(word).cloned().unwrap_or_default() {
+            self.send(&reply);
+        }
+        if !(word.starts_with('G') || word.starts_with('M') || word.starts_with('T')) {
+            self.send(&format!("echo:Unknown command: \"{command}\""));
+        }
+
+        if self.advanced_ok {
+            let buffer_free = self.buffer_size - self.queue.len() - 1;
+            self.send(&format!("ok N{} P15 B{buffer_free}", self.last_line_number));
+        } else {
+            self.send("ok");
+        }
+        self.executed.push(command);
+    }
+
+    fn validate(&mut self, line: &str) -> Result<String, &'static str> {
+        let Some((content, checksum)) = line.rsplit_once('*') else {
+            return Err("No Checksum with line number");
+        };
+        let expected = content.bytes().fold(0u8, |checksum, byte| checksum ^ byte);
+        if checksum.trim().parse() != Ok(expected) {
+            return Err("checksum mismatch");
+        }
+
+        let (number, command) = content.split_once(' ').unwrap_or((content, ""));
+        let Some(Ok(number)) = number.strip_prefix('N').map(str::parse::<u32>) else {
+            return Err("No Line Number with checksum");
+        };
+
+        if let Some(new_number) = command.strip_prefix("M110 N") {
+            self.last_line_number = new_number.parse().unwrap_or(number);
+        } else if number != self.last_line_number + 1 {
+            return Err("Line Number is not Last Line Number+1");
+        } else {
+            self.last_line_number = number;
+        }
+
+        Ok(command.to_string())
+    }
+}
+
+impl Default for FakeMarlin {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn line_number(line: &str) -> Option<u32> {
+    line.strip_prefix('N')?.split(' ').next()?.parse().ok()
+}
+
+impl serial::Write<u8> for FakeMarlin {
+    type Error = Infallible;
+
+    fn write(&mut self, byte: u8) -> nb::Result<(), Self::Error> {
+        if byte == b'\n' {
+            let line = String::from_utf8_lossy(&self.receiving).into_owned();
+            self.receiving.clear();
+            self.receive(line);
+        } else {
+            self.receiving.push(byte);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl serial::Read<u8> for FakeMarlin {
+    type Error = Infallible;
+
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        if self.output.is_empty() {
+            self.tick();
+        }
+        self.output.pop_front().ok_or(nb::Error::WouldBlock)
+    }
+}
@@ -0,0 +1,11 @@
+//! Host side of the Marlin serial protocol: numbered and checksummed lines,
+//! resends and streaming against Marlin's command buffer.
+//!
+//! [`SerialWrapper`] works over any embedded-hal serial port, so it can be
+//! tested against [`fake_marlin::FakeMarlin`] on the host.
+
+pub mod fake_marlin;
+mod serial;
+pub mod temperature;
+
+pub use serial::{Monitor, SerialLineError, SerialWrapper};
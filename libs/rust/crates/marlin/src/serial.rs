@@ -0,0 +1,471 @@
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use embedded_hal::{blocking::delay::DelayMs, serial, watchdog::Watchdog};
+use log::{error, info, warn};
+
+use crate::temperature::Temperatures;
+
+/// How many sent lines are kept around so Marlin can ask for them again.
+const RESEND_HISTORY_LEN: usize = 64;
+
+/// `BUFSIZE` of the stock Ender 3 firmware. Used until an `ADVANCED_OK`
+/// response tells us the real size of Marlin's command queue.
+const DEFAULT_BUFFER_SIZE: usize = 4;
+
+/// How long Marlin may stay silent while lines are waiting on it. Busy
+/// commands report `echo:busy:` every couple of seconds, so only a printer
+/// that was reset or disconnected stays silent this long.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Gets to see every line Marlin sends and may inject commands of its own,
+/// like a serial console does.
+pub trait Monitor: Send {
+    fn on_response(&mut self, response: &str);
+
+    /// Commands to send the next time the serial port is polled.
+    fn take_commands(&mut self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+pub struct SerialWrapper<U, D> {
+    uart: U,
+    delay: D,
+    read_line_buffer: Vec<u8>,
+    line_number: u32,
+    sent_lines: VecDeque<(u32, String)>,
+    /// Lines written to Marlin that haven't been acknowledged with `ok` yet.
+    outstanding: usize,
+    buffer_size: usize,
+    resend_queue: VecDeque<(u32, String)>,
+    pending_resend: Option<PendingResend>,
+    last_resend_request: u32,
+    /// Resend requests for the same line Marlin is still going to send, one
+    /// for every line that was in flight behind the rejected one.
+    duplicate_resends: usize,
+    monitor: Option<Box<dyn Monitor>>,
+    /// Collects Marlin's answers while a [`SerialWrapper::query`] is running.
+    responses: Option<Vec<String>>,
+    halted: Arc<AtomicBool>,
+    /// When a line was last sent or a response last read.
+    last_activity: Instant,
+    response_timeout: Duration,
+}
+
+enum PendingResend {
+    From(u32),
+    Duplicate,
+}
+
+impl<U, D> SerialWrapper<U, D>
+where
+    U: serial::Read<u8> + serial::Write<u8>,
+    <U as serial::Read<u8>>::Error: Debug,
+    <U as serial::Write<u8>>::Error: Debug,
+    D: DelayMs<u32>,
+{
+    pub fn new(uart: U, delay: D) -> Self {
+        Self {
+            uart,
+            delay,
+            read_line_buffer: Vec::new(),
+            line_number: 0,
+            sent_lines: VecDeque::with_capacity(RESEND_HISTORY_LEN),
+            outstanding: 0,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            resend_queue: VecDeque::new(),
+            pending_resend: None,
+            last_resend_request: 0,
+            duplicate_resends: 0,
+            monitor: None,
+            responses: None,
+            halted: Arc::new(AtomicBool::new(false)),
+            last_activity: Instant::now(),
+            response_timeout: RESPONSE_TIMEOUT,
+        }
+    }
+
+    pub fn set_monitor(&mut self, monitor: impl Monitor + 'static) {
+        self.monitor = Some(Box::new(monitor));
+    }
+
+    /// How long Marlin may stay silent before waiting on it fails with
+    /// [`SerialLineError::Timeout`], [`RESPONSE_TIMEOUT`] unless set.
+    pub fn set_response_timeout(&mut self, timeout: Duration) {
+        self.response_timeout = timeout;
+    }
+
+    /// A flag that stops the wrapper from sending anything once set, making
+    /// whoever is waiting on Marlin return [`SerialLineError::Halted`].
+    ///
+    /// Meant to be set from another thread after an emergency stop, as
+    /// Marlin won't acknowledge anything until it is reset.
+    pub fn halt_flag(&self) -> Arc<AtomicBool> {
+        self.halted.clone()
+    }
+
+    /// Gives back the serial port and delay.
+    pub fn release(self) -> (U, D) {
+        (self.uart, self.delay)
+    }
+
+    /// Tells Marlin to restart its line numbering with `M110`.
+    ///
+    /// Should be sent before every print, as Marlin remembers the last line
+    /// number across host reconnects.
+    pub fn reset_line_number(
+        &mut self,
+        watchdog: &mut impl Watchdog,
+    ) -> Result<(), SerialLineError> {
+        self.drain(watchdog)?;
+        self.line_number = 0;
+        self.sent_lines.clear();
+        self.send_numbered(0, "M110 N0")?;
+        self.drain(watchdog)
+    }
+
+    /// Queues a line on the printer.
+    ///
+    /// Returns as soon as the line is written, only blocking while Marlin's
+    /// command buffer is full, so the planner is kept fed while streaming.
+    pub fn write(
+        &mut self,
+        line: impl AsRef<str>,
+        watchdog: &mut impl Watchdog,
+    ) -> Result<(), SerialLineError> {
+        let Some(command) = executable(line.as_ref()) else {
+            return Ok(());
+        };
+
+        loop {
+            self.send_resends()?;
+            if self.resend_queue.is_empty() && self.outstanding < self.buffer_size {
+                break;
+            }
+            self.wait_for_response(watchdog)?;
+        }
+
+        self.line_number += 1;
+        self.send_numbered(self.line_number, &command)
+    }
+
+    /// Blocks until Marlin has acknowledged every line written so far.
+    pub fn drain(&mut self, watchdog: &mut impl Watchdog) -> Result<(), SerialLineError> {
+        loop {
+            self.send_resends()?;
+            if self.resend_queue.is_empty() && self.outstanding == 0 {
+                return Ok(());
+            }
+            self.wait_for_response(watchdog)?;
+        }
+    }
+
+    /// Sends `command` once every earlier line is acknowledged and returns
+    /// what Marlin answered before its `ok`.
+    pub fn query(
+        &mut self,
+        command: impl AsRef<str>,
+        watchdog: &mut impl Watchdog,
+    ) -> Result<Vec<String>, SerialLineError> {
+        self.drain(watchdog)?;
+        self.responses = Some(Vec::new());
+        let result = self
+            .write(command, watchdog)
+            .and_then(|()| self.drain(watchdog));
+        let responses = self.responses.take().unwrap_or_default();
+        result.map(|()| responses)
+    }
+
+    /// Sends the commands injected by the [`Monitor`] and handles everything
+    /// Marlin sent on its own, like temperature auto-reports.
+    pub fn poll(&mut self, watchdog: &mut impl Watchdog) -> Result<(), SerialLineError> {
+        let commands = self
+            .monitor
+            .as_mut()
+            .map(|monitor| monitor.take_commands())
+            .unwrap_or_default();
+        for command in commands {
+            self.write(command, watchdog)?;
+        }
+
+        while let Some(response) = self.read()? {
+            self.handle_response(&response, watchdog)?;
+        }
+        Ok(())
+    }
+
+    /// Frames `command` as `N<number> <command>*<checksum>`, remembers it for
+    /// resends and writes it to the UART.
+    fn send_numbered(&mut self, number: u32, command: &str) -> Result<(), SerialLineError> {
+        if self.sent_lines.len() == RESEND_HISTORY_LEN {
+            self.sent_lines.pop_front();
+        }
+        self.sent_lines.push_back((number, command.to_string()));
+        self.transmit(number, command)
+    }
+
+    fn transmit(&mut self, number: u32, command: &str) -> Result<(), SerialLineError> {
+        self.check_halted()?;
+        self.inner_write(frame_line(number, command))?;
+        self.outstanding += 1;
+        self.last_activity = Instant::now();
+        Ok(())
+    }
+
+    fn send_resends(&mut self) -> Result<(), SerialLineError> {
+        while self.outstanding < self.buffer_size {
+            let Some((number, command)) = self.resend_queue.pop_front() else {
+                break;
+            };
+            self.transmit(number, &command)?;
+        }
+        Ok(())
+    }
+
+    /// Handles the next response from Marlin, giving other tasks a moment
+    /// to run if there is none yet.
+    fn wait_for_response(&mut self, watchdog: &mut impl Watchdog) -> Result<(), SerialLineError> {
+        self.check_halted()?;
+        match self.read()? {
+            Some(response) => self.handle_response(&response, watchdog),
+            None if self.last_activity.elapsed() >= self.response_timeout => {
+                error!(
+                    "no response from Marlin in {:?}, {} lines unacknowledged",
+                    self.response_timeout, self.outstanding
+                );
+                Err(SerialLineError::Timeout)
+            }
+            None => {
+                self.delay.delay_ms(1);
+                Ok(())
+            }
+        }
+    }
+
+    fn handle_response(
+        &mut self,
+        response: &str,
+        watchdog: &mut impl Watchdog,
+    ) -> Result<(), SerialLineError> {
+        self.last_activity = Instant::now();
+        if let Some(monitor) = &mut self.monitor {
+            monitor.on_response(response);
+        }
+
+        if let Some(responses) = &mut self.responses {
+            let is_auto_report =
+                !response.starts_with("ok") && Temperatures::parse(response).is_some();
+            let answer = response.strip_prefix("ok").unwrap_or(response).trim();
+            let is_noise = answer.is_empty()
+                || is_auto_report
+                || answer.starts_with("echo:busy:")
+                || parse_resend(answer).is_some();
+            if !is_noise {
+                responses.push(answer.to_string());
+            }
+        }
+
+        if response.starts_with("ok") {
+            return self.handle_ok(response);
+        }
+
+        if let Some(number) = parse_resend(response) {
+            warn!("resend requested: {}", response.trim_end());
+            self.handle_resend_request(number);
+        } else if response.starts_with("Error:") {
+            error!("{}", response.trim_end());
+            if response.contains("checksum mismatch") {
+                self.handle_resend_request(self.line_number);
+            }
+        } else if response.starts_with("echo:busy:") {
+            self.feed_watch_dog(watchdog);
+        } else if response.starts_with("echo:Unknown command:") {
+            info!("unknown command: {response:?}");
+        }
+        Ok(())
+    }
+
+    fn handle_ok(&mut self, response: &str) -> Result<(), SerialLineError> {
+        if let Some(buffer_free) = parse_buffer_free(response) {
+            // The acknowledged command still holds its slot when Marlin
+            // reports, so the queue is one larger than the most it ever
+            // reports as free.
+            self.buffer_size = self.buffer_size.max(buffer_free + 1);
+        }
+
+        match self.pending_resend.take() {
+            // The `ok` following a resend request belongs to the rejected
+            // line. Marlin drops everything that was in flight after it, so
+            // we start over from that line. Lines before it are still in
+            // Marlin's buffer and get acknowledged as usual.
+            Some(PendingResend::From(number)) => {
+                let Some(index) = self.sent_lines.iter().position(|(n, _)| *n == number) else {
+                    error!("line {number} is no longer in the resend history");
+                    return Err(SerialLineError::Resend);
+                };
+                let rejected = self.last_transmitted().saturating_sub(number) as usize + 1;
+                self.outstanding = self.outstanding.saturating_sub(rejected);
+                self.resend_queue = self.sent_lines.range(index..).cloned().collect();
+            }
+            Some(PendingResend::Duplicate) => {}
+            None => self.outstanding = self.outstanding.saturating_sub(1),
+        }
+        Ok(())
+    }
+
+    fn handle_resend_request(&mut self, number: u32) {
+        // `Error:checksum mismatch` comes right before its `Resend:`, in which
+        // case both describe the same request.
+        let is_duplicate = !matches!(self.pending_resend, Some(PendingResend::From(_)))
+            && self.duplicate_resends > 0
+            && self.last_resend_request == number;
+
+        if is_duplicate {
+            self.duplicate_resends -= 1;
+            self.pending_resend = Some(PendingResend::Duplicate);
+        } else {
+            self.last_resend_request = number;
+            self.duplicate_resends = self.last_transmitted().saturating_sub(number) as usize;
+            self.pending_resend = Some(PendingResend::From(number));
+        }
+    }
+
+    fn check_halted(&self) -> Result<(), SerialLineError> {
+        if self.halted.load(Ordering::SeqCst) {
+            return Err(SerialLineError::Halted);
+        }
+        Ok(())
+    }
+
+    /// The newest line that actually went out, as resent lines are written
+    /// again in order.
+    fn last_transmitted(&self) -> u32 {
+        self.resend_queue
+            .front()
+            .map_or(self.line_number, |(number, _)| number.saturating_sub(1))
+    }
+
+    fn feed_watch_dog(&mut self, watchdog: &mut impl Watchdog) {
+        watchdog.feed();
+        self.delay.delay_ms(10);
+    }
+
+    fn inner_write(&mut self, line: impl AsRef<str>) -> Result<(), SerialLineError> {
+        for byte in line.as_ref().bytes() {
+            nb::block!(self.uart.write(byte)).map_err(|err| {
+                error!("{err:#?}");
+                SerialLineError::Write
+            })?;
+        }
+
+        nb::block!(self.uart.flush()).map_err(|err| {
+            error!("{err:#?}");
+            SerialLineError::Write
+        })
+    }
+
+    /// Returns the next complete line Marlin sent, newline included, or
+    /// `None` if there is none yet.
+    pub fn read(&mut self) -> Result<Option<String>, SerialLineError> {
+        loop {
+            if let Some(newline_index) = self.read_line_buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.read_line_buffer.drain(..=newline_index).collect();
+                // Line noise, e.g. while the printer boots, shouldn't abort a
+                // print, so invalid bytes are replaced instead.
+                return Ok(Some(String::from_utf8_lossy(&line).into_owned()));
+            }
+
+            match self.uart.read() {
+                Ok(byte) => self.read_line_buffer.push(byte),
+                Err(nb::Error::WouldBlock) => return Ok(None),
+                Err(nb::Error::Other(err)) => {
+                    error!("{err:#?}");
+                    return Err(SerialLineError::Read);
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self) -> Result<(), SerialLineError> {
+        self.read_line_buffer.clear();
+        // Any acknowledgements still on their way are thrown away with the
+        // receive buffer.
+        self.outstanding = 0;
+        self.resend_queue.clear();
+        self.pending_resend = None;
+        self.duplicate_resends = 0;
+        loop {
+            match self.uart.read() {
+                Ok(_) => {}
+                Err(nb::Error::WouldBlock) => return Ok(()),
+                Err(nb::Error::Other(err)) => {
+                    error!("{err:#?}");
+                    return Err(SerialLineError::Clear);
+                }
+            }
+        }
+    }
+}
+
+/// Returns what Marlin executes of a G-code line, or `None` if there is
+/// nothing, like on comment-only lines.
+///
+/// A line the tokenizer rejects is still sent without its `;` comment, as
+/// Marlin may well understand it and dropping it could ruin a print.
+fn executable(line: &str) -> Option<String> {
+    match gcode::Line::parse(line) {
+        Ok(line) => (!line.is_empty()).then(|| line.executable().to_string()),
+        Err(err) => {
+            warn!("sending unparsed line {line:?}: {err:?}");
+            let command = line.split(';').next().unwrap_or_default().trim();
+            (!command.is_empty()).then(|| command.to_string())
+        }
+    }
+}
+
+fn frame_line(number: u32, command: &str) -> String {
+    let line = format!("N{number} {command}");
+    format!("{line}*{}\n", gcode::checksum(&line))
+}
+
+/// Parses `Resend: 12` and `rs N12` style resend requests.
+fn parse_resend(response: &str) -> Option<u32> {
+    let number = response
+        .strip_prefix("Resend:")
+        .or_else(|| response.strip_prefix("rs"))?
+        .trim()
+        .trim_start_matches('N');
+    number.parse().ok()
+}
+
+/// The free command buffer slots Marlin adds to `ok` when built with
+/// `ADVANCED_OK`, e.g. `ok N12 P15 B3`.
+///
+/// The planner's free blocks (`P`) don't matter for flow control, Marlin
+/// simply holds back the `ok` while its planner is full.
+fn parse_buffer_free(response: &str) -> Option<usize> {
+    response
+        .split_whitespace()
+        .find_map(|word| word.strip_prefix('B'))
+        .and_then(|value| value.parse().ok())
+}
+
+#[derive(Debug)]
+pub enum SerialLineError {
+    Write,
+    Clear,
+    Read,
+    Resend,
+    UnexpectedResponse,
+    Halted,
+    /// Marlin stopped answering, see [`SerialWrapper::set_response_timeout`].
+    Timeout,
+}
@@ -0,0 +1,78 @@
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Heater {
+    pub current: f32,
+    pub target: f32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Temperatures {
+    /// One entry per extruder, `T0` first.
+    pub hotends: Vec<Heater>,
+    pub bed: Option<Heater>,
+}
+
+impl Temperatures {
+    /// Parses a temperature report such as `ok T:200.0 /200.0 B:60.0 /60.0 @:0 B@:0`,
+    /// as sent in reply to `M105` or by `M155` auto-reporting.
+    ///
+    /// Printers with several extruders report the active one as `T:` followed
+    /// by every extruder as `T0:`, `T1:`, ... in which case the numbered ones
+    /// are used.
+    pub fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        let line = line.strip_prefix("ok").unwrap_or(line).trim_start();
+        if !(line.starts_with("T:") || line.starts_with("B:")) {
+            return None;
+        }
+
+        let mut active_hotend = None;
+        let mut hotends = Vec::new();
+        let mut bed = None;
+
+        let mut words = line.split_whitespace().peekable();
+        while let Some(word) = words.next() {
+            let Some((name, value)) = word.split_once(':') else {
+                continue;
+            };
+
+            // The target is either glued to the value (`T:200.0/200.0`) or
+            // the next word (`T:200.0 /200.0`).
+            let (current, target) = match value.split_once('/') {
+                Some((current, target)) => (current, target),
+                None => match words.peek().and_then(|next| next.strip_prefix('/')) {
+                    Some(target) => {
+                        words.next();
+                        (value, target)
+                    }
+                    None => (value, ""),
+                },
+            };
+            let (Ok(current), Ok(target)) = (current.parse(), target.parse()) else {
+                continue;
+            };
+            let heater = Heater { current, target };
+
+            match name {
+                "T" => active_hotend = Some(heater),
+                "B" => bed = Some(heater),
+                _ => {
+                    let Some(Ok(index)) = name.strip_prefix('T').map(str::parse::<usize>) else {
+                        continue;
+                    };
+                    if hotends.len() <= index {
+                        hotends.resize(index + 1, heater);
+                    }
+                    hotends[index] = heater;
+                }
+            }
+        }
+
+        if hotends.is_empty() {
+            hotends.extend(active_hotend);
+        }
+
+        Some(Self { hotends, bed })
+    }
+}
@@ -0,0 +1,250 @@
+use std::{
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::Duration,
+};
+
+use embedded_hal::{blocking::delay::DelayMs, watchdog::Watchdog};
+use marlin::{fake_marlin::FakeMarlin, Monitor, SerialLineError, SerialWrapper};
+
+struct NoDelay;
+
+impl DelayMs<u32> for NoDelay {
+    fn delay_ms(&mut self, _: u32) {}
+}
+
+#[derive(Default)]
+struct CountingWatchdog {
+    feeds: usize,
+}
+
+impl Watchdog for CountingWatchdog {
+    fn feed(&mut self) {
+        self.feeds += 1;
+    }
+}
+
+fn moves(count: usize) -> Vec<String> {
+    (0..count).map(|i| format!("G1 X{i} Y{i}")).collect()
+}
+
+fn stream(marlin: FakeMarlin, lines: &[String]) -> FakeMarlin {
+    let mut watchdog = CountingWatchdog::default();
+    let mut serial = SerialWrapper::new(marlin, NoDelay);
+    serial.reset_line_number(&mut watchdog).unwrap();
+    for line in lines {
+        serial.write(line, &mut watchdog).unwrap();
+    }
+    serial.drain(&mut watchdog).unwrap();
+    serial.release().0
+}
+
+fn executed_after_reset(marlin: &FakeMarlin) -> &[String] {
+    let (reset, executed) = marlin.executed().split_first().unwrap();
+    assert_eq!(reset, "M110 N0");
+    executed
+}
+
+#[test]
+fn streams_every_line_in_order() {
+    let lines = moves(50);
+    let marlin = stream(FakeMarlin::new(), &lines);
+    assert_eq!(executed_after_reset(&marlin), lines);
+}
+
+#[test]
+fn keeps_the_command_buffer_full_without_overflowing_it() {
+    let marlin = stream(FakeMarlin::new(), &moves(50));
+    assert_eq!(marlin.max_in_flight(), 4);
+}
+
+#[test]
+fn learns_the_buffer_size_from_advanced_ok() {
+    let marlin = stream(
+        FakeMarlin::new().with_buffer_size(8).with_advanced_ok(),
+        &moves(50),
+    );
+    assert_eq!(marlin.max_in_flight(), 8);
+}
+
+#[test]
+fn resends_a_corrupted_line() {
+    let lines = moves(20);
+    let marlin = stream(FakeMarlin::new().corrupt_line(5), &lines);
+    assert_eq!(executed_after_reset(&marlin), lines);
+}
+
+#[test]
+fn resends_several_corrupted_lines() {
+    let lines = moves(40);
+    let marlin = stream(
+        FakeMarlin::new()
+            .corrupt_line(3)
+            .corrupt_line(4)
+            .corrupt_line(17)
+            .corrupt_line(17)
+            .corrupt_line(39),
+        &lines,
+    );
+    assert_eq!(executed_after_reset(&marlin), lines);
+}
+
+#[test]
+fn resends_with_a_large_buffer_in_flight() {
+    let lines = moves(60);
+    let marlin = stream(
+        FakeMarlin::new()
+            .with_buffer_size(16)
+            .with_advanced_ok()
+            .corrupt_line(30)
+            .corrupt_line(31)
+            .corrupt_line(45),
+        &lines,
+    );
+    assert_eq!(executed_after_reset(&marlin), lines);
+}
+
+#[test]
+fn strips_comments_and_skips_comment_only_lines() {
+    let lines = [
+        "; generated by a slicer".to_string(),
+        "G28 ; home".to_string(),
+        "".to_string(),
+        "G1 X10".to_string(),
+    ];
+    let marlin = stream(FakeMarlin::new(), &lines);
+    assert_eq!(executed_after_reset(&marlin), ["G28", "G1 X10"]);
+}
+
+#[test]
+fn sends_lines_the_tokenizer_rejects_without_their_comment() {
+    let lines = [
+        "G1 #X1 ; macro".to_string(),
+        "G1 (oops".to_string(),
+        "  ; just a comment (".to_string(),
+        "G1 X2".to_string(),
+    ];
+    let marlin = stream(FakeMarlin::new(), &lines);
+    assert_eq!(
+        executed_after_reset(&marlin),
+        ["G1 #X1", "G1 (oops", "G1 X2"]
+    );
+}
+
+#[test]
+fn feeds_the_watchdog_while_marlin_is_busy() {
+    let mut watchdog = CountingWatchdog::default();
+    let mut serial = SerialWrapper::new(FakeMarlin::new().busy("G28", 3), NoDelay);
+    serial.write("G28", &mut watchdog).unwrap();
+    serial.drain(&mut watchdog).unwrap();
+    assert_eq!(watchdog.feeds, 3);
+}
+
+#[test]
+fn unknown_commands_are_still_acknowledged() {
+    let lines = ["FOO".to_string(), "G1 X1".to_string()];
+    let marlin = stream(FakeMarlin::new(), &lines);
+    assert_eq!(executed_after_reset(&marlin), lines);
+}
+
+#[test]
+fn query_returns_the_reply_without_noise() {
+    let mut watchdog = CountingWatchdog::default();
+    let mut marlin = FakeMarlin::new().reply("M114", &["X:1.00 Y:2.00 Z:3.00 E:4.00 Count X:80"]);
+    marlin.send(" T:20.00 /0.00 B:20.00 /0.00 @:0 B@:0");
+    let mut serial = SerialWrapper::new(marlin, NoDelay);
+
+    let response = serial.query("M114", &mut watchdog).unwrap();
+
+    assert_eq!(response, ["X:1.00 Y:2.00 Z:3.00 E:4.00 Count X:80"]);
+}
+
+#[derive(Clone, Default)]
+struct RecordingMonitor {
+    responses: Arc<Mutex<Vec<String>>>,
+    commands: Arc<Mutex<Vec<String>>>,
+}
+
+impl Monitor for RecordingMonitor {
+    fn on_response(&mut self, response: &str) {
+        self.responses.lock().unwrap().push(response.to_string());
+    }
+
+    fn take_commands(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.commands.lock().unwrap())
+    }
+}
+
+#[test]
+fn monitor_sees_responses_and_injects_commands() {
+    let mut watchdog = CountingWatchdog::default();
+    let mut marlin = FakeMarlin::new();
+    marlin.send(" T:20.00 /0.00 B:20.00 /0.00 @:0 B@:0");
+    let monitor = RecordingMonitor::default();
+    monitor.commands.lock().unwrap().push("M105".to_string());
+
+    let mut serial = SerialWrapper::new(marlin, NoDelay);
+    serial.set_monitor(monitor.clone());
+    serial.poll(&mut watchdog).unwrap();
+    serial.drain(&mut watchdog).unwrap();
+
+    assert_eq!(serial.release().0.executed(), ["M105"]);
+    assert_eq!(
+        *monitor.responses.lock().unwrap(),
+        [" T:20.00 /0.00 B:20.00 /0.00 @:0 B@:0\n", "ok\n"]
+    );
+}
+
+#[test]
+fn stops_waiting_on_marlin_once_halted() {
+    let mut watchdog = CountingWatchdog::default();
+    let mut serial = SerialWrapper::new(FakeMarlin::new().with_buffer_size(1), NoDelay);
+    serial.write("G28", &mut watchdog).unwrap();
+
+    serial.halt_flag().store(true, Ordering::SeqCst);
+
+    assert!(matches!(
+        serial.write("G1 X10", &mut watchdog),
+        Err(SerialLineError::Halted)
+    ));
+    assert!(matches!(
+        serial.drain(&mut watchdog),
+        Err(SerialLineError::Halted)
+    ));
+    assert_eq!(serial.release().0.executed(), [] as [String; 0]);
+}
+
+#[test]
+fn gives_up_on_a_silent_marlin() {
+    let mut watchdog = CountingWatchdog::default();
+    let mut serial = SerialWrapper::new(FakeMarlin::new().restarts_on("G28"), NoDelay);
+    serial.set_response_timeout(Duration::from_millis(50));
+    serial.write("G28", &mut watchdog).unwrap();
+    serial.write("G1 X10", &mut watchdog).unwrap();
+
+    assert!(matches!(
+        serial.drain(&mut watchdog),
+        Err(SerialLineError::Timeout)
+    ));
+}
+
+#[test]
+fn talks_to_marlin_again_after_it_restarted() {
+    let mut watchdog = CountingWatchdog::default();
+    let marlin = FakeMarlin::new()
+        .restarts_on("G28")
+        .reply("M114", &["X:0.00 Y:0.00 Z:0.00 E:0.00"]);
+    let mut serial = SerialWrapper::new(marlin, NoDelay);
+    serial.set_response_timeout(Duration::from_millis(50));
+    serial.reset_line_number(&mut watchdog).unwrap();
+    serial.write("G28", &mut watchdog).unwrap();
+    serial.write("G1 X10", &mut watchdog).unwrap();
+    assert!(serial.drain(&mut watchdog).is_err());
+
+    serial.clear().unwrap();
+    serial.reset_line_number(&mut watchdog).unwrap();
+
+    assert_eq!(
+        serial.query("M114", &mut watchdog).unwrap(),
+        ["X:0.00 Y:0.00 Z:0.00 E:0.00"]
+    );
+}
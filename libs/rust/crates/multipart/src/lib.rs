@@ -0,0 +1,241 @@
+//! A parser for `multipart/form-data` bodies, fed the body in pieces of any
+//! size, so that a file sent in a form can be streamed on as it arrives
+//! rather than held in memory.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+
+/// How long the headers of one part can be.
+pub const MAX_HEADERS_LEN: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MultipartError {
+    /// Neither `--` nor a line break follows a boundary.
+    MalformedBoundary,
+    /// The headers of a part are no UTF-8 or don't name its field.
+    MalformedHeaders,
+    HeadersTooLong,
+    /// The body ended before its closing boundary.
+    Truncated,
+}
+
+/// A field of the form.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Part {
+    pub name: String,
+    /// Set for a file, as it was called on the client.
+    pub file_name: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Event<'a> {
+    /// A part starts, its data follows.
+    Begin(&'a Part),
+    Data(&'a [u8]),
+    /// The part started last ends.
+    End,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Preamble,
+    AfterBoundary,
+    Headers,
+    Body,
+    Done,
+}
+
+pub struct MultipartParser {
+    /// A line break, `--` and the boundary, which the data of a part can't
+    /// contain.
+    delimiter: Vec<u8>,
+    state: State,
+    /// What was pushed but can't be told about yet, as it may be the start
+    /// of a delimiter or of unfinished headers.
+    buffer: Vec<u8>,
+}
+
+impl MultipartParser {
+    /// Takes the boundary of the body's `Content-Type`, see [`boundary`].
+    pub fn new(boundary: &str) -> Self {
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+        Self {
+            delimiter,
+            state: State::Preamble,
+            // The first boundary needs no line break before it.
+            buffer: b"\r\n".to_vec(),
+        }
+    }
+
+    /// Parses the next piece of the body, calling `on_event` for each part
+    /// and its data.
+    pub fn push(
+        &mut self,
+        input: &[u8],
+        mut on_event: impl FnMut(Event<'_>),
+    ) -> Result<(), MultipartError> {
+        self.buffer.extend_from_slice(input);
+        let mut start = 0;
+        let result = loop {
+            let rest = &self.buffer[start..];
+            match self.state {
+                State::Preamble | State::Body => match find(rest, &self.delimiter) {
+                    Some(position) => {
+                        if self.state == State::Body {
+                            if position > 0 {
+                                on_event(Event::Data(&rest[..position]));
+                            }
+                            on_event(Event::End);
+                        }
+                        start += position + self.delimiter.len();
+                        self.state = State::AfterBoundary;
+                    }
+                    None => {
+                        // Keeps what could be the start of the delimiter.
+                        let len = rest.len().saturating_sub(self.delimiter.len() - 1);
+                        if self.state == State::Body && len > 0 {
+                            on_event(Event::Data(&rest[..len]));
+                        }
+                        start += len;
+                        break Ok(());
+                    }
+                },
+                State::AfterBoundary => {
+                    if rest.len() < 2 {
+                        break Ok(());
+                    }
+                    if rest.starts_with(b"--") {
+                        // Whatever follows the closing boundary is ignored.
+                        start = self.buffer.len();
+                        self.state = State::Done;
+                    } else if rest.starts_with(b"\r\n") {
+                        // The line break stays, so that headers always
+                        // start after one.
+                        self.state = State::Headers;
+                    } else {
+                        break Err(MultipartError::MalformedBoundary);
+                    }
+                }
+                State::Headers => match find(rest, b"\r\n\r\n") {
+                    Some(position) if position <= MAX_HEADERS_LEN => {
+                        let part = match parse_headers(&rest[2..position.max(2)]) {
+                            Ok(part) => part,
+                            Err(err) => break Err(err),
+                        };
+                        on_event(Event::Begin(&part));
+                        start += position + 4;
+                        self.state = State::Body;
+                    }
+                    Some(_) => break Err(MultipartError::HeadersTooLong),
+                    None if rest.len() > MAX_HEADERS_LEN => {
+                        break Err(MultipartError::HeadersTooLong)
+                    }
+                    None => break Ok(()),
+                },
+                State::Done => {
+                    start = self.buffer.len();
+                    break Ok(());
+                }
+            }
+        };
+        self.buffer.drain(..start);
+        result
+    }
+
+    /// Whether the closing boundary was seen.
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Called once the body ended, to tell whether all of it arrived.
+    pub fn finish(&self) -> Result<(), MultipartError> {
+        match self.state {
+            State::Done => Ok(()),
+            _ => Err(MultipartError::Truncated),
+        }
+    }
+}
+
+/// The boundary of a `multipart/form-data` `Content-Type`.
+pub fn boundary(content_type: &str) -> Option<&str> {
+    let (media_type, parameters) = content_type.split_once(';')?;
+    if !media_type
+        .trim()
+        .eq_ignore_ascii_case("multipart/form-data")
+    {
+        return None;
+    }
+    parameters
+        .split(';')
+        .find_map(|parameter| {
+            let (key, value) = parameter.split_once('=')?;
+            let is_boundary = key.trim().eq_ignore_ascii_case("boundary");
+            is_boundary.then(|| value.trim().trim_matches('"'))
+        })
+        .filter(|boundary| !boundary.is_empty())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Takes the field's name and file name from `Content-Disposition`, other
+/// headers don't matter to a form.
+fn parse_headers(headers: &[u8]) -> Result<Part, MultipartError> {
+    let headers = core::str::from_utf8(headers).map_err(|_| MultipartError::MalformedHeaders)?;
+    let disposition = headers
+        .split("\r\n")
+        .find_map(|header| {
+            let (name, value) = header.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case("content-disposition")
+                .then_some(value)
+        })
+        .ok_or(MultipartError::MalformedHeaders)?;
+    Ok(Part {
+        name: parameter(disposition, "name").ok_or(MultipartError::MalformedHeaders)?,
+        file_name: parameter(disposition, "filename"),
+    })
+}
+
+/// The value of the `name` parameter of a header such as
+/// `form-data; name="file"; filename="a.gcode"`.
+fn parameter(header: &str, name: &str) -> Option<String> {
+    let mut rest = header.split_once(';')?.1;
+    loop {
+        let (key, value) = rest.split_once('=')?;
+        let value = value.trim_start();
+        let (value, tail) = match value.strip_prefix('"') {
+            Some(quoted) => unquote(quoted)?,
+            None => {
+                let (value, tail) = value.split_once(';').unwrap_or((value, ""));
+                (String::from(value.trim_end()), tail)
+            }
+        };
+        if key.trim().eq_ignore_ascii_case(name) {
+            return Some(value);
+        }
+        rest = tail.trim_start();
+        rest = rest.strip_prefix(';').unwrap_or(rest);
+    }
+}
+
+/// Splits a quoted string, without its opening quote, at its closing one.
+fn unquote(quoted: &str) -> Option<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = quoted.char_indices();
+    while let Some((index, char)) = chars.next() {
+        match char {
+            '"' => return Some((value, &quoted[index + 1..])),
+            '\\' => value.push(chars.next()?.1),
+            char => value.push(char),
+        }
+    }
+    None
+}
@@ -0,0 +1,158 @@
+use multipart::{boundary, Event, MultipartError, MultipartParser, MAX_HEADERS_LEN};
+
+/// A field as the parser told about it, with its data put together.
+#[derive(Debug, PartialEq, Eq)]
+struct Field {
+    name: String,
+    file_name: Option<String>,
+    data: Vec<u8>,
+}
+
+/// Feeds `body` in pieces of `piece_len`, as it arrives over the network.
+fn parse(boundary: &str, body: &[u8], piece_len: usize) -> Result<Vec<Field>, MultipartError> {
+    let mut parser = MultipartParser::new(boundary);
+    let mut fields: Vec<Field> = Vec::new();
+    let mut open = false;
+    for piece in body.chunks(piece_len) {
+        parser.push(piece, |event| match event {
+            Event::Begin(part) => {
+                assert!(!open);
+                open = true;
+                fields.push(Field {
+                    name: part.name.clone(),
+                    file_name: part.file_name.clone(),
+                    data: Vec::new(),
+                });
+            }
+            Event::Data(data) => {
+                assert!(open);
+                fields.last_mut().unwrap().data.extend_from_slice(data);
+            }
+            Event::End => {
+                assert!(open);
+                open = false;
+            }
+        })?;
+    }
+    parser.finish()?;
+    Ok(fields)
+}
+
+/// A body as PrusaSlicer sends it to OctoPrint, with a file whose lines
+/// come close to looking like a boundary.
+fn slicer_body() -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(b"--------------------------d74496d66958873e\r\n");
+    body.extend_from_slice(b"Content-Disposition: form-data; name=\"print\"\r\n\r\n");
+    body.extend_from_slice(b"true\r\n");
+    body.extend_from_slice(b"--------------------------d74496d66958873e\r\n");
+    body.extend_from_slice(b"Content-Disposition: form-data; name=\"path\"\r\n\r\n");
+    body.extend_from_slice(b"\r\n");
+    body.extend_from_slice(b"--------------------------d74496d66958873e\r\n");
+    body.extend_from_slice(
+        b"Content-Disposition: form-data; name=\"file\"; filename=\"cube; v2.gcode\"\r\n",
+    );
+    body.extend_from_slice(b"Content-Type: application/octet-stream\r\n\r\n");
+    body.extend_from_slice(&file());
+    body.extend_from_slice(b"\r\n--------------------------d74496d66958873e--\r\n");
+    body
+}
+
+fn file() -> Vec<u8> {
+    let mut file = Vec::new();
+    for index in 0..200 {
+        file.extend_from_slice(format!("G1 X{index} Y{index}\r\n").as_bytes());
+        file.extend_from_slice(b"\r\n--------------------------d74496d66958873\r\n");
+        file.extend_from_slice(b"--\r\n");
+    }
+    file
+}
+
+#[test]
+fn parses_fields_and_files_in_pieces_of_any_size() {
+    let boundary = "------------------------d74496d66958873e";
+    let expected = vec![
+        Field {
+            name: "print".into(),
+            file_name: None,
+            data: b"true".to_vec(),
+        },
+        Field {
+            name: "path".into(),
+            file_name: None,
+            data: Vec::new(),
+        },
+        Field {
+            name: "file".into(),
+            file_name: Some("cube; v2.gcode".into()),
+            data: file(),
+        },
+    ];
+    for piece_len in [1, 2, 3, 7, 64, 1024, usize::MAX] {
+        assert_eq!(
+            parse(boundary, &slicer_body(), piece_len).unwrap(),
+            expected,
+            "{piece_len}"
+        );
+    }
+}
+
+#[test]
+fn ignores_the_preamble_and_epilogue() {
+    let body =
+        b"preamble\r\n--b\r\nContent-Disposition: form-data; name=a\r\n\r\n1\r\n--b--\r\nepilogue";
+    let fields = parse("b", body, 5).unwrap();
+    assert_eq!(
+        fields,
+        vec![Field {
+            name: "a".into(),
+            file_name: None,
+            data: b"1".to_vec(),
+        }]
+    );
+}
+
+#[test]
+fn tells_when_the_body_is_truncated() {
+    let body = slicer_body();
+    assert_eq!(
+        parse(
+            "------------------------d74496d66958873e",
+            &body[..body.len() - 10],
+            100
+        ),
+        Err(MultipartError::Truncated)
+    );
+}
+
+#[test]
+fn rejects_parts_without_a_name() {
+    let body = b"--b\r\nContent-Type: text/plain\r\n\r\n1\r\n--b--\r\n";
+    assert_eq!(parse("b", body, 3), Err(MultipartError::MalformedHeaders));
+
+    let body = b"--b\r\n\r\n1\r\n--b--\r\n";
+    assert_eq!(parse("b", body, 3), Err(MultipartError::MalformedHeaders));
+}
+
+#[test]
+fn limits_the_length_of_headers() {
+    let mut body = b"--b\r\nContent-Disposition: form-data; name=a\r\nX-Padding: ".to_vec();
+    body.resize(body.len() + MAX_HEADERS_LEN, b'x');
+    body.extend_from_slice(b"\r\n\r\n1\r\n--b--\r\n");
+    assert_eq!(parse("b", &body, 100), Err(MultipartError::HeadersTooLong));
+}
+
+#[test]
+fn takes_the_boundary_from_the_content_type() {
+    assert_eq!(
+        boundary("multipart/form-data; boundary=----WebKitFormBoundary7MA4YWxk"),
+        Some("----WebKitFormBoundary7MA4YWxk")
+    );
+    assert_eq!(
+        boundary("Multipart/Form-Data; charset=utf-8; Boundary=\"a b\""),
+        Some("a b")
+    );
+    assert_eq!(boundary("multipart/form-data; boundary="), None);
+    assert_eq!(boundary("multipart/mixed; boundary=a"), None);
+    assert_eq!(boundary("application/json"), None);
+}
@@ -2,16 +2,28 @@
 
 use std::{fs::read_to_string, time::Duration};
 
+use log::warn;
+
 fn main() {
     env_logger::init();
 
-    let file = read_to_string("CE3_sphere_bot-weight_arm.gcode").unwrap();
+    let name = "CE3_sphere_bot-weight_arm.gcode";
+    let file = read_to_string(name).unwrap();
     // dbg!(file.as_bytes().len());
 
+    // The firmware sends lines the tokenizer rejects as they are, so they are
+    // only pointed out here.
+    for (index, line) in file.lines().enumerate() {
+        if let Err(err) = gcode::Line::parse(line) {
+            warn!("line {}: {err:?}: {line}", index + 1);
+        }
+    }
+
     let client = reqwest::blocking::Client::new();
 
     let res = client
-        .post("http://192.168.1.103/file/write")
+        .post("http://192.168.1.103/files/upload")
+        .query(&[("name", name)])
         .timeout(Duration::from_secs(1000000))
         .body(file)
         .send()
@@ -20,7 +32,8 @@ fn main() {
     // dbg!(res);
 
     let res = client
-        .post("http://192.168.1.103/file/print")
+        .post("http://192.168.1.103/files/print")
+        .query(&[("name", name)])
         .timeout(Duration::from_secs(1000000))
         .send()
         .unwrap();
@@ -4,5 +4,9 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     embuild::build::CfgArgs::output_propagated("ESP_IDF")?;
     embuild::build::LinkArgs::output_propagated("ESP_IDF")?;
+
+    // What the clock falls back to until SNTP synced.
+    let build_time = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+    println!("cargo:rustc-env=BUILD_UNIX_TIME={}", build_time.as_secs());
     Ok(())
 }
@@ -0,0 +1,131 @@
+use std::{
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use embedded_sdmmc::{TimeSource, Timestamp};
+use esp_idf_svc::{
+    nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault},
+    sntp::{EspSntp, SyncStatus},
+};
+use log::{error, info};
+
+/// Set by `build.rs`.
+const BUILD_UNIX_TIME: &str = env!("BUILD_UNIX_TIME");
+
+const NVS_NAMESPACE: &str = "clock";
+const LAST_KNOWN_KEY: &str = "last_known";
+
+/// How often the synced time is saved for the next boot's fallback.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Wall-clock time, which is right once SNTP synced.
+///
+/// Until then it counts on from the newer of the build time and the last
+/// time that was synced, so new files at least sort after older ones.
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    fallback: u64,
+    booted: Instant,
+}
+
+impl Clock {
+    pub fn new(last_known: Option<u64>) -> Self {
+        let build_time = BUILD_UNIX_TIME.parse().unwrap_or_default();
+        Self {
+            fallback: last_known.unwrap_or_default().max(build_time),
+            booted: Instant::now(),
+        }
+    }
+
+    /// Seconds since the Unix epoch.
+    pub fn now(&self) -> u64 {
+        let system = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs());
+        // The system clock starts at 1970 until SNTP sets it.
+        if system >= self.fallback {
+            system
+        } else {
+            self.fallback + self.booted.elapsed().as_secs()
+        }
+    }
+
+    pub fn timestamp(&self) -> Timestamp {
+        timestamp_from_unix(self.now())
+    }
+}
+
+impl TimeSource for Clock {
+    fn get_timestamp(&self) -> Timestamp {
+        self.timestamp()
+    }
+}
+
+/// The time SNTP last synced to before this boot.
+pub fn last_known_time(partition: EspDefaultNvsPartition) -> Option<u64> {
+    let nvs = EspNvs::new(partition, NVS_NAMESPACE, true).ok()?;
+    let mut buffer = [0u8; 8];
+    let bytes = nvs.get_raw(LAST_KNOWN_KEY, &mut buffer).ok()??;
+    Some(u64::from_le_bytes(bytes.try_into().ok()?))
+}
+
+/// Starts SNTP, which needs Wi-Fi to be up, and keeps saving the synced time
+/// to NVS for [`last_known_time`].
+pub fn start_sntp(partition: EspDefaultNvsPartition) {
+    thread::Builder::new()
+        .stack_size(4096)
+        .spawn(move || {
+            let sntp = EspSntp::new_default().expect("Should start sntp");
+            while sntp.get_sync_status() != SyncStatus::Completed {
+                thread::sleep(Duration::from_secs(1));
+            }
+            info!("Time synced with SNTP");
+
+            let mut nvs: EspNvs<NvsDefault> =
+                EspNvs::new(partition, NVS_NAMESPACE, true).expect("Should open nvs namespace");
+            loop {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since_epoch| since_epoch.as_secs());
+                if let Err(err) = nvs.set_raw(LAST_KNOWN_KEY, &now.to_le_bytes()) {
+                    error!("{err:#?}");
+                }
+                thread::sleep(SAVE_INTERVAL);
+            }
+        })
+        .unwrap();
+}
+
+fn timestamp_from_unix(seconds: u64) -> Timestamp {
+    let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+    let seconds_of_day = seconds % 86_400;
+    Timestamp {
+        year_since_1970: (year - 1970).clamp(0, 255) as u8,
+        zero_indexed_month: (month - 1) as u8,
+        zero_indexed_day: (day - 1) as u8,
+        hours: (seconds_of_day / 3600) as u8,
+        minutes: (seconds_of_day / 60 % 60) as u8,
+        seconds: (seconds_of_day % 60) as u8,
+    }
+}
+
+/// Year, month and day of the days since 1970-01-01, after Howard Hinnant's
+/// `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month as u32, day as u32)
+}
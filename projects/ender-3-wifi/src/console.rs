@@ -0,0 +1,78 @@
+use embedded_svc::ws::{FrameType, Sender};
+use esp_idf_svc::http::server::ws::EspHttpWsDetachedSender;
+use log::warn;
+
+/// The clients of the `/ws/console` WebSocket.
+///
+/// Every client sees what the printer sends, but only the one that connected
+/// first may send commands. When it leaves, the next oldest client takes over.
+pub struct Console {
+    clients: Vec<(i32, EspHttpWsDetachedSender)>,
+    commands: Vec<String>,
+}
+
+impl Console {
+    pub fn new() -> Self {
+        Self {
+            clients: Vec::new(),
+            commands: Vec::new(),
+        }
+    }
+
+    pub fn connect(&mut self, session: i32, mut sender: EspHttpWsDetachedSender) {
+        let role = if self.clients.is_empty() {
+            "controller"
+        } else {
+            "viewer"
+        };
+        let greeting = format!("// connected as {role}\n");
+        if sender
+            .send(FrameType::Text(false), greeting.as_bytes())
+            .is_ok()
+        {
+            self.clients.push((session, sender));
+        }
+    }
+
+    pub fn disconnect(&mut self, session: i32) {
+        let was_controller = self.is_controller(session);
+        self.clients.retain(|(client, _)| *client != session);
+        if was_controller {
+            self.broadcast("// controller left, the oldest viewer took over\n");
+        }
+    }
+
+    pub fn is_controller(&self, session: i32) -> bool {
+        self.clients
+            .first()
+            .is_some_and(|(controller, _)| *controller == session)
+    }
+
+    pub fn queue_command(&mut self, command: &str) {
+        self.broadcast(&format!("> {command}\n"));
+        self.commands.push(command.to_string());
+    }
+
+    pub fn take_commands(&mut self) -> Vec<String> {
+        core::mem::take(&mut self.commands)
+    }
+
+    /// Sends `line` to every client, dropping the ones that went away.
+    pub fn broadcast(&mut self, line: &str) {
+        self.clients.retain_mut(|(session, sender)| {
+            match sender.send(FrameType::Text(false), line.as_bytes()) {
+                Ok(()) => true,
+                Err(err) => {
+                    warn!("dropping console client {session}: {err}");
+                    false
+                }
+            }
+        });
+    }
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Self::new()
+    }
+}
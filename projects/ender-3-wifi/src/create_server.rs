@@ -9,9 +9,8 @@ const SSID: &str = env!("WIFI_SSID");
 const PASSWORD: &str = env!("WIFI_PASS");
 const STACK_SIZE: usize = 10000;
 
-pub fn create_server(modem: &mut Modem) -> EspHttpServer {
+pub fn create_server(modem: &mut Modem, nvs: EspDefaultNvsPartition) -> EspHttpServer {
     let sys_loop = EspSystemEventLoop::take().expect("Should give system event loop");
-    let nvs = EspDefaultNvsPartition::take().expect("Should give esp nvs partition");
 
     let esp_wifi =
         EspWifi::new(modem, sys_loop.clone(), Some(nvs)).expect("Should create esp wifi");
@@ -34,6 +33,10 @@ pub fn create_server(modem: &mut Modem) -> EspHttpServer {
 
     let server_configuration = esp_idf_svc::http::server::Configuration {
         stack_size: STACK_SIZE,
+        // For `/files/<name>/thumbnail`.
+        uri_match_wildcard: true,
+        // There are more than the default 32.
+        max_uri_handlers: 48,
         ..Default::default()
     };
 
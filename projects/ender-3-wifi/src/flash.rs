@@ -0,0 +1,180 @@
+use std::ffi::{CStr, CString};
+
+use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx};
+use esp_idf_sys::{
+    esp, esp_partition_find_first, esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_FAT,
+    esp_partition_type_t_ESP_PARTITION_TYPE_DATA, esp_vfs_fat_mount_config_t,
+    esp_vfs_fat_spiflash_mount_rw_wl, esp_vfs_fat_spiflash_unmount_rw_wl, wl_erase_range,
+    wl_handle_t, wl_mount, wl_read, wl_sector_size, wl_size, wl_write, EspError, ESP_ERR_NOT_FOUND,
+    WL_INVALID_HANDLE,
+};
+use library::{open_storage, LIBRARY_DIR_NAME};
+use log::warn;
+
+use crate::{clock::Clock, storage::StorageWrapper};
+
+/// The data partition in `partitions.csv` the library is kept in.
+const PARTITION_LABEL: &str = "gcode";
+/// Where the partition is mounted while it is prepared.
+const MOUNT_POINT: &str = "/gcode";
+/// Wear levelling is set to 512 byte sectors in `sdkconfig.defaults`, so
+/// that FAT's sectors are `embedded-sdmmc`'s blocks.
+const SECTOR_SIZE: usize = Block::LEN;
+const PARTITION_ID_FAT16: u8 = 0x06;
+
+/// A FAT volume in a flash partition, behind ESP-IDF's wear levelling.
+///
+/// ESP-IDF formats it without a partition table, which `embedded-sdmmc`
+/// needs, so block 0 is a made up MBR with the volume as its only partition
+/// and the volume starts at block 1.
+pub struct FlashBlockDevice {
+    handle: wl_handle_t,
+    num_sectors: u32,
+}
+
+impl FlashBlockDevice {
+    pub fn new(label: &str) -> Result<Self, EspError> {
+        let label = CString::new(label).unwrap();
+        prepare(&label)?;
+
+        // SAFETY: `label` is a NUL terminated string that outlives the call,
+        // which only reads it.
+        let partition = unsafe {
+            esp_partition_find_first(
+                esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+                esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_FAT,
+                label.as_ptr(),
+            )
+        };
+        if partition.is_null() {
+            return Err(EspError::from_infallible::<ESP_ERR_NOT_FOUND>());
+        }
+        let mut handle = WL_INVALID_HANDLE;
+        // SAFETY: `partition` was just checked not to be null and points to
+        // ESP-IDF's partition table, which is never freed. `handle` is a local
+        // that is only written to.
+        esp!(unsafe { wl_mount(partition, &mut handle) })?;
+
+        // SAFETY: `handle` was mounted above, a failed mount returned early.
+        let sector_size = unsafe { wl_sector_size(handle) };
+        assert_eq!(
+            sector_size, SECTOR_SIZE,
+            "Wear levelling should use 512 byte sectors"
+        );
+        // SAFETY: `handle` was mounted above, a failed mount returned early.
+        let size = unsafe { wl_size(handle) };
+        Ok(Self {
+            handle,
+            num_sectors: (size / SECTOR_SIZE) as u32,
+        })
+    }
+
+    fn master_boot_record(&self) -> Block {
+        let mut block = Block::new();
+        let partition = &mut block.contents[446..462];
+        partition[4] = PARTITION_ID_FAT16;
+        partition[8..12].copy_from_slice(&1u32.to_le_bytes());
+        partition[12..16].copy_from_slice(&self.num_sectors.to_le_bytes());
+        block.contents[510..].copy_from_slice(&[0x55, 0xAA]);
+        block
+    }
+}
+
+impl BlockDevice for FlashBlockDevice {
+    type Error = EspError;
+
+    fn read(
+        &self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        _reason: &str,
+    ) -> Result<(), Self::Error> {
+        for (block, block_idx) in blocks.iter_mut().zip(start_block_idx.0..) {
+            let Some(sector) = block_idx.checked_sub(1) else {
+                *block = self.master_boot_record();
+                continue;
+            };
+            // SAFETY: `handle` was mounted in `Self::new` and is never
+            // unmounted. `block.contents` is `SECTOR_SIZE` bytes long, as
+            // `Self::new` checked wear levelling's sectors are, and is
+            // borrowed mutably for the whole call.
+            esp!(unsafe {
+                wl_read(
+                    self.handle,
+                    sector as usize * SECTOR_SIZE,
+                    block.contents.as_mut_ptr().cast(),
+                    SECTOR_SIZE,
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        for (block, block_idx) in blocks.iter().zip(start_block_idx.0..) {
+            let Some(sector) = block_idx.checked_sub(1) else {
+                warn!("Not overwriting the made up MBR");
+                continue;
+            };
+            let address = sector as usize * SECTOR_SIZE;
+            // SAFETY: `handle` was mounted in `Self::new` and is never
+            // unmounted, and wear levelling checks the range is within the
+            // partition.
+            esp!(unsafe { wl_erase_range(self.handle, address, SECTOR_SIZE) })?;
+            // SAFETY: As above, and `block.contents` is `SECTOR_SIZE` bytes
+            // long, as `Self::new` checked wear levelling's sectors are, and
+            // borrowed for the whole call.
+            esp!(unsafe {
+                wl_write(
+                    self.handle,
+                    address,
+                    block.contents.as_ptr().cast(),
+                    SECTOR_SIZE,
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        Ok(BlockCount(self.num_sectors + 1))
+    }
+}
+
+/// Mounts the partition with ESP-IDF's own FAT driver once, which formats it
+/// if it is blank and can create the library directory.
+///
+/// Small partitions would be formatted as FAT12, which `embedded-sdmmc`
+/// can't read, so the partition needs more than 4085 sectors.
+fn prepare(label: &CStr) -> Result<(), EspError> {
+    let base_path = CString::new(MOUNT_POINT).unwrap();
+    let config = esp_vfs_fat_mount_config_t {
+        format_if_mount_failed: true,
+        max_files: 1,
+        allocation_unit_size: SECTOR_SIZE,
+        ..Default::default()
+    };
+    let mut handle = WL_INVALID_HANDLE;
+    // SAFETY: `base_path` and `label` are NUL terminated strings and `config`
+    // a filled in struct, all of which outlive the call, which copies what it
+    // keeps of them. `handle` is a local that is only written to.
+    esp!(unsafe {
+        esp_vfs_fat_spiflash_mount_rw_wl(base_path.as_ptr(), label.as_ptr(), &config, &mut handle)
+    })?;
+    let created = std::fs::create_dir_all(format!("{MOUNT_POINT}/{LIBRARY_DIR_NAME}"));
+    // SAFETY: `handle` is the one the mount above, which returned early if it
+    // failed, filled in for `base_path`, and nothing uses either afterwards.
+    esp!(unsafe { esp_vfs_fat_spiflash_unmount_rw_wl(base_path.as_ptr(), handle) })?;
+
+    if let Err(err) = created {
+        warn!("Couldn't create /{LIBRARY_DIR_NAME}: {err:?}");
+    }
+    Ok(())
+}
+
+/// Keeps the library in on-chip flash, for boards without a card.
+pub fn create_flash_storage(clock: Clock) -> StorageWrapper<FlashBlockDevice> {
+    let block_device =
+        FlashBlockDevice::new(PARTITION_LABEL).expect("Should mount the gcode partition");
+    open_storage(block_device, clock).expect("Should open the library")
+}
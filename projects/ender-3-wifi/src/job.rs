@@ -0,0 +1,376 @@
+use std::collections::VecDeque;
+
+use embedded_hal::watchdog::Watchdog;
+use gcode::Line;
+use library::format_timestamp;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::Clock,
+    progress::Progress,
+    serial::{SerialLineError, SerialWrapper},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum JobState {
+    Idle,
+    Printing,
+    Paused,
+    Cancelling,
+    Finished,
+    Failed,
+    /// Marlin was sent `M112` and has to be reset before printing again.
+    Halted,
+}
+
+/// How many finished prints [`Job::history`] remembers.
+const HISTORY_LEN: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum PrintOutcome {
+    Finished,
+    Failed,
+    Cancelled,
+    Halted,
+}
+
+/// A print that ran since boot.
+#[derive(Debug, Clone, Serialize)]
+pub struct PrintRecord {
+    pub file: String,
+    pub outcome: PrintOutcome,
+    pub started: String,
+    pub finished: String,
+}
+
+#[derive(Debug)]
+pub enum JobError {
+    AlreadyRunning,
+    NotPrinting,
+    NotPaused,
+    NotRunning,
+    Halted,
+    NotHalted,
+}
+
+/// How a print is parked on pause and shut down on cancel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobSettings {
+    pub park_x: f32,
+    pub park_y: f32,
+    pub park_lift_z: f32,
+    pub retract_length: f32,
+    pub cancel_gcode: Vec<String>,
+}
+
+impl Default for JobSettings {
+    fn default() -> Self {
+        Self {
+            park_x: 0.0,
+            park_y: 220.0,
+            park_lift_z: 10.0,
+            retract_length: 5.0,
+            cancel_gcode: [
+                "M104 S0 ; turn off extruder",
+                "M140 S0 ; turn off bed",
+                "M106 S0 ; turn off cooling fan",
+                "G91 ; relative positioning",
+                "G1 Z10 F600 ; lift Z",
+                "G90 ; absolute positioning",
+                "M84 ; disable motors",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+}
+
+/// The state of the print job, shared between the print thread and the
+/// HTTP handlers controlling it.
+pub struct Job {
+    state: JobState,
+    pub settings: JobSettings,
+    pub progress: Progress,
+    clock: Clock,
+    /// The file being printed and when it started.
+    current: Option<(String, String)>,
+    history: VecDeque<PrintRecord>,
+}
+
+impl Job {
+    pub fn new(clock: Clock) -> Self {
+        Self {
+            state: JobState::Idle,
+            settings: JobSettings::default(),
+            progress: Progress::default(),
+            clock,
+            current: None,
+            history: VecDeque::new(),
+        }
+    }
+
+    pub fn state(&self) -> JobState {
+        self.state
+    }
+
+    /// The name of the file being printed, or last printed.
+    pub fn file(&self) -> Option<&str> {
+        self.current.as_ref().map(|(file, _)| file.as_str())
+    }
+
+    /// The latest prints, newest first.
+    pub fn history(&self) -> impl Iterator<Item = &PrintRecord> {
+        self.history.iter()
+    }
+
+    pub fn is_running(&self) -> bool {
+        matches!(
+            self.state,
+            JobState::Printing | JobState::Paused | JobState::Cancelling
+        )
+    }
+
+    pub fn start(&mut self, file: &str) -> Result<(), JobError> {
+        if self.state == JobState::Halted {
+            return Err(JobError::Halted);
+        }
+        if self.is_running() {
+            return Err(JobError::AlreadyRunning);
+        }
+        self.state = JobState::Printing;
+        let started = format_timestamp(&self.clock.timestamp());
+        self.current = Some((file.to_string(), started));
+        Ok(())
+    }
+
+    pub fn pause(&mut self) -> Result<(), JobError> {
+        if self.state != JobState::Printing {
+            return Err(JobError::NotPrinting);
+        }
+        self.state = JobState::Paused;
+        Ok(())
+    }
+
+    pub fn resume(&mut self) -> Result<(), JobError> {
+        if self.state != JobState::Paused {
+            return Err(JobError::NotPaused);
+        }
+        self.state = JobState::Printing;
+        Ok(())
+    }
+
+    pub fn cancel(&mut self) -> Result<(), JobError> {
+        if !matches!(self.state, JobState::Printing | JobState::Paused) {
+            return Err(JobError::NotRunning);
+        }
+        self.state = JobState::Cancelling;
+        Ok(())
+    }
+
+    /// Stops whatever is going on after an emergency stop.
+    pub fn halt(&mut self) {
+        self.state = JobState::Halted;
+    }
+
+    /// Allows printing again once the printer was reset after an emergency
+    /// stop.
+    pub fn reset(&mut self) -> Result<(), JobError> {
+        if self.state != JobState::Halted {
+            return Err(JobError::NotHalted);
+        }
+        self.state = JobState::Idle;
+        Ok(())
+    }
+
+    /// Called by the print thread once it stops streaming.
+    pub fn finish<E>(&mut self, result: &Result<(), E>) {
+        let (state, outcome) = match (self.state, result) {
+            (JobState::Halted, _) => (JobState::Halted, PrintOutcome::Halted),
+            (JobState::Cancelling, _) => (JobState::Idle, PrintOutcome::Cancelled),
+            (_, Ok(())) => (JobState::Finished, PrintOutcome::Finished),
+            (_, Err(_)) => (JobState::Failed, PrintOutcome::Failed),
+        };
+        self.state = state;
+
+        if let Some((file, started)) = &self.current {
+            self.history.push_front(PrintRecord {
+                file: file.clone(),
+                outcome,
+                started: started.clone(),
+                finished: format_timestamp(&self.clock.timestamp()),
+            });
+            self.history.truncate(HISTORY_LEN);
+        }
+    }
+}
+
+/// The modal state of the G-code stream that a pause or a recovery has to
+/// restore.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct StreamState {
+    feedrate: Option<f32>,
+    relative_extrusion: bool,
+    hotend_target: Option<f32>,
+    bed_target: Option<f32>,
+}
+
+impl StreamState {
+    pub fn observe(&mut self, line: &str) {
+        let Ok(line) = Line::parse(line) else {
+            return;
+        };
+        let Some(command) = line.command() else {
+            return;
+        };
+        let parameter = |letter| line.parameter(letter).and_then(|word| word.value::<f32>());
+
+        if command.is("G0") || command.is("G1") {
+            self.feedrate = parameter('F').or(self.feedrate);
+        } else if command.is("M82") {
+            self.relative_extrusion = false;
+        } else if command.is("M83") {
+            self.relative_extrusion = true;
+        } else if command.is("M104") || command.is("M109") {
+            self.hotend_target = parameter('S').or(self.hotend_target);
+        } else if command.is("M140") || command.is("M190") {
+            self.bed_target = parameter('S').or(self.bed_target);
+        }
+    }
+
+    pub fn hotend_target(&self) -> Option<f32> {
+        self.hotend_target
+    }
+
+    pub fn bed_target(&self) -> Option<f32> {
+        self.bed_target
+    }
+
+    /// Sends the feedrate and extrusion mode the print was using.
+    pub fn restore(
+        &self,
+        serial: &mut SerialWrapper,
+        watchdog: &mut impl Watchdog,
+    ) -> Result<(), SerialLineError> {
+        if self.relative_extrusion {
+            serial.write("M83", watchdog)?;
+        }
+        if let Some(feedrate) = self.feedrate {
+            serial.write(format!("G1 F{feedrate}"), watchdog)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Position {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub e: f32,
+}
+
+impl Position {
+    /// Parses an `M114` report such as `X:10.00 Y:20.00 Z:0.30 E:5.00 Count X:800 ...`.
+    fn parse(line: &str) -> Option<Self> {
+        let (logical, _) = line.split_once("Count").unwrap_or((line, ""));
+        let axis = |name: &str| {
+            logical
+                .split_whitespace()
+                .find_map(|word| word.strip_prefix(name)?.strip_prefix(':'))?
+                .parse()
+                .ok()
+        };
+        Some(Self {
+            x: axis("X")?,
+            y: axis("Y")?,
+            z: axis("Z")?,
+            e: axis("E")?,
+        })
+    }
+
+    /// Asks Marlin where the last queued move ends.
+    pub fn query(
+        serial: &mut SerialWrapper,
+        watchdog: &mut impl Watchdog,
+    ) -> Result<Self, SerialLineError> {
+        serial
+            .query("M114", watchdog)?
+            .iter()
+            .find_map(|line| Self::parse(line))
+            .ok_or(SerialLineError::UnexpectedResponse)
+    }
+}
+
+/// Where the print was when it got paused.
+pub struct Parked {
+    position: Position,
+    stream_state: StreamState,
+}
+
+/// Waits for the queued moves to finish, then retracts, lifts Z and moves
+/// the head out of the way.
+pub fn park(
+    serial: &mut SerialWrapper,
+    watchdog: &mut impl Watchdog,
+    settings: &JobSettings,
+    stream_state: StreamState,
+) -> Result<Parked, SerialLineError> {
+    serial.query("M400", watchdog)?;
+    let position = Position::query(serial, watchdog)?;
+
+    let retract = settings.retract_length;
+    let park_z = position.z + settings.park_lift_z;
+    for line in [
+        "G91".to_string(),
+        format!("G1 E-{retract} F2100"),
+        "G90".to_string(),
+        format!("G1 Z{park_z} F600"),
+        format!("G1 X{} Y{} F6000", settings.park_x, settings.park_y),
+    ] {
+        serial.write(line, watchdog)?;
+    }
+    serial.drain(watchdog)?;
+
+    Ok(Parked {
+        position,
+        stream_state,
+    })
+}
+
+impl Parked {
+    /// Moves the head back to where it was parked from and restores the
+    /// extruder position and feedrate the print was using.
+    pub fn restore(
+        self,
+        serial: &mut SerialWrapper,
+        watchdog: &mut impl Watchdog,
+        settings: &JobSettings,
+    ) -> Result<(), SerialLineError> {
+        let Position { x, y, z, e } = self.position;
+        for line in [
+            "G90".to_string(),
+            format!("G1 X{x} Y{y} F6000"),
+            format!("G1 Z{z} F600"),
+            "G91".to_string(),
+            format!("G1 E{} F2100", settings.retract_length),
+            "G90".to_string(),
+            format!("G92 E{e}"),
+        ] {
+            serial.write(line, watchdog)?;
+        }
+        self.stream_state.restore(serial, watchdog)?;
+        serial.drain(watchdog)
+    }
+}
+
+/// Runs the configured shutdown sequence after a cancelled print.
+pub fn run_cancel_sequence(
+    serial: &mut SerialWrapper,
+    watchdog: &mut impl Watchdog,
+    settings: &JobSettings,
+) -> Result<(), SerialLineError> {
+    for line in &settings.cancel_gcode {
+        serial.write(line, watchdog)?;
+    }
+    serial.drain(watchdog)
+}
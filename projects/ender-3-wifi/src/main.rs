@@ -1,29 +1,69 @@
+mod clock;
+mod console;
 mod create_server;
+#[cfg(feature = "flash-storage")]
+mod flash;
+mod job;
+mod moonraker;
+mod octoprint;
+mod printer_sd;
+mod progress;
+mod recovery;
 mod serial;
 mod storage;
+mod temperature;
+mod upload;
 
 use std::{
     ops::DerefMut,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, MutexGuard},
     thread,
-    time::{self, Duration},
+    time::{self, Duration, Instant},
 };
 
-use embedded_svc::http::{Headers, Method};
+use embedded_hal::watchdog::Watchdog;
+use embedded_svc::{
+    http::{
+        server::{HandlerResult, Request},
+        Headers, Method,
+    },
+    io::{Read, Write},
+    ws::{FrameType, Receiver, Sender},
+};
 use enumset::enum_set;
 use esp_idf_hal::{
     cpu::Core,
     delay::FreeRtos,
-    gpio::PinDriver,
     prelude::Peripherals,
-    task::watchdog::{TWDTConfig, TWDTDriver},
+    task::watchdog::{TWDTConfig, TWDTDriver, WatchdogSubscription},
 };
 
+use clock::Clock;
+use console::Console;
 use create_server::create_server;
-use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::{
+    http::server::{EspHttpConnection, EspHttpServer},
+    nvs::EspDefaultNvsPartition,
+};
+use esp_idf_sys::EspError;
+use job::{park, run_cancel_sequence, Job, JobSettings, JobState, StreamState};
+use library::{
+    read_ahead, write_behind, Encoding, ThumbnailExtractor, Upload, WrappedReaderWriter, Writer,
+};
 use log::{error, info, Level, LevelFilter, Metadata, Record};
-use serial::{create_serial, SerialWrapper};
-use storage::{create_storage, BlockDev, StorageWrapper};
+use moonraker::{RpcError, RpcRequest};
+use octoprint::{FormFileReader, JobCommand};
+use printer_sd::{SdPrintStatus, UploadProgress, UploadState};
+use progress::Progress;
+use recovery::{Checkpoint, CheckpointStore, CHECKPOINT_INTERVAL};
+use serial::{create_serial, EmergencyStop, SerialLineError, SerialWrapper};
+#[cfg(not(feature = "flash-storage"))]
+use storage::create_storage;
+use storage::{
+    is_short_file_name, BlockDev, StorageDeleteError, StorageLibraryError, StorageWrapper,
+};
+use temperature::{TemperatureHistory, AUTO_REPORT_INTERVAL_SECONDS};
+use upload::{ContentRange, Throughput, UploadReport, UploadSessions, UploadVerifier};
 
 fn main() {
     esp_idf_sys::link_patches();
@@ -31,14 +71,23 @@ fn main() {
     log::set_max_level(LevelFilter::Trace);
 
     let mut peripherals = Peripherals::take().expect("Should get peripherals");
+    let nvs = EspDefaultNvsPartition::take().expect("Should give esp nvs partition");
+    let clock = Clock::new(clock::last_known_time(nvs.clone()));
     let config =
         esp_idf_hal::uart::config::Config::default().baudrate(esp_idf_hal::units::Hertz(115_200));
 
-    let mut serial = create_serial(
+    let temperatures = Arc::new(Mutex::new(TemperatureHistory::new()));
+    let console = Arc::new(Mutex::new(Console::new()));
+    let sd_status = Arc::new(Mutex::new(None::<SdPrintStatus>));
+
+    let (mut serial, emergency_stop) = create_serial(
         peripherals.uart1,
         peripherals.pins.gpio6,
         peripherals.pins.gpio7,
         &config,
+        temperatures.clone(),
+        console.clone(),
+        sd_status.clone(),
     );
 
     let config = TWDTConfig {
@@ -52,6 +101,7 @@ fn main() {
     {
         let mut watchdog = driver.watch_current_task().unwrap();
 
+        serial.reset_line_number(&mut watchdog).unwrap();
         serial.write("G28 ;Home\n", &mut watchdog).unwrap();
         serial
             .write("M106 S0 ; turn off cooling fan\n", &mut watchdog)
@@ -68,31 +118,60 @@ fn main() {
         serial
             .write("M82 ;absolute extrusion mode\n", &mut watchdog)
             .unwrap();
+        serial
+            .write(
+                format!("M155 S{AUTO_REPORT_INTERVAL_SECONDS} ; auto-report temperatures\n"),
+                &mut watchdog,
+            )
+            .unwrap();
 
+        serial.drain(&mut watchdog).unwrap();
         serial.clear().unwrap();
     }
 
+    #[cfg(feature = "flash-storage")]
+    let storage = flash::create_flash_storage(clock);
+    #[cfg(not(feature = "flash-storage"))]
     let storage = create_storage(
         peripherals.spi2,
         peripherals.pins.gpio3,
         peripherals.pins.gpio0,
         peripherals.pins.gpio1,
-        PinDriver::output(peripherals.pins.gpio2).unwrap(),
+        esp_idf_hal::gpio::PinDriver::output(peripherals.pins.gpio2).unwrap(),
+        clock,
     );
 
     let ender = Arc::new(Mutex::new(Ender {
         serial,
         storage,
         driver,
+        recovery: CheckpointStore::new(nvs.clone()),
     }));
 
+    let job = Arc::new(Mutex::new(Job::new(clock)));
+
     // let ender = setup(&mut peripherals);
-    let mut server = create_server(&mut peripherals.modem);
+    let mut server = create_server(&mut peripherals.modem, nvs.clone());
+    clock::start_sntp(nvs);
 
-    print_file_handler(&ender, &mut server);
-    write_file_handler(&ender, &mut server);
+    library_handlers(&ender, &job, &sd_status, &mut server);
+    upload_session_handlers(&ender, &job, &mut server);
+    octoprint_handlers(&ender, &job, &sd_status, &mut server);
+    recover_handlers(&ender, &job, &sd_status, &mut server);
+    printer_sd_upload_handlers(&ender, &job, &sd_status, &mut server);
+    printer_sd_handlers(&ender, &job, &sd_status, &mut server);
+    temperature_handler(&temperatures, &mut server);
+    job_handlers(&job, &mut server);
+    emergency_stop_handlers(&ender, &emergency_stop, &job, &mut server);
+    gcode_handler(&ender, &job, &sd_status, &mut server);
+    console_handler(&console, &mut server);
+    moonraker_handler(&ender, &job, &sd_status, &temperatures, &mut server);
+    // Last, as its wildcard would take the other `/files/` paths.
+    thumbnail_handler(&ender, &job, &mut server);
     std::mem::forget(server);
 
+    poll_serial(&ender);
+
     loop {
         FreeRtos::delay_ms(1000);
     }
@@ -102,97 +181,1945 @@ struct Ender<B: BlockDev> {
     serial: SerialWrapper<'static>,
     storage: StorageWrapper<B>,
     driver: TWDTDriver<'static>,
+    recovery: CheckpointStore,
 }
 
-fn print_file_handler<B: BlockDev>(ender: &Arc<Mutex<Ender<B>>>, server: &mut EspHttpServer) {
+/// Keeps reading the serial port while it is idle, so reports Marlin sends on
+/// its own don't pile up in the UART buffer.
+fn poll_serial<B: BlockDev>(ender: &Arc<Mutex<Ender<B>>>) {
     let ender1 = ender.clone();
+    thread::Builder::new()
+        .stack_size(10000)
+        .spawn(move || loop {
+            if let Ok(mut ender) = ender1.try_lock() {
+                let ender2 = ender.deref_mut();
+                let mut watchdog = ender2.driver.watch_current_task().unwrap();
+                if let Err(err) = ender2.serial.poll(&mut watchdog) {
+                    error!("{err:?}");
+                }
+            }
+            FreeRtos::delay_ms(500);
+        })
+        .unwrap();
+}
+
+fn temperature_handler(temperatures: &Arc<Mutex<TemperatureHistory>>, server: &mut EspHttpServer) {
+    let temperatures = temperatures.clone();
+    server
+        .fn_handler("/printer/temperature", Method::Get, move |request| {
+            let json = temperatures.lock().unwrap().to_json();
+            respond_json(request, &json)
+        })
+        .unwrap();
+}
+
+fn job_handlers(job: &Arc<Mutex<Job>>, server: &mut EspHttpServer) {
+    let job1 = job.clone();
     server
-        .fn_handler("/file/print", Method::Post, move |_| {
-            let ender1 = ender1.clone();
+        .fn_handler("/job", Method::Get, move |request| {
+            let job = job1.lock().unwrap();
+            let json = serde_json::json!({
+                "state": job.state(),
+                "file": job.file(),
+                "progress": job.progress.report(),
+            })
+            .to_string();
+            drop(job);
+            respond_json(request, &json)
+        })
+        .unwrap();
 
-            let builder = thread::Builder::new();
-            builder
-                .stack_size(10000)
-                .spawn(move || {
-                    match print_file(&ender1) {
-                        Ok(_) => info!("File printed"),
-                        Err(err) => error!("{err}"),
-                    };
-                })
-                .unwrap();
+    let job1 = job.clone();
+    server
+        .fn_handler("/job/history", Method::Get, move |request| {
+            let json = serde_json::to_string(&job1.lock().unwrap().history().collect::<Vec<_>>())
+                .map_err(|err| format!("{err:?}"))?;
+            respond_json(request, &json)
+        })
+        .unwrap();
+
+    for (uri, transition) in [
+        (
+            "/job/pause",
+            Job::pause as fn(&mut Job) -> Result<(), job::JobError>,
+        ),
+        ("/job/resume", Job::resume),
+        ("/job/cancel", Job::cancel),
+    ] {
+        let job1 = job.clone();
+        server
+            .fn_handler(uri, Method::Post, move |request| {
+                let result = transition(&mut job1.lock().unwrap());
+                match result {
+                    Ok(()) => {
+                        request.into_ok_response()?;
+                    }
+                    Err(err) => {
+                        request.into_response(409, Some(&format!("{err:?}")), &[])?;
+                    }
+                }
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    let job1 = job.clone();
+    server
+        .fn_handler("/job/settings", Method::Get, move |request| {
+            let json = serde_json::to_string(&job1.lock().unwrap().settings)?;
+            respond_json(request, &json)
+        })
+        .unwrap();
+
+    let job1 = job.clone();
+    server
+        .fn_handler("/job/settings", Method::Post, move |mut request| {
+            let body = read_body(&mut request)?;
+            let settings: JobSettings = serde_json::from_slice(&body)?;
+            job1.lock().unwrap().settings = settings;
+            request.into_ok_response()?;
             Ok(())
         })
         .unwrap();
 }
 
-fn print_file<B: BlockDev>(ender1: &Arc<Mutex<Ender<B>>>) -> Result<(), String> {
-    let mut ender = ender1.lock().unwrap();
-    let ender2 = ender.deref_mut();
-    let mut watchdog = ender2.driver.watch_current_task().unwrap();
-    ender2.serial.clear().map_err(|err| format!("{err:?}"))?;
+/// `POST /emergency-stop` doesn't wait for the lock on [`Ender`], which a
+/// running print holds until it is done, and leaves the job halted until
+/// `POST /job/reset` is called once the printer was reset.
+fn emergency_stop_handlers<B: BlockDev>(
+    ender: &Arc<Mutex<Ender<B>>>,
+    emergency_stop: &EmergencyStop,
+    job: &Arc<Mutex<Job>>,
+    server: &mut EspHttpServer,
+) {
+    let emergency_stop1 = emergency_stop.clone();
+    let job1 = job.clone();
+    server
+        .fn_handler("/emergency-stop", Method::Post, move |request| {
+            emergency_stop1.trigger()?;
+            job1.lock().unwrap().halt();
+            error!("Emergency stop");
+            request.into_ok_response()?;
+            Ok(())
+        })
+        .unwrap();
 
-    let mut reader = ender2.storage.get_reader();
+    let ender1 = ender.clone();
+    let emergency_stop1 = emergency_stop.clone();
+    let job1 = job.clone();
+    server
+        .fn_handler("/job/reset", Method::Post, move |request| {
+            if job1.lock().unwrap().state() != JobState::Halted {
+                let err = job::JobError::NotHalted;
+                request.into_response(409, Some(&format!("{err:?}")), &[])?;
+                return Ok(());
+            }
 
-    while let Some(line) = reader.read().unwrap() {
-        // info!("Line from SD card: {}", line);
-        ender2.serial.write(line, &mut watchdog).unwrap();
+            // The restarted Marlin has forgotten the lines of the stopped
+            // print and counts lines from 1 again.
+            emergency_stop1.reset();
+            let reset = {
+                let mut ender = ender1.lock().unwrap();
+                let ender2 = ender.deref_mut();
+                let mut watchdog = ender2.driver.watch_current_task().unwrap();
+                ender2
+                    .serial
+                    .clear()
+                    .and_then(|()| ender2.serial.reset_line_number(&mut watchdog))
+            };
+            // Left halted, to be reset again once the printer answers.
+            if let Err(err) = reset {
+                error!("Resetting the serial connection failed: {err:?}");
+                request.into_response(503, Some(&format!("{err:?}")), &[])?;
+                return Ok(());
+            }
+
+            match job1.lock().unwrap().reset() {
+                Ok(()) => request.into_ok_response()?,
+                Err(err) => request.into_response(409, Some(&format!("{err:?}")), &[])?,
+            };
+            Ok(())
+        })
+        .unwrap();
+}
+
+/// Sends the lines of the request body one by one and answers with what the
+/// printer replied to each of them.
+fn gcode_handler<B: BlockDev>(
+    ender: &Arc<Mutex<Ender<B>>>,
+    job: &Arc<Mutex<Job>>,
+    sd_status: &Arc<Mutex<Option<SdPrintStatus>>>,
+    server: &mut EspHttpServer,
+) {
+    let ender1 = ender.clone();
+    let job1 = job.clone();
+    let sd_status1 = sd_status.clone();
+    server
+        .fn_handler("/gcode", Method::Post, move |mut request| {
+            let body = read_body(&mut request)?;
+            let body = core::str::from_utf8(&body)?;
+
+            let mut ender = match lock_idle_ender(&ender1, &job1, &sd_status1) {
+                Ok(ender) => ender,
+                Err(reason) => {
+                    request.into_response(409, Some(reason), &[])?;
+                    return Ok(());
+                }
+            };
+            let ender2 = ender.deref_mut();
+            let mut watchdog = ender2.driver.watch_current_task().unwrap();
 
-        let total_read_of_file =
-            (reader.file_size_in_bytes() - reader.remaining_bytes_in_file()) as f32;
-        info!(
-            "{}%",
-            100f32 * (total_read_of_file / reader.file_size_in_bytes() as f32),
-        );
+            let mut replies = Vec::new();
+            for command in body.lines().filter(|line| !line.trim().is_empty()) {
+                let response = ender2
+                    .serial
+                    .query(command, &mut watchdog)
+                    .map_err(|err| format!("{err:?}"))?;
+                replies.push(serde_json::json!({
+                    "command": command.trim(),
+                    "response": response,
+                }));
+            }
+
+            respond_json(request, &serde_json::Value::from(replies).to_string())
+        })
+        .unwrap();
+}
+
+/// How long a command sent by hand waits for something else that has the
+/// card and the serial port, like an upload, before it is refused.
+const ENDER_LOCK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Refuses what would send the printer lines while it is printing from its own
+/// card, as reported by the monitor. A report left from before the printer
+/// was reset is replaced once `GET /printer/sd/status` asks again.
+const PRINTING_FROM_SD: &str = "The printer is printing from its card";
+
+fn is_printing_from_sd(sd_status: &Mutex<Option<SdPrintStatus>>) -> bool {
+    matches!(
+        *sd_status.lock().unwrap(),
+        Some(SdPrintStatus::Printing { .. })
+    )
+}
+
+/// Locks `ender` for commands sent by hand, or says why they are refused.
+///
+/// The job is checked with its lock held while `ender` is tried, so a print
+/// can't start in between. Trying doesn't block, which keeps it from
+/// deadlocking with a print, which locks `job` while it holds `ender`.
+fn lock_idle_ender<'a, B: BlockDev>(
+    ender: &'a Mutex<Ender<B>>,
+    job: &Mutex<Job>,
+    sd_status: &Mutex<Option<SdPrintStatus>>,
+) -> Result<MutexGuard<'a, Ender<B>>, &'static str> {
+    let started = Instant::now();
+    loop {
+        {
+            let job = job.lock().unwrap();
+            if job.state() == JobState::Halted {
+                return Err("The printer is halted");
+            }
+            if job.is_running() {
+                return Err("A print is running");
+            }
+            if is_printing_from_sd(sd_status) {
+                return Err(PRINTING_FROM_SD);
+            }
+            if let Ok(ender) = ender.try_lock() {
+                return Ok(ender);
+            }
+        }
+        if started.elapsed() >= ENDER_LOCK_TIMEOUT {
+            return Err("The printer is busy");
+        }
+        FreeRtos::delay_ms(50);
     }
+}
 
-    Ok(())
+fn console_handler(console: &Arc<Mutex<Console>>, server: &mut EspHttpServer) {
+    const MAX_COMMAND_LEN: usize = 256;
+
+    let console = console.clone();
+    server
+        .ws_handler("/ws/console", move |ws| {
+            let session = ws.session();
+            if ws.is_new() {
+                let sender = ws.create_detached_sender()?;
+                console.lock().unwrap().connect(session, sender);
+                return Ok::<(), EspError>(());
+            }
+            if ws.is_closed() {
+                console.lock().unwrap().disconnect(session);
+                return Ok(());
+            }
+
+            let (_, len) = ws.recv(&mut [])?;
+            let mut buffer = [0u8; MAX_COMMAND_LEN];
+            if len > buffer.len() {
+                ws.send(FrameType::Text(false), b"// command too long\n")?;
+                return Ok(());
+            }
+            ws.recv(&mut buffer[..len])?;
+
+            let Ok(text) = core::str::from_utf8(&buffer[..len]) else {
+                ws.send(FrameType::Text(false), b"// command is not valid UTF-8\n")?;
+                return Ok(());
+            };
+
+            let mut console = console.lock().unwrap();
+            if !console.is_controller(session) {
+                drop(console);
+                ws.send(
+                    FrameType::Text(false),
+                    b"// only the controlling client can send commands\n",
+                )?;
+                return Ok(());
+            }
+            for command in text.trim_end_matches('\0').lines() {
+                if !command.trim().is_empty() {
+                    console.queue_command(command.trim());
+                }
+            }
+            Ok(())
+        })
+        .unwrap();
+}
+
+/// Moonraker's JSON-RPC API on `/websocket`, see [`moonraker`], answering
+/// each request on the connection it came on.
+fn moonraker_handler<B: BlockDev>(
+    ender: &Arc<Mutex<Ender<B>>>,
+    job: &Arc<Mutex<Job>>,
+    sd_status: &Arc<Mutex<Option<SdPrintStatus>>>,
+    temperatures: &Arc<Mutex<TemperatureHistory>>,
+    server: &mut EspHttpServer,
+) {
+    let started = Instant::now();
+    let ender1 = ender.clone();
+    let job1 = job.clone();
+    let sd_status1 = sd_status.clone();
+    let temperatures1 = temperatures.clone();
+    server
+        .ws_handler("/websocket", move |ws| {
+            if ws.is_new() || ws.is_closed() {
+                return Ok::<(), EspError>(());
+            }
+
+            let (_, len) = ws.recv(&mut [])?;
+            let mut buffer = [0u8; moonraker::MAX_REQUEST_LEN];
+            if len > buffer.len() {
+                let error = RpcError::invalid_request("Request too long");
+                let response = moonraker::response(&serde_json::Value::Null, Err(error));
+                ws.send(FrameType::Text(false), response.as_bytes())?;
+                return Ok(());
+            }
+            ws.recv(&mut buffer[..len])?;
+            let len = buffer[..len]
+                .iter()
+                .rposition(|&byte| byte != b'\0')
+                .map_or(0, |last| last + 1);
+
+            let Ok(request) = serde_json::from_slice::<RpcRequest>(&buffer[..len]) else {
+                let response =
+                    moonraker::response(&serde_json::Value::Null, Err(RpcError::parse_error()));
+                ws.send(FrameType::Text(false), response.as_bytes())?;
+                return Ok(());
+            };
+            let result = moonraker_call(
+                &ender1,
+                &job1,
+                &sd_status1,
+                &temperatures1,
+                started,
+                &request,
+            );
+            if let Err(err) = &result {
+                info!("{} failed: {}", request.method, err.message);
+            }
+            if let Some(id) = &request.id {
+                let response = moonraker::response(id, result);
+                ws.send(FrameType::Text(false), response.as_bytes())?;
+            }
+            Ok(())
+        })
+        .unwrap();
+}
+
+/// Runs a Moonraker method, refusing the ones that need the card or the
+/// printer while a print is running, as the REST API does.
+fn moonraker_call<B: BlockDev>(
+    ender: &Arc<Mutex<Ender<B>>>,
+    job: &Arc<Mutex<Job>>,
+    sd_status: &Mutex<Option<SdPrintStatus>>,
+    temperatures: &Arc<Mutex<TemperatureHistory>>,
+    started: Instant,
+    request: &RpcRequest,
+) -> Result<serde_json::Value, RpcError> {
+    let params = &request.params;
+    let transition = |apply: fn(&mut Job) -> Result<(), job::JobError>| {
+        apply(&mut job.lock().unwrap())
+            .map(|()| serde_json::json!("ok"))
+            .map_err(|err| RpcError::refused(format!("{err:?}")))
+    };
+
+    match request.method.as_str() {
+        "server.info" => Ok(moonraker::server_info()),
+        "printer.objects.query" => {
+            let job = job.lock().unwrap();
+            let temperatures = temperatures.lock().unwrap();
+            let eventtime = started.elapsed().as_secs_f64();
+            moonraker::objects_query(params, &job, temperatures.latest(), eventtime)
+        }
+        "server.files.list" => {
+            if job.lock().unwrap().is_running() {
+                return Err(RpcError::refused("A print is running"));
+            }
+            let files = ender
+                .lock()
+                .unwrap()
+                .storage
+                .list()
+                .map_err(|err| RpcError::internal(format!("{err:?}")))?;
+            Ok(moonraker::file_list(&files))
+        }
+        "printer.print.start" => {
+            let name = moonraker::string_param(params, "filename")?;
+            if job.lock().unwrap().is_running() {
+                return Err(RpcError::refused("A print is running"));
+            }
+            if is_printing_from_sd(sd_status) {
+                return Err(RpcError::refused(PRINTING_FROM_SD));
+            }
+            let exists = ender.lock().unwrap().storage.get_reader(name).is_ok();
+            if !exists {
+                return Err(RpcError::not_found("No such file"));
+            }
+            job.lock()
+                .unwrap()
+                .start(name)
+                .map_err(|err| RpcError::refused(format!("{err:?}")))?;
+            spawn_print(ender, job, name.to_string(), None);
+            Ok(serde_json::json!("ok"))
+        }
+        "printer.print.pause" => transition(Job::pause),
+        "printer.print.resume" => transition(Job::resume),
+        "printer.print.cancel" => transition(Job::cancel),
+        "printer.gcode.script" => {
+            let script = moonraker::string_param(params, "script")?;
+            let mut ender = lock_idle_ender(ender, job, sd_status).map_err(RpcError::refused)?;
+            let ender2 = ender.deref_mut();
+            let mut watchdog = ender2.driver.watch_current_task().unwrap();
+            for command in script.lines().filter(|line| !line.trim().is_empty()) {
+                ender2
+                    .serial
+                    .query(command, &mut watchdog)
+                    .map_err(|err| RpcError::internal(format!("{err:?}")))?;
+            }
+            Ok(serde_json::json!("ok"))
+        }
+        method => Err(RpcError::method_not_found(method)),
+    }
+}
+
+/// `POST /printer/sd/upload?name=MODEL.GCO` writes the body to the printer's
+/// own card with `M28`/`M29`, adding `&print=1` starts printing it from there.
+/// `GET /printer/sd/upload` reports how far the last upload got.
+fn printer_sd_upload_handlers<B: BlockDev>(
+    ender: &Arc<Mutex<Ender<B>>>,
+    job: &Arc<Mutex<Job>>,
+    sd_status: &Arc<Mutex<Option<SdPrintStatus>>>,
+    server: &mut EspHttpServer,
+) {
+    let upload = Arc::new(Mutex::new(None::<UploadProgress>));
+
+    let upload1 = upload.clone();
+    server
+        .fn_handler("/printer/sd/upload", Method::Get, move |request| {
+            let json = serde_json::to_string(&*upload1.lock().unwrap())?;
+            respond_json(request, &json)
+        })
+        .unwrap();
+
+    let ender1 = ender.clone();
+    let job1 = job.clone();
+    let sd_status1 = sd_status.clone();
+    let upload1 = upload.clone();
+    server
+        .fn_handler("/printer/sd/upload", Method::Post, move |mut request| {
+            let uri = request.uri().to_string();
+            let name = query_parameter(&uri, "name").unwrap_or_default();
+            // Marlin's card only knows 8.3 names.
+            if !is_short_file_name(&name) {
+                request.into_response(400, Some("Expected an 8.3 file name"), &[])?;
+                return Ok(());
+            }
+            let print = query_parameter(&uri, "print").as_deref() == Some("1");
+            let Some(content_length) = request.content_len() else {
+                return Err("No content length".into());
+            };
+
+            if job1.lock().unwrap().is_running() {
+                request.into_response(409, Some("A print is running"), &[])?;
+                return Ok(());
+            }
+            if is_printing_from_sd(&sd_status1) {
+                request.into_response(409, Some(PRINTING_FROM_SD), &[])?;
+                return Ok(());
+            }
+            let mut ender = ender1.lock().unwrap();
+            let ender2 = ender.deref_mut();
+            let mut watchdog = ender2.driver.watch_current_task().unwrap();
+
+            *upload1.lock().unwrap() = Some(UploadProgress::new(&name, content_length as usize));
+            let result = upload_to_printer_sd(
+                &mut request,
+                &mut ender2.serial,
+                &mut watchdog,
+                &name,
+                &upload1,
+            )
+            .and_then(|()| {
+                if print {
+                    info!("Printing {name} from the printer's card");
+                    printer_sd::start_print(&mut ender2.serial, &mut watchdog, &name)
+                        .map_err(|err| format!("{err:?}"))?;
+                }
+                Ok(())
+            });
+
+            if let Some(upload) = upload1.lock().unwrap().as_mut() {
+                upload.state = match result {
+                    Ok(()) => UploadState::Done,
+                    Err(_) => UploadState::Failed,
+                };
+            }
+            result?;
+            request.into_ok_response()?;
+            Ok(())
+        })
+        .unwrap();
 }
 
-fn write_file_handler<B: BlockDev>(ender: &Arc<Mutex<Ender<B>>>, server: &mut EspHttpServer) {
+/// Manages the files on the printer's own card and the prints it runs from
+/// there.
+fn printer_sd_handlers<B: BlockDev>(
+    ender: &Arc<Mutex<Ender<B>>>,
+    job: &Arc<Mutex<Job>>,
+    sd_status: &Arc<Mutex<Option<SdPrintStatus>>>,
+    server: &mut EspHttpServer,
+) {
     let ender1 = ender.clone();
+    let job1 = job.clone();
     server
-        .fn_handler("/file/write", Method::Post, move |mut request| {
+        .fn_handler("/printer/sd/files", Method::Get, move |request| {
+            if job1.lock().unwrap().is_running() {
+                request.into_response(409, Some("A print is running"), &[])?;
+                return Ok(());
+            }
             let mut ender = ender1.lock().unwrap();
             let ender2 = ender.deref_mut();
             let mut watchdog = ender2.driver.watch_current_task().unwrap();
 
+            let files = printer_sd::list_files(&mut ender2.serial, &mut watchdog)
+                .map_err(|err| format!("{err:?}"))?;
+            drop(ender);
+            respond_json(request, &serde_json::to_string(&files)?)
+        })
+        .unwrap();
+
+    for (uri, action) in [
+        (
+            "/printer/sd/print",
+            printer_sd::start_print
+                as fn(
+                    &mut SerialWrapper,
+                    &mut WatchdogSubscription,
+                    &str,
+                ) -> Result<(), SerialLineError>,
+        ),
+        ("/printer/sd/delete", printer_sd::delete_file),
+    ] {
+        let ender1 = ender.clone();
+        let job1 = job.clone();
+        let sd_status1 = sd_status.clone();
+        server
+            .fn_handler(uri, Method::Post, move |request| {
+                let uri = request.uri().to_string();
+                let name = query_parameter(&uri, "name").unwrap_or_default();
+                // Marlin's card only knows 8.3 names.
+                if !is_short_file_name(&name) {
+                    request.into_response(400, Some("Expected an 8.3 file name"), &[])?;
+                    return Ok(());
+                }
+                if job1.lock().unwrap().is_running() {
+                    request.into_response(409, Some("A print is running"), &[])?;
+                    return Ok(());
+                }
+                if is_printing_from_sd(&sd_status1) {
+                    request.into_response(409, Some(PRINTING_FROM_SD), &[])?;
+                    return Ok(());
+                }
+                let mut ender = ender1.lock().unwrap();
+                let ender2 = ender.deref_mut();
+                let mut watchdog = ender2.driver.watch_current_task().unwrap();
+
+                action(&mut ender2.serial, &mut watchdog, &name)
+                    .map_err(|err| format!("{err:?}"))?;
+                request.into_ok_response()?;
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    let ender1 = ender.clone();
+    let job1 = job.clone();
+    let sd_status1 = sd_status.clone();
+    server
+        .fn_handler("/printer/sd/status", Method::Get, move |request| {
+            // Asks for a fresh report when the serial port is free, the
+            // monitor records the answer.
+            if !job1.lock().unwrap().is_running() {
+                if let Ok(mut ender) = ender1.try_lock() {
+                    let ender2 = ender.deref_mut();
+                    let mut watchdog = ender2.driver.watch_current_task().unwrap();
+                    ender2
+                        .serial
+                        .query("M27", &mut watchdog)
+                        .map_err(|err| format!("{err:?}"))?;
+                }
+            }
+            let json = serde_json::to_string(&*sd_status1.lock().unwrap())?;
+            respond_json(request, &json)
+        })
+        .unwrap();
+}
+
+fn upload_to_printer_sd(
+    request: &mut Request<&mut EspHttpConnection>,
+    serial: &mut SerialWrapper,
+    watchdog: &mut impl Watchdog,
+    name: &str,
+    upload: &Mutex<Option<UploadProgress>>,
+) -> Result<(), String> {
+    printer_sd::begin_upload(serial, watchdog, name).map_err(|err| format!("{err:?}"))?;
+
+    let mut result = Ok(());
+    let buffer = &mut [0u8; 1000];
+    let mut pending = Vec::new();
+    let mut last_instant = time::Instant::now();
+    loop {
+        let num_read = match request.read(buffer) {
+            Ok(num_read) => num_read,
+            Err(err) => {
+                result = Err(format!("{err:?}"));
+                break;
+            }
+        };
+        if num_read == 0 {
+            break;
+        }
+        pending.extend_from_slice(&buffer[..num_read]);
+
+        let complete = pending
+            .iter()
+            .rposition(|b| *b == b'\n')
+            .map_or(0, |i| i + 1);
+        for line in pending[..complete].split(|b| *b == b'\n') {
+            let line = String::from_utf8_lossy(line);
+            if let Err(err) = printer_sd::upload_line(serial, watchdog, &line) {
+                result = Err(format!("{err:?}"));
+                break;
+            }
+        }
+        pending.drain(..complete);
+        if result.is_err() {
+            break;
+        }
+
+        if let Some(upload) = upload.lock().unwrap().as_mut() {
+            upload.bytes_done += num_read;
+            info!(
+                "{}%, {} bytes/second",
+                100f32 * (upload.bytes_done as f32 / upload.total_bytes as f32),
+                (num_read as f32) / last_instant.elapsed().as_secs_f32()
+            );
+        }
+        last_instant = time::Instant::now();
+        watchdog.feed();
+    }
+
+    if result.is_ok() && !pending.is_empty() {
+        let line = String::from_utf8_lossy(&pending);
+        result = printer_sd::upload_line(serial, watchdog, &line).map_err(|err| format!("{err:?}"));
+    }
+
+    // The printer keeps saving whatever it gets until told otherwise, even
+    // if the upload failed.
+    let finished = printer_sd::finish_upload(serial, watchdog).map_err(|err| format!("{err:?}"));
+    result.and(finished)
+}
+
+/// Looks up `name` in the query string of `uri` and decodes it.
+fn query_parameter(uri: &str, name: &str) -> Option<String> {
+    let (_, query) = uri.split_once('?')?;
+    query.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        (key == name).then(|| percent_decode(value))
+    })
+}
+
+fn percent_decode(value: &str) -> String {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = rest.get(..2).and_then(|hex| core::str::from_utf8(hex).ok());
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(decoded) => {
+                        bytes.push(decoded);
+                        rest = &rest[2..];
+                    }
+                    None => bytes.push(b'%'),
+                }
+            }
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn respond_json(request: Request<&mut EspHttpConnection>, json: &str) -> HandlerResult {
+    request
+        .into_response(200, None, &[("Content-Type", "application/json")])?
+        .write_all(json.as_bytes())?;
+    Ok(())
+}
+
+fn read_body(request: &mut Request<&mut EspHttpConnection>) -> Result<Vec<u8>, String> {
+    let Some(content_length) = request.content_len() else {
+        return Err("No content length".into());
+    };
+
+    let mut body = vec![0u8; content_length as usize];
+    let mut total_read = 0;
+    while total_read < body.len() {
+        let num_read = request
+            .read(&mut body[total_read..])
+            .map_err(|err| format!("{err:?}"))?;
+        if num_read == 0 {
+            return Err("Body ended early".into());
+        }
+        total_read += num_read;
+    }
+    Ok(body)
+}
+
+/// Streams `body` onto the card through `writer`, checking it with
+/// `verifier` and looking for `thumbnails` on the way. Receiving the next
+/// bytes goes on while the card is written on a thread of its own.
+///
+/// A connection that drops ends the body early rather than failing, so that
+/// what did arrive is on the card and was seen by `verifier`.
+fn receive_body<B: BlockDev>(
+    body: &mut impl Read,
+    writer: WrappedReaderWriter<Writer, B, Clock>,
+    verifier: &mut UploadVerifier,
+    thumbnails: &mut ThumbnailExtractor,
+    watchdog: &mut WatchdogSubscription,
+) -> Result<usize, String> {
+    let (mut block_writer, flusher) = write_behind();
+    thread::scope(|scope| -> Result<usize, String> {
+        let flushing = thread::Builder::new()
+            .stack_size(8192)
+            .spawn_scoped(scope, move || flusher.run(writer))
+            .map_err(|err| format!("{err:?}"))?;
+
+        let buffer = &mut [0u8; 1024];
+        let mut total_read = 0;
+        loop {
+            let num_read = match body.read(buffer) {
+                Ok(0) => break,
+                Ok(num_read) => num_read,
+                Err(err) => {
+                    error!("Receiving failed: {err:?}");
+                    break;
+                }
+            };
+            verifier.update(&buffer[..num_read]);
+            thumbnails.update(&buffer[..num_read]);
+            // Only fails once writing failed, which the flusher tells about.
+            if block_writer.write(&buffer[..num_read]).is_err() {
+                break;
+            }
+            total_read += num_read;
+            watchdog.feed().unwrap();
+        }
+
+        let _ = block_writer.finish();
+        flushing
+            .join()
+            .map_err(|_| "Writing to the card panicked".to_string())?
+            .map_err(|err| format!("{err:?}"))?;
+        Ok(total_read)
+    })
+}
+
+/// Keeps the thumbnail the slicer embedded in an upload next to it, which
+/// the upload is no worse off without.
+fn attach_thumbnail<B: BlockDev>(
+    storage: &mut StorageWrapper<B>,
+    upload: &mut Upload,
+    thumbnails: ThumbnailExtractor,
+) {
+    let Some(thumbnail) = thumbnails.finish() else {
+        return;
+    };
+    info!(
+        "Thumbnail of {}: {}x{} {:?}",
+        upload.name(),
+        thumbnail.width,
+        thumbnail.height,
+        thumbnail.format
+    );
+    if let Err(err) = storage.attach_thumbnail(upload, &thumbnail.image) {
+        error!("{err:?}");
+    }
+}
+
+/// A checkpoint for a file that was replaced is no good anymore.
+fn forget_replaced_checkpoint(recovery: &mut CheckpointStore, name: &str) -> Result<(), String> {
+    let checkpoint = recovery.load().map_err(|err| format!("{err:?}"))?;
+    if checkpoint.is_some_and(|checkpoint| checkpoint.file == name) {
+        recovery.clear().map_err(|err| format!("{err:?}"))?;
+    }
+    Ok(())
+}
+
+/// The G-code library on the ESP's card, with files addressed by the
+/// `name` query parameter.
+fn library_handlers<B: BlockDev>(
+    ender: &Arc<Mutex<Ender<B>>>,
+    job: &Arc<Mutex<Job>>,
+    sd_status: &Arc<Mutex<Option<SdPrintStatus>>>,
+    server: &mut EspHttpServer,
+) {
+    let ender1 = ender.clone();
+    let job1 = job.clone();
+    server
+        .fn_handler("/files", Method::Get, move |request| {
+            if job1.lock().unwrap().is_running() {
+                request.into_response(409, Some("A print is running"), &[])?;
+                return Ok(());
+            }
+            let files = ender1
+                .lock()
+                .unwrap()
+                .storage
+                .list()
+                .map_err(|err| format!("{err:?}"))?;
+            respond_json(request, &serde_json::to_string(&files)?)
+        })
+        .unwrap();
+
+    let ender1 = ender.clone();
+    let job1 = job.clone();
+    server
+        .fn_handler("/files/upload", Method::Post, move |mut request| {
+            let Some(name) = query_parameter(request.uri(), "name") else {
+                request.into_response(400, Some("Missing name"), &[])?;
+                return Ok(());
+            };
+            if job1.lock().unwrap().is_running() {
+                request.into_response(409, Some("A print is running"), &[])?;
+                return Ok(());
+            }
+            // Compressed uploads are stored as they are and decompressed
+            // while printing.
+            let content_encoding = request.header(upload::CONTENT_ENCODING_HEADER);
+            let Some(name) = upload::stored_name(&name, content_encoding) else {
+                request.into_response(415, Some("Unsupported content encoding"), &[])?;
+                return Ok(());
+            };
+            let verifier = UploadVerifier::from_headers(
+                request.header(upload::SHA256_HEADER),
+                request.header(upload::CRC32_HEADER),
+            );
+            let Ok(mut verifier) = verifier else {
+                request.into_response(400, Some("Invalid checksum header"), &[])?;
+                return Ok(());
+            };
             let Some(content_length) = request.content_len() else {
                 return Err("No content length".into());
             };
 
-            info!("Content length: {}", content_length);
+            let mut ender = ender1.lock().unwrap();
+            let ender2 = ender.deref_mut();
+            let mut watchdog = ender2.driver.watch_current_task().unwrap();
+
+            info!("Uploading {name}, content length: {}", content_length);
+
+            let (mut upload, writer) = match ender2.storage.begin_upload(&name) {
+                Ok(upload) => upload,
+                Err(StorageLibraryError::InvalidName) => {
+                    request.into_response(400, Some("Invalid name"), &[])?;
+                    return Ok(());
+                }
+                Err(err) => return Err(format!("{err:?}").into()),
+            };
 
-            ender2.storage.delete().unwrap();
+            let started = Instant::now();
+            let mut thumbnails = ThumbnailExtractor::new(Encoding::from_file_name(&name));
+            let received = receive_body(
+                &mut request,
+                writer,
+                &mut verifier,
+                &mut thumbnails,
+                &mut watchdog,
+            );
 
-            let mut writer = ender2.storage.get_writer();
+            let failure = match received {
+                Err(err) => Some((500, err)),
+                Ok(total_read) if total_read as u64 != content_length => {
+                    Some((400, "Upload ended early".to_string()))
+                }
+                Ok(_) => verifier.verify().err().map(|err| (422, format!("{err:?}"))),
+            };
+            if let Some((status, message)) = failure {
+                error!("Upload of {name} failed: {message}");
+                if let Err(err) = ender2.storage.abort_upload(upload) {
+                    error!("{err:?}");
+                }
+                request.into_response(status, Some(&message), &[])?;
+                return Ok(());
+            }
 
-            let buffer = &mut [0u8; 1000];
+            attach_thumbnail(&mut ender2.storage, &mut upload, thumbnails);
+            ender2
+                .storage
+                .commit_upload(upload)
+                .map_err(|err| format!("{err:?}"))?;
 
-            let mut total_read = 0f32;
-            let mut last_instant = time::Instant::now();
-            loop {
-                let num_read = request.read(buffer).unwrap();
+            forget_replaced_checkpoint(&mut ender2.recovery, &name)?;
 
+            let throughput = Throughput::new(content_length as usize, started.elapsed());
+            info!("Uploaded {name}: {throughput:?}");
+            let report = UploadReport { name, throughput };
+            respond_json(request, &serde_json::to_string(&report)?)
+        })
+        .unwrap();
+
+    let ender1 = ender.clone();
+    let job1 = job.clone();
+    server
+        .fn_handler("/files/download", Method::Get, move |request| {
+            let Some(name) = query_parameter(request.uri(), "name") else {
+                request.into_response(400, Some("Missing name"), &[])?;
+                return Ok(());
+            };
+            if job1.lock().unwrap().is_running() {
+                request.into_response(409, Some("A print is running"), &[])?;
+                return Ok(());
+            }
+            let mut ender = ender1.lock().unwrap();
+            let ender2 = ender.deref_mut();
+            let mut watchdog = ender2.driver.watch_current_task().unwrap();
+
+            let mut reader = match ender2.storage.get_reader(&name) {
+                Ok(reader) => reader,
+                Err(StorageLibraryError::NotFound) => {
+                    request.into_response(404, Some("No such file"), &[])?;
+                    return Ok(());
+                }
+                Err(err) => return Err(format!("{err:?}").into()),
+            };
+
+            let mut response =
+                request.into_response(200, None, &[("Content-Type", "text/plain")])?;
+            let buffer = &mut [0u8; 1000];
+            loop {
+                let num_read = reader
+                    .read_chunk(buffer)
+                    .map_err(|err| format!("{err:?}"))?;
                 if num_read == 0 {
                     break;
                 }
+                response.write_all(&buffer[..num_read])?;
+                watchdog.feed().unwrap();
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    let ender1 = ender.clone();
+    let job1 = job.clone();
+    server
+        .fn_handler("/files/delete", Method::Post, move |request| {
+            let Some(name) = query_parameter(request.uri(), "name") else {
+                request.into_response(400, Some("Missing name"), &[])?;
+                return Ok(());
+            };
+            if job1.lock().unwrap().is_running() {
+                request.into_response(409, Some("A print is running"), &[])?;
+                return Ok(());
+            }
+            let result = ender1.lock().unwrap().storage.delete(&name);
+            match result {
+                Ok(()) => request.into_ok_response()?,
+                Err(StorageDeleteError::NotFound) => {
+                    request.into_response(404, Some("No such file"), &[])?
+                }
+                Err(err) => return Err(format!("{err:?}").into()),
+            };
+            Ok(())
+        })
+        .unwrap();
+
+    let ender1 = ender.clone();
+    let job1 = job.clone();
+    server
+        .fn_handler("/files/rename", Method::Post, move |request| {
+            let (Some(name), Some(to)) = (
+                query_parameter(request.uri(), "name"),
+                query_parameter(request.uri(), "to"),
+            ) else {
+                request.into_response(400, Some("Missing name or to"), &[])?;
+                return Ok(());
+            };
+            if job1.lock().unwrap().is_running() {
+                request.into_response(409, Some("A print is running"), &[])?;
+                return Ok(());
+            }
+            let result = ender1.lock().unwrap().storage.rename(&name, &to);
+            match result {
+                Ok(()) => request.into_ok_response()?,
+                Err(StorageLibraryError::NotFound) => {
+                    request.into_response(404, Some("No such file"), &[])?
+                }
+                Err(
+                    err @ (StorageLibraryError::AlreadyExists | StorageLibraryError::InvalidName),
+                ) => request.into_response(409, Some(&format!("{err:?}")), &[])?,
+                Err(err) => return Err(format!("{err:?}").into()),
+            };
+            Ok(())
+        })
+        .unwrap();
 
-                let s = core::str::from_utf8(&buffer[..num_read]).unwrap();
-                writer.write(s).unwrap();
+    let ender1 = ender.clone();
+    let job1 = job.clone();
+    let sd_status1 = sd_status.clone();
+    server
+        .fn_handler("/files/print", Method::Post, move |request| {
+            let Some(name) = query_parameter(request.uri(), "name") else {
+                request.into_response(400, Some("Missing name"), &[])?;
+                return Ok(());
+            };
+            if job1.lock().unwrap().is_running() {
+                request.into_response(409, Some("A print is running"), &[])?;
+                return Ok(());
+            }
+            if is_printing_from_sd(&sd_status1) {
+                request.into_response(409, Some(PRINTING_FROM_SD), &[])?;
+                return Ok(());
+            }
+            let exists = ender1.lock().unwrap().storage.get_reader(&name).is_ok();
+            if !exists {
+                request.into_response(404, Some("No such file"), &[])?;
+                return Ok(());
+            }
 
-                total_read += num_read as f32;
-                let time_diff = time::Instant::now().duration_since(last_instant);
-                info!(
-                    "{}%, {} bytes/second",
-                    100f32 * (total_read / (content_length as f32)),
-                    (num_read as f32) / (time_diff.as_secs_f32())
-                );
-                last_instant = time::Instant::now();
+            if let Err(err) = job1.lock().unwrap().start(&name) {
+                request.into_response(409, Some(&format!("{err:?}")), &[])?;
+                return Ok(());
+            }
+
+            spawn_print(&ender1, &job1, name, None);
+            Ok(())
+        })
+        .unwrap();
+}
+
+/// The image type of a thumbnail, told by the bytes it starts with.
+fn thumbnail_content_type(image: &[u8]) -> &'static str {
+    if image.starts_with(b"\x89PNG") {
+        "image/png"
+    } else if image.starts_with(&[0xFF, 0xD8]) {
+        "image/jpeg"
+    } else {
+        // QOI, which browsers don't know.
+        "application/octet-stream"
+    }
+}
+
+/// Serves the thumbnail kept for a file at `/files/<name>/thumbnail`.
+fn thumbnail_handler<B: BlockDev>(
+    ender: &Arc<Mutex<Ender<B>>>,
+    job: &Arc<Mutex<Job>>,
+    server: &mut EspHttpServer,
+) {
+    let ender1 = ender.clone();
+    let job1 = job.clone();
+    server
+        .fn_handler("/files/*", Method::Get, move |request| {
+            let path = request.uri().split('?').next().unwrap_or_default();
+            let name = path
+                .strip_prefix("/files/")
+                .and_then(|path| path.strip_suffix("/thumbnail"))
+                .map(percent_decode);
+            let Some(name) = name else {
+                request.into_response(404, Some("No such path"), &[])?;
+                return Ok(());
+            };
+            if job1.lock().unwrap().is_running() {
+                request.into_response(409, Some("A print is running"), &[])?;
+                return Ok(());
+            }
+            let mut ender = ender1.lock().unwrap();
+            let ender2 = ender.deref_mut();
+            let mut watchdog = ender2.driver.watch_current_task().unwrap();
+
+            let mut reader = match ender2.storage.get_thumbnail(&name) {
+                Ok(reader) => reader,
+                Err(StorageLibraryError::NotFound) => {
+                    request.into_response(404, Some("No thumbnail"), &[])?;
+                    return Ok(());
+                }
+                Err(err) => return Err(format!("{err:?}").into()),
+            };
+
+            let buffer = &mut [0u8; 1000];
+            let mut num_read = reader
+                .read_chunk(buffer)
+                .map_err(|err| format!("{err:?}"))?;
+            let content_type = thumbnail_content_type(&buffer[..num_read]);
+            let mut response = request.into_response(
+                200,
+                None,
+                &[
+                    ("Content-Type", content_type),
+                    ("Cache-Control", "no-cache"),
+                ],
+            )?;
+            while num_read > 0 {
+                response.write_all(&buffer[..num_read])?;
                 watchdog.feed().unwrap();
-                FreeRtos::delay_ms(10);
+                num_read = reader
+                    .read_chunk(buffer)
+                    .map_err(|err| format!("{err:?}"))?;
             }
+            Ok(())
+        })
+        .unwrap();
+}
+
+/// Whether a request to the OctoPrint API came with its key.
+fn has_api_key(request: &Request<&mut EspHttpConnection>) -> bool {
+    octoprint::is_authorized(
+        request.header(octoprint::API_KEY_HEADER),
+        query_parameter(request.uri(), octoprint::API_KEY_PARAMETER).as_deref(),
+    )
+}
 
+/// What slicers use of OctoPrint's API, see [`octoprint`], which is left off
+/// unless built with `OCTOPRINT_API_KEY` set:
+///
+/// - `GET /api/version` tells slicers they found an OctoPrint
+/// - `POST /api/files/local` uploads the file in a `multipart/form-data`
+///   body, printing it right away if its `print` field is `true`
+/// - `GET /api/job` tells how the print is going
+/// - `POST /api/job` starts, pauses or cancels the print
+fn octoprint_handlers<B: BlockDev>(
+    ender: &Arc<Mutex<Ender<B>>>,
+    job: &Arc<Mutex<Job>>,
+    sd_status: &Arc<Mutex<Option<SdPrintStatus>>>,
+    server: &mut EspHttpServer,
+) {
+    if octoprint::API_KEY.is_none() {
+        info!("The OctoPrint API is off, as no OCTOPRINT_API_KEY was set");
+        return;
+    }
+    // The file the `start` command prints, which uploads can select.
+    let selected = Arc::new(Mutex::new(None::<String>));
+
+    server
+        .fn_handler("/api/version", Method::Get, move |request| {
+            if !has_api_key(&request) {
+                request.into_response(403, Some("Invalid API key"), &[])?;
+                return Ok(());
+            }
+            respond_json(request, &octoprint::version().to_string())
+        })
+        .unwrap();
+
+    let ender1 = ender.clone();
+    let job1 = job.clone();
+    let selected1 = selected.clone();
+    let sd_status1 = sd_status.clone();
+    server
+        .fn_handler("/api/files/local", Method::Post, move |mut request| {
+            if !has_api_key(&request) {
+                request.into_response(403, Some("Invalid API key"), &[])?;
+                return Ok(());
+            }
+            let boundary = request
+                .header("Content-Type")
+                .and_then(multipart::boundary)
+                .map(str::to_string);
+            let Some(boundary) = boundary else {
+                request.into_response(400, Some("Not a multipart form"), &[])?;
+                return Ok(());
+            };
+            if job1.lock().unwrap().is_running() {
+                request.into_response(409, Some("A print is running"), &[])?;
+                return Ok(());
+            }
+
+            let mut ender = ender1.lock().unwrap();
+            let ender2 = ender.deref_mut();
+            let mut watchdog = ender2.driver.watch_current_task().unwrap();
+
+            let mut form = FormFileReader::new(&mut request, &boundary);
+            let name = match form.next_file() {
+                Ok(Some(name)) => name,
+                Ok(None) => {
+                    drop(form);
+                    request.into_response(400, Some("No file in the form"), &[])?;
+                    return Ok(());
+                }
+                Err(err) => return Err(format!("{err:?}").into()),
+            };
+            info!("Uploading {name} through the OctoPrint API");
+
+            let (mut upload, writer) = match ender2.storage.begin_upload(&name) {
+                Ok(upload) => upload,
+                Err(StorageLibraryError::InvalidName) => {
+                    drop(form);
+                    request.into_response(400, Some("Invalid name"), &[])?;
+                    return Ok(());
+                }
+                Err(err) => return Err(format!("{err:?}").into()),
+            };
+
+            let started = Instant::now();
+            let mut thumbnails = ThumbnailExtractor::new(Encoding::from_file_name(&name));
+            let received = receive_body(
+                &mut form,
+                writer,
+                &mut UploadVerifier::Unchecked,
+                &mut thumbnails,
+                &mut watchdog,
+            );
+            // The form ends with the closing boundary, short of which the
+            // file may be cut short.
+            let received = match received {
+                Ok(num_received) => form
+                    .finish()
+                    .map(|fields| (num_received, fields))
+                    .map_err(|err| (400, format!("{err:?}"))),
+                Err(err) => Err((500, err)),
+            };
+            let (num_received, fields) = match received {
+                Ok(received) => received,
+                Err((status, message)) => {
+                    error!("Upload of {name} failed: {message}");
+                    if let Err(err) = ender2.storage.abort_upload(upload) {
+                        error!("{err:?}");
+                    }
+                    request.into_response(status, Some(&message), &[])?;
+                    return Ok(());
+                }
+            };
+
+            attach_thumbnail(&mut ender2.storage, &mut upload, thumbnails);
+            ender2
+                .storage
+                .commit_upload(upload)
+                .map_err(|err| format!("{err:?}"))?;
+            forget_replaced_checkpoint(&mut ender2.recovery, &name)?;
+            drop(ender);
+
+            let throughput = Throughput::new(num_received, started.elapsed());
+            info!("Uploaded {name}: {throughput:?}");
+
+            if fields.select || fields.print {
+                *selected1.lock().unwrap() = Some(name.clone());
+            }
+            if fields.print {
+                if is_printing_from_sd(&sd_status1) {
+                    request.into_response(409, Some(PRINTING_FROM_SD), &[])?;
+                    return Ok(());
+                }
+                if let Err(err) = job1.lock().unwrap().start(&name) {
+                    request.into_response(409, Some(&format!("{err:?}")), &[])?;
+                    return Ok(());
+                }
+                spawn_print(&ender1, &job1, name.clone(), None);
+            }
+
+            let json = octoprint::upload_result(&name).to_string();
+            request
+                .into_response(201, None, &[("Content-Type", "application/json")])?
+                .write_all(json.as_bytes())?;
             Ok(())
         })
         .unwrap();
+
+    let job1 = job.clone();
+    server
+        .fn_handler("/api/job", Method::Get, move |request| {
+            if !has_api_key(&request) {
+                request.into_response(403, Some("Invalid API key"), &[])?;
+                return Ok(());
+            }
+            let json = octoprint::job_status(&job1.lock().unwrap()).to_string();
+            respond_json(request, &json)
+        })
+        .unwrap();
+
+    let ender1 = ender.clone();
+    let job1 = job.clone();
+    let sd_status1 = sd_status.clone();
+    server
+        .fn_handler("/api/job", Method::Post, move |mut request| {
+            if !has_api_key(&request) {
+                request.into_response(403, Some("Invalid API key"), &[])?;
+                return Ok(());
+            }
+            let body = read_body(&mut request)?;
+            let Ok(command) = serde_json::from_slice::<JobCommand>(&body) else {
+                request.into_response(400, Some("Unknown command"), &[])?;
+                return Ok(());
+            };
+
+            let result = match command {
+                JobCommand::Start => {
+                    let (file, is_running) = {
+                        let job = job1.lock().unwrap();
+                        let file = selected.lock().unwrap().clone();
+                        (
+                            file.or_else(|| job.file().map(str::to_string)),
+                            job.is_running(),
+                        )
+                    };
+                    let Some(file) = file else {
+                        request.into_response(409, Some("No file selected"), &[])?;
+                        return Ok(());
+                    };
+                    if is_running {
+                        request.into_response(409, Some("A print is running"), &[])?;
+                        return Ok(());
+                    }
+                    if is_printing_from_sd(&sd_status1) {
+                        request.into_response(409, Some(PRINTING_FROM_SD), &[])?;
+                        return Ok(());
+                    }
+                    let exists = ender1.lock().unwrap().storage.get_reader(&file).is_ok();
+                    if !exists {
+                        request.into_response(409, Some("The selected file is gone"), &[])?;
+                        return Ok(());
+                    }
+                    let result = job1.lock().unwrap().start(&file);
+                    if result.is_ok() {
+                        spawn_print(&ender1, &job1, file, None);
+                    }
+                    result
+                }
+                JobCommand::Cancel => job1.lock().unwrap().cancel(),
+                JobCommand::Pause { action } => action.apply(&mut job1.lock().unwrap()),
+                // A paused print could only start over once it was cancelled.
+                JobCommand::Restart => {
+                    request.into_response(409, Some("Restarting isn't supported"), &[])?;
+                    return Ok(());
+                }
+            };
+            match result {
+                Ok(()) => request.into_response(204, None, &[])?,
+                Err(err) => request.into_response(409, Some(&format!("{err:?}")), &[])?,
+            };
+            Ok(())
+        })
+        .unwrap();
+}
+
+/// Uploads sent in chunks, addressed by the `id` query parameter:
+///
+/// - `POST /files/upload/begin?name=..&size=..` opens a session, taking the
+///   same checksum and `Content-Encoding` headers as `/files/upload`
+/// - `PUT /files/upload/chunk?id=..` appends the bytes its `Content-Range`
+///   says, which have to start where the file on the card ends, one chunk
+///   of a session at a time
+/// - `GET /files/upload/status?id=..` tells how many bytes are on the card,
+///   for a client whose connection dropped to continue from
+/// - `POST /files/upload/finish?id=..` checks the file and puts it in place
+/// - `POST /files/upload/cancel?id=..` deletes what was received
+fn upload_session_handlers<B: BlockDev>(
+    ender: &Arc<Mutex<Ender<B>>>,
+    job: &Arc<Mutex<Job>>,
+    server: &mut EspHttpServer,
+) {
+    let sessions = Arc::new(Mutex::new(UploadSessions::default()));
+
+    let ender1 = ender.clone();
+    let job1 = job.clone();
+    let sessions1 = sessions.clone();
+    server
+        .fn_handler("/files/upload/begin", Method::Post, move |request| {
+            let Some(name) = query_parameter(request.uri(), "name") else {
+                request.into_response(400, Some("Missing name"), &[])?;
+                return Ok(());
+            };
+            if job1.lock().unwrap().is_running() {
+                request.into_response(409, Some("A print is running"), &[])?;
+                return Ok(());
+            }
+            let size = match query_parameter(request.uri(), "size").map(|size| size.parse()) {
+                None => None,
+                Some(Ok(size)) => Some(size),
+                Some(Err(_)) => {
+                    request.into_response(400, Some("Invalid size"), &[])?;
+                    return Ok(());
+                }
+            };
+            let content_encoding = request.header(upload::CONTENT_ENCODING_HEADER);
+            let Some(name) = upload::stored_name(&name, content_encoding) else {
+                request.into_response(415, Some("Unsupported content encoding"), &[])?;
+                return Ok(());
+            };
+            let verifier = UploadVerifier::from_headers(
+                request.header(upload::SHA256_HEADER),
+                request.header(upload::CRC32_HEADER),
+            );
+            let Ok(verifier) = verifier else {
+                request.into_response(400, Some("Invalid checksum header"), &[])?;
+                return Ok(());
+            };
+
+            let mut sessions = sessions1.lock().unwrap();
+            let mut ender = ender1.lock().unwrap();
+            let upload = match ender.storage.begin_upload(&name) {
+                Ok((upload, _)) => upload,
+                Err(StorageLibraryError::InvalidName) => {
+                    request.into_response(400, Some("Invalid name"), &[])?;
+                    return Ok(());
+                }
+                Err(err) => return Err(format!("{err:?}").into()),
+            };
+
+            let thumbnails = ThumbnailExtractor::new(Encoding::from_file_name(&name));
+            let (session, dropped) = sessions.open(upload, size, verifier, thumbnails);
+            let status = session.status();
+            for upload in dropped {
+                info!("Dropping the upload session for {}", upload.name());
+                if let Err(err) = ender.storage.abort_upload(upload) {
+                    error!("{err:?}");
+                }
+            }
+            info!("Upload session {} for {name}, size: {size:?}", status.id);
+            respond_json(request, &serde_json::to_string(&status)?)
+        })
+        .unwrap();
+
+    let ender1 = ender.clone();
+    let job1 = job.clone();
+    let sessions1 = sessions.clone();
+    server
+        .fn_handler("/files/upload/chunk", Method::Put, move |mut request| {
+            let Some(id) = query_parameter(request.uri(), "id").and_then(|id| id.parse().ok())
+            else {
+                request.into_response(400, Some("Missing id"), &[])?;
+                return Ok(());
+            };
+            if job1.lock().unwrap().is_running() {
+                request.into_response(409, Some("A print is running"), &[])?;
+                return Ok(());
+            }
+            let range = request
+                .header(upload::CONTENT_RANGE_HEADER)
+                .and_then(ContentRange::parse);
+            let Some(range) = range else {
+                request.into_response(400, Some("Missing or invalid Content-Range"), &[])?;
+                return Ok(());
+            };
+            if request.content_len() != Some(u64::from(range.num_bytes())) {
+                request.into_response(400, Some("Content-Range doesn't match the body"), &[])?;
+                return Ok(());
+            }
+
+            let mut sessions = sessions1.lock().unwrap();
+            let Some(session) = sessions.get_mut(id) else {
+                request.into_response(404, Some("No such upload session"), &[])?;
+                return Ok(());
+            };
+            session.last_active = Instant::now();
+            if range
+                .total
+                .is_some_and(|total| session.size.is_some_and(|size| size != total))
+            {
+                request.into_response(400, Some("Content-Range doesn't match the size"), &[])?;
+                return Ok(());
+            }
+            session.size = session.size.or(range.total);
+            // The client has to continue where the card says, which it can
+            // look up with the status this answers with.
+            if range.start != session.received {
+                let status = serde_json::to_string(&session.status())?;
+                request
+                    .into_response(416, None, &[("Content-Type", "application/json")])?
+                    .write_all(status.as_bytes())?;
+                return Ok(());
+            }
+
+            let Some(mut chunk_state) = session.chunk_state.take() else {
+                request.into_response(409, Some("A chunk is being received"), &[])?;
+                return Ok(());
+            };
+            let upload = session.upload.clone();
+            // Others can look at the session while the chunk arrives, the
+            // card stays locked.
+            drop(sessions);
+
+            let mut ender = ender1.lock().unwrap();
+            let ender2 = ender.deref_mut();
+            let mut watchdog = ender2.driver.watch_current_task().unwrap();
+            let writer = match ender2.storage.resume_upload(&upload) {
+                Ok(writer) => writer,
+                Err(err) => {
+                    drop(watchdog);
+                    drop(ender);
+                    if let Some(session) = sessions1.lock().unwrap().get_mut(id) {
+                        session.chunk_state = Some(chunk_state);
+                    }
+                    return Err(format!("{err:?}").into());
+                }
+            };
+            let received = receive_body(
+                &mut request,
+                writer,
+                &mut chunk_state.verifier,
+                &mut chunk_state.thumbnails,
+                &mut watchdog,
+            );
+            drop(watchdog);
+            drop(ender);
+
+            let mut sessions = sessions1.lock().unwrap();
+            match received {
+                Ok(num_received) => {
+                    // Deleted along with its file if it was cancelled or
+                    // timed out meanwhile.
+                    let Some(session) = sessions.get_mut(id) else {
+                        request.into_response(404, Some("No such upload session"), &[])?;
+                        return Ok(());
+                    };
+                    session.received += num_received as u32;
+                    session.chunk_state = Some(chunk_state);
+                    session.last_active = Instant::now();
+                    if num_received as u32 != range.num_bytes() {
+                        info!("Chunk of upload session {id} ended early");
+                    }
+                    respond_json(request, &serde_json::to_string(&session.status())?)
+                }
+                // What made it to the card is unknown, so the upload can't
+                // continue.
+                Err(err) => {
+                    error!("Upload session {id} failed: {err}");
+                    if let Some(session) = sessions.remove(id) {
+                        drop(sessions);
+                        if let Err(err) =
+                            ender1.lock().unwrap().storage.abort_upload(session.upload)
+                        {
+                            error!("{err:?}");
+                        }
+                    }
+                    request.into_response(500, Some(&err), &[])?;
+                    Ok(())
+                }
+            }
+        })
+        .unwrap();
+
+    let sessions1 = sessions.clone();
+    server
+        .fn_handler("/files/upload/status", Method::Get, move |request| {
+            let Some(id) = query_parameter(request.uri(), "id").and_then(|id| id.parse().ok())
+            else {
+                request.into_response(400, Some("Missing id"), &[])?;
+                return Ok(());
+            };
+            let status = sessions1.lock().unwrap().get_mut(id).map(|session| {
+                session.last_active = Instant::now();
+                session.status()
+            });
+            match status {
+                Some(status) => respond_json(request, &serde_json::to_string(&status)?),
+                None => {
+                    request.into_response(404, Some("No such upload session"), &[])?;
+                    Ok(())
+                }
+            }
+        })
+        .unwrap();
+
+    let ender1 = ender.clone();
+    let job1 = job.clone();
+    let sessions1 = sessions.clone();
+    server
+        .fn_handler("/files/upload/finish", Method::Post, move |request| {
+            let Some(id) = query_parameter(request.uri(), "id").and_then(|id| id.parse().ok())
+            else {
+                request.into_response(400, Some("Missing id"), &[])?;
+                return Ok(());
+            };
+            if job1.lock().unwrap().is_running() {
+                request.into_response(409, Some("A print is running"), &[])?;
+                return Ok(());
+            }
+            let mut sessions = sessions1.lock().unwrap();
+            match sessions.get_mut(id) {
+                None => {
+                    request.into_response(404, Some("No such upload session"), &[])?;
+                    return Ok(());
+                }
+                Some(session) if session.chunk_state.is_none() => {
+                    request.into_response(409, Some("A chunk is being received"), &[])?;
+                    return Ok(());
+                }
+                Some(session) if !session.is_complete() => {
+                    session.last_active = Instant::now();
+                    request.into_response(409, Some("Upload incomplete"), &[])?;
+                    return Ok(());
+                }
+                Some(_) => {}
+            }
+            let mut session = sessions.remove(id).unwrap();
+            let chunk_state = session.chunk_state.take().unwrap();
+
+            let mut ender = ender1.lock().unwrap();
+            let ender2 = ender.deref_mut();
+            let name = session.upload.name().to_string();
+            // Which chunk was off can't be told, so the whole upload goes.
+            if let Err(err) = chunk_state.verifier.verify() {
+                error!("Upload of {name} failed: {err:?}");
+                if let Err(err) = ender2.storage.abort_upload(session.upload) {
+                    error!("{err:?}");
+                }
+                request.into_response(422, Some(&format!("{err:?}")), &[])?;
+                return Ok(());
+            }
+            attach_thumbnail(
+                &mut ender2.storage,
+                &mut session.upload,
+                chunk_state.thumbnails,
+            );
+            ender2
+                .storage
+                .commit_upload(session.upload)
+                .map_err(|err| format!("{err:?}"))?;
+            forget_replaced_checkpoint(&mut ender2.recovery, &name)?;
+
+            let throughput = Throughput::new(session.received as usize, session.started.elapsed());
+            info!("Uploaded {name} in chunks: {throughput:?}");
+            let report = UploadReport { name, throughput };
+            respond_json(request, &serde_json::to_string(&report)?)
+        })
+        .unwrap();
+
+    let ender1 = ender.clone();
+    let job1 = job.clone();
+    let sessions1 = sessions;
+    server
+        .fn_handler("/files/upload/cancel", Method::Post, move |request| {
+            let Some(id) = query_parameter(request.uri(), "id").and_then(|id| id.parse().ok())
+            else {
+                request.into_response(400, Some("Missing id"), &[])?;
+                return Ok(());
+            };
+            if job1.lock().unwrap().is_running() {
+                request.into_response(409, Some("A print is running"), &[])?;
+                return Ok(());
+            }
+            let Some(session) = sessions1.lock().unwrap().remove(id) else {
+                request.into_response(404, Some("No such upload session"), &[])?;
+                return Ok(());
+            };
+            ender1
+                .lock()
+                .unwrap()
+                .storage
+                .abort_upload(session.upload)
+                .map_err(|err| format!("{err:?}"))?;
+            request.into_ok_response()?;
+            Ok(())
+        })
+        .unwrap();
+}
+
+/// `GET /job/recover` shows where an interrupted print would continue,
+/// `POST /job/recover` continues it.
+fn recover_handlers<B: BlockDev>(
+    ender: &Arc<Mutex<Ender<B>>>,
+    job: &Arc<Mutex<Job>>,
+    sd_status: &Arc<Mutex<Option<SdPrintStatus>>>,
+    server: &mut EspHttpServer,
+) {
+    let ender1 = ender.clone();
+    server
+        .fn_handler("/job/recover", Method::Get, move |request| {
+            let checkpoint = ender1
+                .lock()
+                .unwrap()
+                .recovery
+                .load()
+                .map_err(|err| format!("{err:?}"))?;
+            match checkpoint {
+                Some(checkpoint) => respond_json(request, &serde_json::to_string(&checkpoint)?),
+                None => {
+                    request.into_response(404, Some("Nothing to recover"), &[])?;
+                    Ok(())
+                }
+            }
+        })
+        .unwrap();
+
+    let ender1 = ender.clone();
+    let job1 = job.clone();
+    let sd_status1 = sd_status.clone();
+    server
+        .fn_handler("/job/recover", Method::Post, move |request| {
+            if job1.lock().unwrap().is_running() {
+                request.into_response(409, Some("A print is running"), &[])?;
+                return Ok(());
+            }
+            if is_printing_from_sd(&sd_status1) {
+                request.into_response(409, Some(PRINTING_FROM_SD), &[])?;
+                return Ok(());
+            }
+
+            let checkpoint = ender1
+                .lock()
+                .unwrap()
+                .recovery
+                .load()
+                .map_err(|err| format!("{err:?}"))?;
+            let Some(checkpoint) = checkpoint else {
+                request.into_response(404, Some("Nothing to recover"), &[])?;
+                return Ok(());
+            };
+
+            if let Err(err) = job1.lock().unwrap().start(&checkpoint.file) {
+                request.into_response(409, Some(&format!("{err:?}")), &[])?;
+                return Ok(());
+            }
+            info!("Recovering print from byte {}", checkpoint.offset);
+            spawn_print(&ender1, &job1, checkpoint.file.clone(), Some(checkpoint));
+            Ok(())
+        })
+        .unwrap();
+}
+
+fn spawn_print<B: BlockDev>(
+    ender: &Arc<Mutex<Ender<B>>>,
+    job: &Arc<Mutex<Job>>,
+    file: String,
+    resume_from: Option<Checkpoint>,
+) {
+    let ender1 = ender.clone();
+    let job1 = job.clone();
+
+    let builder = thread::Builder::new();
+    builder
+        .stack_size(10000)
+        .spawn(move || {
+            let result = print_file(&ender1, &job1, &file, resume_from);
+            match &result {
+                Ok(_) => info!("File printed"),
+                Err(err) => error!("{err}"),
+            };
+            job1.lock().unwrap().finish(&result);
+        })
+        .unwrap();
+}
+
+fn print_file<B: BlockDev>(
+    ender1: &Arc<Mutex<Ender<B>>>,
+    job: &Arc<Mutex<Job>>,
+    file: &str,
+    resume_from: Option<Checkpoint>,
+) -> Result<(), String> {
+    let mut ender = ender1.lock().unwrap();
+    let ender2 = ender.deref_mut();
+    let mut watchdog = ender2.driver.watch_current_task().unwrap();
+    ender2.serial.clear().map_err(|err| format!("{err:?}"))?;
+    ender2
+        .serial
+        .reset_line_number(&mut watchdog)
+        .map_err(|err| format!("{err:?}"))?;
+
+    let mut reader = ender2
+        .storage
+        .get_reader(file)
+        .map_err(|err| format!("{err:?}"))?;
+    let file_size = reader.file_size_in_bytes();
+    let encoding = Encoding::from_file_name(file);
+    let mut stream_state = StreamState::default();
+    let mut parked = None;
+
+    let offset = match &resume_from {
+        Some(checkpoint) => {
+            if checkpoint.file_size != file_size {
+                return Err("The model file changed since the print was interrupted".into());
+            }
+            // Compressed files can't be seeked into, their lines before the
+            // offset are skipped instead.
+            if encoding == Encoding::Identity {
+                reader
+                    .seek(checkpoint.offset)
+                    .map_err(|err| format!("{err:?}"))?;
+            }
+            stream_state = checkpoint.stream_state;
+            checkpoint.offset
+        }
+        None => {
+            ender2.recovery.clear().map_err(|err| format!("{err:?}"))?;
+            0
+        }
+    };
+    let mut last_checkpoint = Instant::now();
+    job.lock().unwrap().progress = Progress::start(file_size);
+
+    // The card is read on a thread of its own, so the next lines are ready
+    // as soon as Marlin has room for them.
+    let seeked_to = match encoding {
+        Encoding::Identity => offset,
+        _ => 0,
+    };
+    thread::scope(|scope| -> Result<(), String> {
+        // Made in here, so that the prefetcher stops with the lines dropped on
+        // every way out, or the scope would wait on it forever.
+        let (prefetcher, mut lines) = read_ahead(seeked_to, encoding);
+        thread::Builder::new()
+            .stack_size(8192)
+            .spawn_scoped(scope, move || prefetcher.run(reader))
+            .map_err(|err| format!("{err:?}"))?;
+        while lines.offset() < offset {
+            if lines
+                .read_line()
+                .map_err(|err| format!("{err:?}"))?
+                .is_none()
+            {
+                break;
+            }
+            watchdog.feed().unwrap();
+        }
+        // Only once the lines before the checkpoint are skipped, so the
+        // nozzle doesn't wait on them at the print.
+        if let Some(checkpoint) = &resume_from {
+            checkpoint
+                .restore(&mut ender2.serial, &mut watchdog)
+                .map_err(|err| format!("{err:?}"))?;
+        }
+
+        loop {
+            // Progress goes by the stored file, which is all there is to go
+            // by for a compressed one.
+            let source_offset = lines.source_offset();
+            let Some(line) = lines.read_line().map_err(|err| format!("{err:?}"))? else {
+                break;
+            };
+            let bytes_done = match encoding {
+                Encoding::Identity => source_offset + line.len() as u32,
+                _ => source_offset,
+            };
+            loop {
+                let state = job.lock().unwrap().state();
+                let settings = || job.lock().unwrap().settings.clone();
+
+                match state {
+                    JobState::Paused => {
+                        if parked.is_none() {
+                            info!("Pausing print");
+                            parked = Some(
+                                park(&mut ender2.serial, &mut watchdog, &settings(), stream_state)
+                                    .map_err(|err| format!("{err:?}"))?,
+                            );
+                        }
+                        ender2
+                            .serial
+                            .poll(&mut watchdog)
+                            .map_err(|err| format!("{err:?}"))?;
+                        watchdog.feed().unwrap();
+                        FreeRtos::delay_ms(100);
+                    }
+                    JobState::Halted => {
+                        return Err("Print aborted by an emergency stop".into());
+                    }
+                    JobState::Cancelling => {
+                        info!("Cancelling print");
+                        run_cancel_sequence(&mut ender2.serial, &mut watchdog, &settings())
+                            .map_err(|err| format!("{err:?}"))?;
+                        return Ok(());
+                    }
+                    _ => {
+                        if let Some(parked) = parked.take() {
+                            info!("Resuming print");
+                            parked
+                                .restore(&mut ender2.serial, &mut watchdog, &settings())
+                                .map_err(|err| format!("{err:?}"))?;
+                        }
+                        ender2
+                            .serial
+                            .poll(&mut watchdog)
+                            .map_err(|err| format!("{err:?}"))?;
+                        break;
+                    }
+                }
+            }
+
+            stream_state.observe(line);
+            let lcd_update = {
+                let mut job = job.lock().unwrap();
+                job.progress.observe(line, bytes_done);
+                job.progress.lcd_update()
+            };
+            if let Some(lcd_update) = lcd_update {
+                info!("{lcd_update}");
+                ender2
+                    .serial
+                    .write(lcd_update, &mut watchdog)
+                    .map_err(|err| format!("{err:?}"))?;
+            }
+            // info!("Line from SD card: {}", line);
+            ender2
+                .serial
+                .write(line, &mut watchdog)
+                .map_err(|err| format!("{err:?}"))?;
+
+            if last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL {
+                let checkpoint = Checkpoint::take(
+                    &mut ender2.serial,
+                    &mut watchdog,
+                    file,
+                    lines.offset(),
+                    file_size,
+                    stream_state,
+                )
+                .map_err(|err| format!("{err:?}"))?;
+                // Losing a checkpoint shouldn't stop the print.
+                if let Err(err) = ender2.recovery.save(&checkpoint) {
+                    error!("{err:?}");
+                }
+                last_checkpoint = Instant::now();
+            }
+        }
+        Ok(())
+    })?;
+
+    ender2
+        .serial
+        .drain(&mut watchdog)
+        .map_err(|err| format!("{err:?}"))?;
+
+    ender2.recovery.clear().map_err(|err| format!("{err:?}"))
 }
@@ -0,0 +1,247 @@
+//! The part of Moonraker's JSON-RPC API dashboards such as Mainsail and
+//! Fluidd need to show and control a print, spoken over a WebSocket. There
+//! are no status notifications, clients poll `printer.objects.query`.
+
+use library::LibraryFile;
+use marlin::temperature::Heater;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    job::{Job, JobState},
+    temperature::TemperatureSample,
+};
+
+/// How long a request can be, which is plenty for the methods there are.
+pub const MAX_REQUEST_LEN: usize = 1024;
+
+/// The Moonraker version answered with, which clients check to be recent
+/// enough.
+const MOONRAKER_VERSION: &str = "v0.8.0";
+const API_VERSION: [u32; 3] = [1, 4, 0];
+
+#[derive(Debug, Deserialize)]
+pub struct RpcRequest {
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+    /// Left out for notifications, which get no answer.
+    pub id: Option<Value>,
+}
+
+#[derive(Debug)]
+pub struct RpcError {
+    pub code: i32,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn parse_error() -> Self {
+        Self {
+            code: -32700,
+            message: "Parse error".into(),
+        }
+    }
+
+    pub fn invalid_request(message: &str) -> Self {
+        Self {
+            code: -32600,
+            message: message.into(),
+        }
+    }
+
+    pub fn method_not_found(method: &str) -> Self {
+        Self {
+            code: -32601,
+            message: format!("Method not found: {method}"),
+        }
+    }
+
+    pub fn invalid_params(message: &str) -> Self {
+        Self {
+            code: -32602,
+            message: message.into(),
+        }
+    }
+
+    /// Moonraker answers with HTTP status codes for requests that are fine
+    /// but can't be done, such as pausing when nothing is printing.
+    pub fn refused(message: impl Into<String>) -> Self {
+        Self {
+            code: 400,
+            message: message.into(),
+        }
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self {
+            code: 404,
+            message: message.into(),
+        }
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self {
+            code: 500,
+            message: message.into(),
+        }
+    }
+}
+
+/// The answer to the request with `id`.
+pub fn response(id: &Value, result: Result<Value, RpcError>) -> String {
+    match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "result": result, "id": id}),
+        Err(err) => json!({
+            "jsonrpc": "2.0",
+            "error": {"code": err.code, "message": err.message},
+            "id": id,
+        }),
+    }
+    .to_string()
+}
+
+/// A string parameter of a request.
+pub fn string_param<'a>(params: &'a Value, name: &str) -> Result<&'a str, RpcError> {
+    params
+        .get(name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| RpcError::invalid_params(&format!("Missing {name}")))
+}
+
+pub fn server_info() -> Value {
+    json!({
+        "klippy_connected": true,
+        "klippy_state": "ready",
+        "components": ["file_manager"],
+        "failed_components": [],
+        "registered_directories": ["gcodes"],
+        "warnings": [],
+        "moonraker_version": MOONRAKER_VERSION,
+        "api_version": API_VERSION,
+        "api_version_string": API_VERSION.map(|part| part.to_string()).join("."),
+    })
+}
+
+/// The answer to `printer.objects.query`, whose `objects` map the names of
+/// the printer objects wanted to the list of their attributes wanted, or to
+/// `null` for all of them. Objects that don't exist here are left out.
+pub fn objects_query(
+    params: &Value,
+    job: &Job,
+    temperatures: Option<&TemperatureSample>,
+    eventtime: f64,
+) -> Result<Value, RpcError> {
+    let objects = params
+        .get("objects")
+        .and_then(Value::as_object)
+        .ok_or_else(|| RpcError::invalid_params("Missing objects"))?;
+
+    let mut status = Map::new();
+    for (name, attributes) in objects {
+        let Some(Value::Object(mut object)) = printer_object(name, job, temperatures) else {
+            continue;
+        };
+        if let Some(attributes) = attributes.as_array() {
+            object.retain(|key, _| attributes.iter().any(|attribute| attribute == key));
+        }
+        status.insert(name.clone(), Value::Object(object));
+    }
+    Ok(json!({"eventtime": eventtime, "status": status}))
+}
+
+/// The status of one of Klipper's printer objects.
+fn printer_object(
+    name: &str,
+    job: &Job,
+    temperatures: Option<&TemperatureSample>,
+) -> Option<Value> {
+    let heater = |heater: Option<&Heater>| {
+        json!({
+            "temperature": heater.map_or(0.0, |heater| heater.current),
+            "target": heater.map_or(0.0, |heater| heater.target),
+        })
+    };
+    let temperatures = temperatures.map(|sample| &sample.temperatures);
+    let progress = job.progress.report();
+    let is_running = job.is_running();
+    let print_duration = if is_running {
+        progress.elapsed_seconds
+    } else {
+        0.0
+    };
+
+    match name {
+        "webhooks" => Some(json!({"state": "ready", "state_message": "Printer is ready"})),
+        "extruder" => Some(heater(
+            temperatures.and_then(|temperatures| temperatures.hotends.first()),
+        )),
+        "heater_bed" => Some(heater(
+            temperatures.and_then(|temperatures| temperatures.bed.as_ref()),
+        )),
+        "print_stats" => Some(json!({
+            "filename": job.file().unwrap_or_default(),
+            "state": print_state(job.state()),
+            "print_duration": print_duration,
+            "total_duration": print_duration,
+            "message": "",
+        })),
+        "virtual_sdcard" => Some(json!({
+            "is_active": job.state() == JobState::Printing,
+            "progress": if is_running { progress.percent / 100.0 } else { 0.0 },
+        })),
+        "display_status" => Some(json!({
+            "progress": if is_running { progress.percent / 100.0 } else { 0.0 },
+            "message": "",
+        })),
+        _ => None,
+    }
+}
+
+/// Klipper's name for the state of the print.
+fn print_state(state: JobState) -> &'static str {
+    match state {
+        JobState::Idle => "standby",
+        JobState::Printing => "printing",
+        JobState::Paused => "paused",
+        JobState::Cancelling => "cancelled",
+        JobState::Finished => "complete",
+        JobState::Failed | JobState::Halted => "error",
+    }
+}
+
+/// The answer to `server.files.list`, with the library as the `gcodes`
+/// root.
+pub fn file_list(files: &[LibraryFile]) -> Value {
+    files
+        .iter()
+        .map(|file| {
+            json!({
+                "path": file.name,
+                "modified": unix_seconds(&file.modified).unwrap_or_default(),
+                "size": file.size,
+                "permissions": "rw",
+            })
+        })
+        .collect()
+}
+
+/// Seconds since 1970 of a `YYYY-MM-DDTHH:MM:SS` timestamp, as the card's
+/// are given.
+fn unix_seconds(timestamp: &str) -> Option<i64> {
+    let (date, time) = timestamp.split_once('T')?;
+    let mut date = date.split('-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    let mut time = time.split(':').map(|part| part.parse::<i64>().ok());
+    let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next()??);
+
+    // Howard Hinnant's `days_from_civil`, with years starting in March so
+    // that the leap day comes last.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    Some(days * 86_400 + hours * 3_600 + minutes * 60 + seconds)
+}
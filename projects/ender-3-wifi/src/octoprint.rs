@@ -0,0 +1,266 @@
+//! The part of OctoPrint's REST API slicers use to send a file to a printer,
+//! so PrusaSlicer, SuperSlicer and Cura can upload to the library and start
+//! printing without a desktop app in between.
+
+use embedded_svc::io::{Error, ErrorKind, ErrorType, Read};
+use multipart::{Event, MultipartError, MultipartParser};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::job::{Job, JobError, JobState};
+
+/// The key slicers are set up with, without which the API is off.
+pub const API_KEY: Option<&str> = option_env!("OCTOPRINT_API_KEY");
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+/// Where OctoPrint also takes the key, for clients that can't set headers.
+pub const API_KEY_PARAMETER: &str = "apikey";
+
+/// The OctoPrint version answered with, which slicers check to be recent
+/// enough.
+const SERVER_VERSION: &str = "1.9.0";
+const API_VERSION: &str = "0.1";
+/// How long the value of a form field other than the file can be.
+const MAX_FIELD_LEN: usize = 64;
+
+/// Whether the key a request came with is the one set up.
+pub fn is_authorized(header: Option<&str>, parameter: Option<&str>) -> bool {
+    let Some(api_key) = API_KEY else {
+        return false;
+    };
+    header.or(parameter) == Some(api_key)
+}
+
+pub fn version() -> Value {
+    json!({
+        "api": API_VERSION,
+        "server": SERVER_VERSION,
+        "text": format!("OctoPrint {SERVER_VERSION}"),
+    })
+}
+
+/// The answer to `GET /api/job`.
+pub fn job_status(job: &Job) -> Value {
+    let state = match job.state() {
+        JobState::Idle | JobState::Finished | JobState::Failed => "Operational",
+        JobState::Printing => "Printing",
+        JobState::Paused => "Paused",
+        JobState::Cancelling => "Cancelling",
+        JobState::Halted => "Error",
+    };
+    let progress = job.progress.report();
+    let is_running = job.is_running();
+    json!({
+        "job": {
+            "file": {
+                "name": job.file(),
+                "origin": "local",
+            },
+        },
+        "progress": {
+            "completion": is_running.then_some(progress.percent),
+            "printTime": is_running.then_some(progress.elapsed_seconds as u32),
+            "printTimeLeft": progress.eta_seconds.filter(|_| is_running).map(|eta| eta as u32),
+        },
+        "state": state,
+    })
+}
+
+/// The body of `POST /api/job`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub enum JobCommand {
+    Start,
+    Cancel,
+    Restart,
+    Pause {
+        #[serde(default)]
+        action: PauseAction,
+    },
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PauseAction {
+    #[default]
+    Toggle,
+    Pause,
+    Resume,
+}
+
+impl PauseAction {
+    pub fn apply(&self, job: &mut Job) -> Result<(), JobError> {
+        match self {
+            Self::Pause => job.pause(),
+            Self::Resume => job.resume(),
+            Self::Toggle if job.state() == JobState::Paused => job.resume(),
+            Self::Toggle => job.pause(),
+        }
+    }
+}
+
+/// The answer to `POST /api/files/local`.
+pub fn upload_result(name: &str) -> Value {
+    json!({
+        "done": true,
+        "files": {
+            "local": {
+                "name": name,
+                "origin": "local",
+            },
+        },
+    })
+}
+
+/// The fields of `POST /api/files/local` other than the file.
+#[derive(Debug, Default)]
+pub struct UploadForm {
+    /// Whether the file is to be the one `start` prints.
+    pub select: bool,
+    pub print: bool,
+}
+
+impl UploadForm {
+    fn set(&mut self, name: &str, value: &str) {
+        let is_true = value.trim() == "true";
+        match name {
+            "select" => self.select = is_true,
+            "print" => self.print = is_true,
+            // There are no folders to upload to.
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FormError<E> {
+    Read(E),
+    Multipart(MultipartError),
+}
+
+impl<E: Error> Error for FormError<E> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Read(err) => err.kind(),
+            Self::Multipart(_) => ErrorKind::InvalidData,
+        }
+    }
+}
+
+/// The part of the form being read.
+enum Field {
+    File,
+    Other(String),
+}
+
+/// Reads the file out of an upload form as it arrives, taking note of the
+/// fields around it.
+pub struct FormFileReader<R> {
+    body: R,
+    parser: MultipartParser,
+    form: UploadForm,
+    field: Option<Field>,
+    value: String,
+    file_name: Option<String>,
+    file_ended: bool,
+    /// What arrived of the file but wasn't read yet.
+    pending: Vec<u8>,
+    pending_start: usize,
+}
+
+impl<R: Read> FormFileReader<R> {
+    pub fn new(body: R, boundary: &str) -> Self {
+        Self {
+            body,
+            parser: MultipartParser::new(boundary),
+            form: UploadForm::default(),
+            field: None,
+            value: String::new(),
+            file_name: None,
+            file_ended: false,
+            pending: Vec::new(),
+            pending_start: 0,
+        }
+    }
+
+    /// Reads up to the file, returning its name without the folders the
+    /// client had it in, or `None` if the form has no file.
+    pub fn next_file(&mut self) -> Result<Option<String>, FormError<R::Error>> {
+        while self.file_name.is_none() {
+            if !self.receive()? {
+                return Ok(None);
+            }
+        }
+        let file_name = self.file_name.as_deref().unwrap_or_default();
+        let base_name = file_name.rsplit(['/', '\\']).next().unwrap_or(file_name);
+        Ok(Some(base_name.to_string()))
+    }
+
+    /// Reads the rest of the form once the file was read.
+    pub fn finish(mut self) -> Result<UploadForm, FormError<R::Error>> {
+        while self.receive()? {}
+        Ok(self.form)
+    }
+
+    /// Reads the next piece of the body, returning whether there was one.
+    fn receive(&mut self) -> Result<bool, FormError<R::Error>> {
+        if self.parser.is_done() {
+            return Ok(false);
+        }
+        let buffer = &mut [0u8; 1024];
+        let num_read = self.body.read(buffer).map_err(FormError::Read)?;
+        if num_read == 0 {
+            self.parser.finish().map_err(FormError::Multipart)?;
+            return Ok(false);
+        }
+
+        let pushed = self.parser.push(&buffer[..num_read], |event| match event {
+            // Only the first file is kept.
+            Event::Begin(part) if part.file_name.is_some() && self.file_name.is_none() => {
+                self.file_name = part.file_name.clone();
+                self.field = Some(Field::File);
+            }
+            Event::Begin(part) => {
+                self.value.clear();
+                self.field = Some(Field::Other(part.name.clone()));
+            }
+            Event::Data(data) => match &self.field {
+                Some(Field::File) => self.pending.extend_from_slice(data),
+                Some(Field::Other(_)) => {
+                    let room = MAX_FIELD_LEN.saturating_sub(self.value.len());
+                    let data = &data[..data.len().min(room)];
+                    self.value.push_str(&String::from_utf8_lossy(data));
+                }
+                None => {}
+            },
+            Event::End => match self.field.take() {
+                Some(Field::File) => self.file_ended = true,
+                Some(Field::Other(name)) => self.form.set(&name, &self.value),
+                None => {}
+            },
+        });
+        pushed.map_err(FormError::Multipart)?;
+        Ok(true)
+    }
+}
+
+impl<R: Read> ErrorType for FormFileReader<R> {
+    type Error = FormError<R::Error>;
+}
+
+impl<R: Read> Read for FormFileReader<R> {
+    /// Reads the file, ending where its part of the form ends.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        while self.pending_start == self.pending.len() {
+            self.pending.clear();
+            self.pending_start = 0;
+            if self.file_ended || !self.receive()? {
+                return Ok(0);
+            }
+        }
+        let pending = &self.pending[self.pending_start..];
+        let len = pending.len().min(buf.len());
+        buf[..len].copy_from_slice(&pending[..len]);
+        self.pending_start += len;
+        Ok(len)
+    }
+}
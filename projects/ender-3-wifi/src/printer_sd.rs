@@ -0,0 +1,178 @@
+use embedded_hal::watchdog::Watchdog;
+use gcode::Line;
+use serde::Serialize;
+
+use crate::serial::{SerialLineError, SerialWrapper};
+
+/// Seconds between the SD print progress reports asked for with `M27 S`.
+pub const SD_REPORT_INTERVAL_SECONDS: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum UploadState {
+    Uploading,
+    Done,
+    Failed,
+}
+
+/// How far the upload to the printer's card got.
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadProgress {
+    pub name: String,
+    pub state: UploadState,
+    pub bytes_done: usize,
+    pub total_bytes: usize,
+}
+
+impl UploadProgress {
+    pub fn new(name: &str, total_bytes: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            state: UploadState::Uploading,
+            bytes_done: 0,
+            total_bytes,
+        }
+    }
+}
+
+/// Makes Marlin write every following line to `name` on its card instead of
+/// running it, until [`finish_upload`].
+pub fn begin_upload(
+    serial: &mut SerialWrapper,
+    watchdog: &mut impl Watchdog,
+    name: &str,
+) -> Result<(), SerialLineError> {
+    let response = serial.query(format!("M28 {name}"), watchdog)?;
+    expect_response(&response, "Writing to file")
+}
+
+/// Writes a line of the uploaded file, numbered and checksummed like any
+/// other line.
+pub fn upload_line(
+    serial: &mut SerialWrapper,
+    watchdog: &mut impl Watchdog,
+    line: &str,
+) -> Result<(), SerialLineError> {
+    // Marlin would stop saving at an `M29` in the file.
+    let ends_upload = Line::parse(line)
+        .ok()
+        .and_then(|line| line.command())
+        .is_some_and(|command| command.is("M29"));
+    if ends_upload {
+        return Ok(());
+    }
+    serial.write(line, watchdog)
+}
+
+pub fn finish_upload(
+    serial: &mut SerialWrapper,
+    watchdog: &mut impl Watchdog,
+) -> Result<(), SerialLineError> {
+    let response = serial.query("M29", watchdog)?;
+    expect_response(&response, "Done saving file")
+}
+
+/// Selects `name` and starts printing it from the printer's card, so the
+/// print goes on without the ESP.
+///
+/// Marlin reports the progress of the print on its own from then on.
+pub fn start_print(
+    serial: &mut SerialWrapper,
+    watchdog: &mut impl Watchdog,
+    name: &str,
+) -> Result<(), SerialLineError> {
+    let response = serial.query(format!("M23 {name}"), watchdog)?;
+    expect_response(&response, "File selected")?;
+    serial.query("M24", watchdog)?;
+    serial.query(format!("M27 S{SD_REPORT_INTERVAL_SECONDS}"), watchdog)?;
+    Ok(())
+}
+
+/// A file on the printer's card, as listed by `M20 L`.
+#[derive(Debug, Clone, Serialize)]
+pub struct SdFile {
+    /// The 8.3 path to use with `M23` and `M30`.
+    pub name: String,
+    pub size: u32,
+    /// Only listed by firmware built with `LONG_FILENAME_HOST_SUPPORT`.
+    pub long_name: Option<String>,
+}
+
+impl SdFile {
+    /// Parses a line like `MODEL.GCO 1234 My Model.gcode`.
+    fn parse(line: &str) -> Option<Self> {
+        let mut parts = line.trim().splitn(3, ' ');
+        let name = parts.next()?.to_string();
+        let size = parts.next()?.parse().ok()?;
+        let long_name = parts
+            .next()
+            .map(str::trim)
+            .filter(|long_name| !long_name.is_empty())
+            .map(String::from);
+        Some(Self {
+            name,
+            size,
+            long_name,
+        })
+    }
+}
+
+pub fn list_files(
+    serial: &mut SerialWrapper,
+    watchdog: &mut impl Watchdog,
+) -> Result<Vec<SdFile>, SerialLineError> {
+    let response = serial.query("M20 L", watchdog)?;
+    let Some(begin) = response.iter().position(|line| line == "Begin file list") else {
+        return Err(SerialLineError::UnexpectedResponse);
+    };
+    Ok(response[begin + 1..]
+        .iter()
+        .take_while(|line| *line != "End file list")
+        .filter_map(|line| SdFile::parse(line))
+        .collect())
+}
+
+pub fn delete_file(
+    serial: &mut SerialWrapper,
+    watchdog: &mut impl Watchdog,
+    name: &str,
+) -> Result<(), SerialLineError> {
+    let response = serial.query(format!("M30 {name}"), watchdog)?;
+    expect_response(&response, "File deleted")
+}
+
+/// Where a print running from the printer's card is, from `M27`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "state")]
+pub enum SdPrintStatus {
+    Printing { bytes_done: u32, total_bytes: u32 },
+    NotPrinting,
+    Done,
+}
+
+impl SdPrintStatus {
+    /// Parses `SD printing byte 123/4567`, `Not SD printing` and
+    /// `Done printing file`.
+    pub fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        if let Some(bytes) = line.strip_prefix("SD printing byte ") {
+            let (bytes_done, total_bytes) = bytes.split_once('/')?;
+            return Some(Self::Printing {
+                bytes_done: bytes_done.parse().ok()?,
+                total_bytes: total_bytes.parse().ok()?,
+            });
+        }
+        match line {
+            "Not SD printing" => Some(Self::NotPrinting),
+            "Done printing file" => Some(Self::Done),
+            _ => None,
+        }
+    }
+}
+
+fn expect_response(response: &[String], expected: &str) -> Result<(), SerialLineError> {
+    if response.iter().any(|line| line.contains(expected)) {
+        Ok(())
+    } else {
+        Err(SerialLineError::UnexpectedResponse)
+    }
+}
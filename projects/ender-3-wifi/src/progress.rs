@@ -0,0 +1,133 @@
+use std::time::Instant;
+
+use gcode::Line;
+use serde::Serialize;
+
+/// Follows how far a print got, from the bytes streamed so far and the
+/// markers slicers leave in the G-code.
+#[derive(Debug, Default)]
+pub struct Progress {
+    started: Option<Instant>,
+    bytes_done: u32,
+    file_size: u32,
+    layer: Option<u32>,
+    total_layers: Option<u32>,
+    slicer_total_seconds: Option<f32>,
+    slicer_elapsed_seconds: Option<f32>,
+    slicer_percent: Option<f32>,
+    slicer_remaining_seconds: Option<f32>,
+    /// Whether the file keeps the LCD up to date with its own `M73`s.
+    has_m73: bool,
+    lcd_percent: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProgressReport {
+    pub percent: f32,
+    pub layer: Option<u32>,
+    pub total_layers: Option<u32>,
+    pub elapsed_seconds: f32,
+    pub slicer_remaining_seconds: Option<f32>,
+    /// The slicer's estimate corrected by how far off it was so far, or a
+    /// guess from the bytes streamed if there is none.
+    pub eta_seconds: Option<f32>,
+}
+
+impl Progress {
+    pub fn start(file_size: u32) -> Self {
+        Self {
+            started: Some(Instant::now()),
+            file_size,
+            ..Default::default()
+        }
+    }
+
+    /// Picks up Cura's `;LAYER:`, `;LAYER_COUNT:`, `;TIME:` and
+    /// `;TIME_ELAPSED:`, PrusaSlicer's `;LAYER_CHANGE` and `M73`.
+    ///
+    /// `bytes_done` counts everything up to and including `line`.
+    pub fn observe(&mut self, line: &str, bytes_done: u32) {
+        self.bytes_done = bytes_done;
+        let Ok(line) = Line::parse(line) else {
+            return;
+        };
+
+        for comment in line.comments() {
+            let comment = comment.trim();
+            let (key, value) = comment.split_once(':').unwrap_or((comment, ""));
+            match key {
+                // Cura counts from 0.
+                "LAYER" => self.layer = value.parse::<u32>().ok().map(|layer| layer + 1),
+                "LAYER_CHANGE" => self.layer = Some(self.layer.map_or(1, |layer| layer + 1)),
+                "LAYER_COUNT" => self.total_layers = value.parse().ok(),
+                "TIME" => self.slicer_total_seconds = value.parse().ok(),
+                "TIME_ELAPSED" => self.slicer_elapsed_seconds = value.parse().ok(),
+                _ => {}
+            }
+        }
+
+        if line.command().is_some_and(|command| command.is("M73")) {
+            self.has_m73 = true;
+            let parameter = |letter| line.parameter(letter).and_then(|word| word.value::<f32>());
+            if let Some(percent) = parameter('P') {
+                self.slicer_percent = Some(percent);
+            }
+            if let Some(minutes) = parameter('R') {
+                self.slicer_remaining_seconds = Some(minutes * 60.0);
+            }
+        }
+    }
+
+    pub fn report(&self) -> ProgressReport {
+        let elapsed_seconds = self
+            .started
+            .map_or(0.0, |started| started.elapsed().as_secs_f32());
+        let percent = self.slicer_percent.unwrap_or_else(|| {
+            if self.file_size == 0 {
+                0.0
+            } else {
+                100.0 * self.bytes_done as f32 / self.file_size as f32
+            }
+        });
+
+        let slicer_remaining_seconds = self
+            .slicer_remaining_seconds
+            .or_else(|| Some((self.slicer_total_seconds? - self.slicer_elapsed_seconds?).max(0.0)));
+        let eta_seconds = match (slicer_remaining_seconds, self.slicer_elapsed_seconds) {
+            (Some(remaining), Some(slicer_elapsed)) if slicer_elapsed > 0.0 => {
+                Some(remaining * elapsed_seconds / slicer_elapsed)
+            }
+            (Some(remaining), _) => Some(remaining),
+            (None, _) if percent > 0.0 => Some(elapsed_seconds * (100.0 - percent) / percent),
+            (None, _) => None,
+        };
+
+        ProgressReport {
+            percent,
+            layer: self.layer,
+            total_layers: self.total_layers,
+            elapsed_seconds,
+            slicer_remaining_seconds,
+            eta_seconds,
+        }
+    }
+
+    /// An `M73` for the printer's LCD each time the percentage moves on,
+    /// unless the file sends its own.
+    pub fn lcd_update(&mut self) -> Option<String> {
+        if self.has_m73 {
+            return None;
+        }
+        let report = self.report();
+        let percent = report.percent as u32;
+        if self.lcd_percent == Some(percent) {
+            return None;
+        }
+        self.lcd_percent = Some(percent);
+
+        Some(match report.eta_seconds {
+            Some(eta) => format!("M73 P{percent} R{}", (eta / 60.0).round() as u32),
+            None => format!("M73 P{percent}"),
+        })
+    }
+}
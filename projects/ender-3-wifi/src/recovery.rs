@@ -0,0 +1,144 @@
+use std::time::Duration;
+
+use embedded_hal::watchdog::Watchdog;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    job::{Position, StreamState},
+    serial::{SerialLineError, SerialWrapper},
+};
+
+/// How often a running print saves how far it got.
+pub const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+const NVS_NAMESPACE: &str = "recovery";
+const CHECKPOINT_KEY: &str = "checkpoint";
+const MAX_CHECKPOINT_LEN: usize = 320;
+
+/// How far a print got, saved often enough to pick it up again after a
+/// brownout.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// The name of the file in the library.
+    pub file: String,
+    /// Where the model file continues after the last line Marlin
+    /// acknowledged.
+    pub offset: u32,
+    /// Tells whether the model file was replaced since.
+    pub file_size: u32,
+    pub position: Position,
+    pub stream_state: StreamState,
+}
+
+impl Checkpoint {
+    /// Waits until Marlin acknowledged every line written so far, so that
+    /// `offset` and the position it reports belong together.
+    pub fn take(
+        serial: &mut SerialWrapper,
+        watchdog: &mut impl Watchdog,
+        file: &str,
+        offset: u32,
+        file_size: u32,
+        stream_state: StreamState,
+    ) -> Result<Self, SerialLineError> {
+        Ok(Self {
+            file: file.to_string(),
+            offset,
+            file_size,
+            position: Position::query(serial, watchdog)?,
+            stream_state,
+        })
+    }
+
+    /// Reheats, homes X and Y and moves back to where the print was.
+    ///
+    /// Z can't be homed without the nozzle running into the print, so Marlin
+    /// is told the saved height instead.
+    pub fn restore(
+        &self,
+        serial: &mut SerialWrapper,
+        watchdog: &mut impl Watchdog,
+    ) -> Result<(), SerialLineError> {
+        let Position { x, y, z, e } = self.position;
+        let mut lines = Vec::new();
+        if let Some(bed) = self.stream_state.bed_target() {
+            lines.push(format!("M190 S{bed}"));
+        }
+        if let Some(hotend) = self.stream_state.hotend_target() {
+            lines.push(format!("M109 S{hotend}"));
+        }
+        lines.extend([
+            format!("G92 Z{z}"),
+            "G91".to_string(),
+            "G1 Z2 F600".to_string(),
+            "G90".to_string(),
+            "G28 X Y".to_string(),
+            format!("G1 X{x} Y{y} F6000"),
+            format!("G1 Z{z} F600"),
+            format!("G92 E{e}"),
+        ]);
+
+        for line in lines {
+            serial.write(line, watchdog)?;
+        }
+        self.stream_state.restore(serial, watchdog)?;
+        serial.drain(watchdog)
+    }
+}
+
+/// Keeps the latest [`Checkpoint`] in NVS, where it survives a power loss.
+pub struct CheckpointStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl CheckpointStore {
+    pub fn new(partition: EspDefaultNvsPartition) -> Self {
+        let nvs = EspNvs::new(partition, NVS_NAMESPACE, true).expect("Should open nvs namespace");
+        Self { nvs }
+    }
+
+    pub fn save(&mut self, checkpoint: &Checkpoint) -> Result<(), RecoveryError> {
+        let bytes = postcard::to_allocvec(checkpoint).map_err(|err| {
+            error!("{err:#?}");
+            RecoveryError::Serialize
+        })?;
+        self.nvs.set_raw(CHECKPOINT_KEY, &bytes).map_err(|err| {
+            error!("{err:#?}");
+            RecoveryError::Nvs
+        })?;
+        Ok(())
+    }
+
+    pub fn load(&self) -> Result<Option<Checkpoint>, RecoveryError> {
+        let mut buffer = [0u8; MAX_CHECKPOINT_LEN];
+        let bytes = self
+            .nvs
+            .get_raw(CHECKPOINT_KEY, &mut buffer)
+            .map_err(|err| {
+                error!("{err:#?}");
+                RecoveryError::Nvs
+            })?;
+        bytes.map(postcard::from_bytes).transpose().map_err(|err| {
+            error!("{err:#?}");
+            RecoveryError::Deserialize
+        })
+    }
+
+    /// Forgets the checkpoint once there is nothing left to recover.
+    pub fn clear(&mut self) -> Result<(), RecoveryError> {
+        self.nvs.remove(CHECKPOINT_KEY).map_err(|err| {
+            error!("{err:#?}");
+            RecoveryError::Nvs
+        })?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum RecoveryError {
+    Nvs,
+    Serialize,
+    Deserialize,
+}
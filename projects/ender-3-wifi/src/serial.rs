@@ -1,20 +1,32 @@
-use std::time::{Duration, Instant};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
 
-use embedded_hal::{serial::Write, watchdog::Watchdog};
 use esp_idf_hal::{
     delay::FreeRtos,
     gpio::{AnyIOPin, InputPin, OutputPin},
     peripheral::Peripheral,
     uart::{config, Uart, UartDriver},
 };
-use log::{error, info};
+use esp_idf_sys::{uart_port_t, uart_write_bytes, EspError, ESP_FAIL};
+use marlin::{temperature::Temperatures, Monitor};
+
+use crate::{console::Console, printer_sd::SdPrintStatus, temperature::TemperatureHistory};
+
+pub use marlin::SerialLineError;
+
+pub type SerialWrapper<'a> = marlin::SerialWrapper<UartDriver<'a>, FreeRtos>;
 
 pub fn create_serial<'a, UART: Uart>(
     uart: impl Peripheral<P = UART> + 'static,
     tx: impl Peripheral<P = impl OutputPin> + 'static,
     rx: impl Peripheral<P = impl InputPin> + 'static,
     config: &config::Config,
-) -> SerialWrapper<'a> {
+    temperatures: Arc<Mutex<TemperatureHistory>>,
+    console: Arc<Mutex<Console>>,
+    sd_status: Arc<Mutex<Option<SdPrintStatus>>>,
+) -> (SerialWrapper<'a>, EmergencyStop) {
     let uart = esp_idf_hal::uart::UartDriver::new(
         uart,
         tx,
@@ -24,176 +36,83 @@ pub fn create_serial<'a, UART: Uart>(
         config,
     )
     .expect("Should create uart");
-
-    SerialWrapper {
-        uart,
-        read_line_buffer: String::new(),
-    }
+    let port = uart.port();
+
+    let mut serial = SerialWrapper::new(uart, FreeRtos);
+    serial.set_monitor(PrinterMonitor {
+        temperatures,
+        console,
+        sd_status,
+    });
+    let emergency_stop = EmergencyStop {
+        port,
+        halted: serial.halt_flag(),
+    };
+    (serial, emergency_stop)
 }
 
-pub struct SerialWrapper<'a> {
-    uart: UartDriver<'a>,
-    read_line_buffer: String,
+/// Sends `M112` straight to the UART, next to the [`SerialWrapper`], so it
+/// reaches the printer while a print holds the lock on the wrapper.
+///
+/// Marlin only acts on it right away when built with `EMERGENCY_PARSER`,
+/// otherwise it waits its turn in the command buffer.
+#[derive(Clone)]
+pub struct EmergencyStop {
+    port: uart_port_t,
+    halted: Arc<AtomicBool>,
 }
 
-impl<'a> SerialWrapper<'a> {
-    pub fn write(
-        &mut self,
-        line: impl AsRef<str>,
-        watchdog: &mut impl Watchdog,
-    ) -> Result<(), SerialLineError> {
-        if line.as_ref().is_empty() || line.as_ref().trim().starts_with(';') {
-            return Ok(());
-        }
- 
-        let report_temperatures = "M114\n";
-        self.inner_write(report_temperatures).unwrap();
-
-        loop {
-            let Some(line) = self.read().unwrap() else {
-                continue;
-            };
-
-            if line == "echo:busy: processing\n" {
-                self.feed_watch_dog(watchdog);
-            }
-
-            if line.contains("X:")
-                && line.contains("Y:")
-                && line.contains("Z:")
-                && line.contains("E:")
-            {
-                let ok = self.read().unwrap().unwrap();
-                debug_assert_eq!(ok, "ok\n");
-                break;
-            }
-        }
-        loop {
-            self.inner_write(&line).unwrap();
-            self.feed_watch_dog(watchdog);
-            let response = self.read().unwrap();
-            let is_unknown_command = response
-                .as_ref()
-                .is_some_and(|s| s.starts_with("echo:Unknown command:"));
-            if !is_unknown_command {
-                break;
-            }
-            info!("unknown command: {response:?}");
-            let ok = self.read().unwrap().unwrap();
-            debug_assert_eq!(ok, "ok\n");
+impl EmergencyStop {
+    /// Halts the [`SerialWrapper`], so a running print gives up, and tells
+    /// Marlin to kill the heaters and motors.
+    pub fn trigger(&self) -> Result<(), EspError> {
+        self.halted.store(true, Ordering::SeqCst);
+
+        // The leading newline ends whatever line the print thread was in the
+        // middle of writing, so Marlin sees `M112` on a line of its own.
+        let command = b"\nM112\n";
+        // SAFETY: `port` is the one the `UartDriver` in the `SerialWrapper`
+        // installed, which stays installed as long as the firmware runs, and
+        // the driver returns an error for one that isn't. `command` is valid
+        // for `command.len()` bytes for the whole call, and ESP-IDF's UART
+        // driver holds the port's transmit lock while it copies them, so they
+        // aren't mixed into a write of the print thread.
+        let written =
+            unsafe { uart_write_bytes(self.port, command.as_ptr().cast(), command.len()) };
+        if written != command.len() as i32 {
+            return Err(EspError::from_infallible::<ESP_FAIL>());
         }
         Ok(())
     }
 
-    fn feed_watch_dog(&self, watchdog: &mut impl Watchdog) {
-        watchdog.feed();
-        FreeRtos::delay_ms(10);
+    /// Lets the [`SerialWrapper`] talk to the printer again.
+    pub fn reset(&self) {
+        self.halted.store(false, Ordering::SeqCst);
     }
+}
 
-    fn inner_write(&mut self, line: impl AsRef<str>) -> Result<(), SerialLineError> {
-        let mut line = line.as_ref();
-        while !line.is_empty() {
-            let remaining_write = match self.uart.remaining_write() {
-                Ok(value) => value,
-                Err(err) => {
-                    error!("{err}");
-                    continue;
-                }
-            };
-
-            let remaining_write = core::cmp::min(remaining_write, line.len());
-            let write_chunk = &line[..remaining_write];
+/// Records temperature and SD print reports and connects the WebSocket
+/// console to the serial port.
+struct PrinterMonitor {
+    temperatures: Arc<Mutex<TemperatureHistory>>,
+    console: Arc<Mutex<Console>>,
+    sd_status: Arc<Mutex<Option<SdPrintStatus>>>,
+}
 
-            let num_written = self
-                .uart
-                .write(write_chunk.as_bytes())
-                .map_err(|err| {
-                    error!("{err:#?}");
-                    SerialLineError::Write
-                })
-                .unwrap_or(0);
+impl Monitor for PrinterMonitor {
+    fn on_response(&mut self, response: &str) {
+        self.console.lock().unwrap().broadcast(response);
 
-            line = &line[num_written..];
+        if let Some(temperatures) = Temperatures::parse(response) {
+            self.temperatures.lock().unwrap().record(temperatures);
         }
 
-        loop {
-            match self.uart.flush() {
-                Ok(_) => break,
-                Err(err) => {
-                    match err {
-                        nb::Error::Other(_) => {
-                            error!("{err:#?}");
-                            return Err(SerialLineError::Write);
-                        }
-                        nb::Error::WouldBlock => continue,
-                    };
-                }
-            }
+        if let Some(status) = SdPrintStatus::parse(response) {
+            *self.sd_status.lock().unwrap() = Some(status);
         }
-
-        Ok(())
     }
 
-    pub fn read(&mut self) -> Result<Option<String>, SerialLineError> {
-        let index_after_newline = match self.read_line_buffer.find('\n') {
-            Some(newline_index) => newline_index + 1,
-            None => {
-                let mut buffer = [0u8; 1000];
-
-                loop {
-                    let remaining_read = self.uart.remaining_read().map_err(|err| {
-                        error!("{err:#?}");
-                        SerialLineError::Read
-                    })?;
-
-                    if remaining_read == 0 {
-                        return Ok(None);
-                    }
-
-                    let remaining_read = core::cmp::min(remaining_read, buffer.len());
-
-                    let num_read =
-                        self.uart
-                            .read(&mut buffer[..remaining_read], 10)
-                            .map_err(|err| {
-                                error!("{err:#?}");
-                                SerialLineError::Read
-                            })?;
-
-                    self.read_line_buffer +=
-                        core::str::from_utf8(&buffer[..num_read]).map_err(|err| {
-                            error!("{err:#?}");
-                            SerialLineError::Utf8Error
-                        })?;
-
-                    if let Some(newline_index) = self.read_line_buffer.find('\n') {
-                        break newline_index + 1;
-                    }
-                }
-            }
-        };
-
-        let (line, rest) = self.read_line_buffer.split_at(index_after_newline);
-        let line = line.to_string();
-        self.read_line_buffer = rest.to_string();
-
-        Ok(Some(line))
+    fn take_commands(&mut self) -> Vec<String> {
+        self.console.lock().unwrap().take_commands()
     }
-
-    pub fn clear(&mut self) -> Result<(), SerialLineError> {
-        self.read_line_buffer.clear();
-        self.uart.clear_rx().map_err(|err| {
-            error!("{err}");
-            SerialLineError::Clear
-        })?;
-        Ok(())
-    }
-}
-
-#[derive(Debug)]
-pub enum SerialLineError {
-    Write,
-    Clear,
-    Read,
-    Utf8Error,
 }
@@ -0,0 +1,66 @@
+use std::{collections::VecDeque, time::Instant};
+
+use marlin::temperature::Temperatures;
+use serde::Serialize;
+
+/// Seconds between the temperature reports Marlin sends on its own (`M155`).
+pub const AUTO_REPORT_INTERVAL_SECONDS: u32 = 2;
+
+/// Ten minutes of auto-reports.
+const HISTORY_LEN: usize = 300;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TemperatureSample {
+    pub seconds_since_boot: f32,
+    #[serde(flatten)]
+    pub temperatures: Temperatures,
+}
+
+/// The latest temperature report and a rolling history of earlier ones.
+pub struct TemperatureHistory {
+    started: Instant,
+    samples: VecDeque<TemperatureSample>,
+}
+
+impl Default for TemperatureHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TemperatureHistory {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            samples: VecDeque::with_capacity(HISTORY_LEN),
+        }
+    }
+
+    pub fn record(&mut self, temperatures: Temperatures) {
+        if self.samples.len() == HISTORY_LEN {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(TemperatureSample {
+            seconds_since_boot: self.started.elapsed().as_secs_f32(),
+            temperatures,
+        });
+    }
+
+    pub fn latest(&self) -> Option<&TemperatureSample> {
+        self.samples.back()
+    }
+
+    pub fn to_json(&self) -> String {
+        #[derive(Serialize)]
+        struct Json<'a> {
+            latest: Option<&'a TemperatureSample>,
+            history: &'a VecDeque<TemperatureSample>,
+        }
+
+        serde_json::to_string(&Json {
+            latest: self.latest(),
+            history: &self.samples,
+        })
+        .expect("Should serialize temperatures")
+    }
+}
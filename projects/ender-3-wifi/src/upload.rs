@@ -0,0 +1,268 @@
+use std::time::{Duration, Instant};
+
+use library::{Encoding, ThumbnailExtractor, Upload};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+pub const SHA256_HEADER: &str = "X-Content-SHA256";
+pub const CRC32_HEADER: &str = "X-Content-CRC32";
+pub const CONTENT_ENCODING_HEADER: &str = "Content-Encoding";
+pub const CONTENT_RANGE_HEADER: &str = "Content-Range";
+
+/// Sessions left alone this long are dropped to make room for new ones.
+pub const SESSION_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+const MAX_SESSIONS: usize = 4;
+
+/// The name an upload is kept under. Compressed files are kept as they were
+/// sent and read by their extension, so one sent with a `Content-Encoding`
+/// gets that encoding's extension unless it has it already. `None` for
+/// encodings that can't be read back.
+pub fn stored_name(name: &str, content_encoding: Option<&str>) -> Option<String> {
+    let Some(content_encoding) = content_encoding else {
+        return Some(name.to_string());
+    };
+    let encoding = Encoding::from_content_encoding(content_encoding)?;
+    if Encoding::from_file_name(name) == encoding {
+        Some(name.to_string())
+    } else {
+        Some(format!("{name}{}", encoding.extension()))
+    }
+}
+
+/// Checks an upload against the checksum the client sent along in
+/// [`SHA256_HEADER`] or [`CRC32_HEADER`], as lower or upper case hex.
+pub enum UploadVerifier {
+    Unchecked,
+    Sha256 {
+        hasher: Sha256,
+        expected: [u8; 32],
+    },
+    Crc32 {
+        hasher: crc32fast::Hasher,
+        expected: u32,
+    },
+}
+
+impl UploadVerifier {
+    pub fn from_headers(
+        sha256: Option<&str>,
+        crc32: Option<&str>,
+    ) -> Result<Self, UploadVerifyError> {
+        if let Some(sha256) = sha256 {
+            let sha256 = sha256.trim();
+            if sha256.len() != 64 || !sha256.is_ascii() {
+                return Err(UploadVerifyError::InvalidHeader);
+            }
+            let mut expected = [0u8; 32];
+            for (byte, hex) in expected.iter_mut().zip(sha256.as_bytes().chunks(2)) {
+                let hex =
+                    core::str::from_utf8(hex).map_err(|_| UploadVerifyError::InvalidHeader)?;
+                *byte =
+                    u8::from_str_radix(hex, 16).map_err(|_| UploadVerifyError::InvalidHeader)?;
+            }
+            return Ok(Self::Sha256 {
+                hasher: Sha256::new(),
+                expected,
+            });
+        }
+        if let Some(crc32) = crc32 {
+            let expected = u32::from_str_radix(crc32.trim(), 16)
+                .map_err(|_| UploadVerifyError::InvalidHeader)?;
+            return Ok(Self::Crc32 {
+                hasher: crc32fast::Hasher::new(),
+                expected,
+            });
+        }
+        Ok(Self::Unchecked)
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Self::Unchecked => {}
+            Self::Sha256 { hasher, .. } => hasher.update(bytes),
+            Self::Crc32 { hasher, .. } => hasher.update(bytes),
+        }
+    }
+
+    pub fn verify(self) -> Result<(), UploadVerifyError> {
+        let matches = match self {
+            Self::Unchecked => true,
+            Self::Sha256 { hasher, expected } => hasher.finalize()[..] == expected,
+            Self::Crc32 { hasher, expected } => hasher.finalize() == expected,
+        };
+        if matches {
+            Ok(())
+        } else {
+            Err(UploadVerifyError::Mismatch)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum UploadVerifyError {
+    InvalidHeader,
+    Mismatch,
+}
+
+/// How fast an upload went, reported once it is done.
+#[derive(Debug, Clone, Serialize)]
+pub struct Throughput {
+    pub bytes: usize,
+    pub seconds: f32,
+    pub bytes_per_second: f32,
+}
+
+/// What an upload reports once it is done.
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadReport {
+    /// Differs from the name asked for if the file was sent compressed.
+    pub name: String,
+    #[serde(flatten)]
+    pub throughput: Throughput,
+}
+
+impl Throughput {
+    pub fn new(bytes: usize, elapsed: Duration) -> Self {
+        let seconds = elapsed.as_secs_f32();
+        Self {
+            bytes,
+            seconds,
+            bytes_per_second: bytes as f32 / seconds.max(f32::EPSILON),
+        }
+    }
+}
+
+/// The part of a file a chunk holds, from `Content-Range: bytes 0-1023/4096`
+/// or `bytes 0-1023/*`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentRange {
+    pub start: u32,
+    /// Inclusive, as in the header.
+    pub end: u32,
+    pub total: Option<u32>,
+}
+
+impl ContentRange {
+    pub fn parse(value: &str) -> Option<Self> {
+        let (range, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+        let (start, end) = range.split_once('-')?;
+        let range = Self {
+            start: start.trim().parse().ok()?,
+            end: end.trim().parse().ok()?,
+            total: match total.trim() {
+                "*" => None,
+                total => Some(total.parse().ok()?),
+            },
+        };
+        let fits = match range.total {
+            Some(total) => range.end < total,
+            None => true,
+        };
+        (range.start <= range.end && fits).then_some(range)
+    }
+
+    pub fn num_bytes(&self) -> u32 {
+        self.end - self.start + 1
+    }
+}
+
+/// An upload sent in chunks, which can be picked up again where the card
+/// says it stopped after the connection dropped.
+pub struct UploadSession {
+    pub id: u32,
+    pub upload: Upload,
+    /// Bytes on the card so far, all of them seen by `chunk_state`.
+    pub received: u32,
+    pub size: Option<u32>,
+    /// Taken by the chunk being received, so that the sessions aren't
+    /// locked while it arrives.
+    pub chunk_state: Option<ChunkState>,
+    pub started: Instant,
+    pub last_active: Instant,
+}
+
+/// What each chunk of an upload goes through on its way to the card.
+pub struct ChunkState {
+    pub verifier: UploadVerifier,
+    pub thumbnails: ThumbnailExtractor,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadSessionStatus {
+    pub id: u32,
+    pub name: String,
+    pub received: u32,
+    pub size: Option<u32>,
+}
+
+impl UploadSession {
+    pub fn status(&self) -> UploadSessionStatus {
+        UploadSessionStatus {
+            id: self.id,
+            name: self.upload.name().to_string(),
+            received: self.received,
+            size: self.size,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        match self.size {
+            Some(size) => self.received == size,
+            None => true,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct UploadSessions {
+    sessions: Vec<UploadSession>,
+    next_id: u32,
+}
+
+impl UploadSessions {
+    /// Adds a session for `upload`, handing back the uploads of sessions
+    /// that timed out, or of the oldest one if there are too many, for
+    /// their files to be deleted.
+    pub fn open(
+        &mut self,
+        upload: Upload,
+        size: Option<u32>,
+        verifier: UploadVerifier,
+        thumbnails: ThumbnailExtractor,
+    ) -> (&UploadSession, Vec<Upload>) {
+        let (expired, sessions): (Vec<_>, Vec<_>) = self
+            .sessions
+            .drain(..)
+            .partition(|session| session.last_active.elapsed() >= SESSION_TIMEOUT);
+        self.sessions = sessions;
+        let mut dropped: Vec<Upload> = expired.into_iter().map(|session| session.upload).collect();
+        if self.sessions.len() >= MAX_SESSIONS {
+            dropped.push(self.sessions.remove(0).upload);
+        }
+
+        self.next_id = self.next_id.wrapping_add(1);
+        let now = Instant::now();
+        self.sessions.push(UploadSession {
+            id: self.next_id,
+            upload,
+            received: 0,
+            size,
+            chunk_state: Some(ChunkState {
+                verifier,
+                thumbnails,
+            }),
+            started: now,
+            last_active: now,
+        });
+        (self.sessions.last().unwrap(), dropped)
+    }
+
+    pub fn get_mut(&mut self, id: u32) -> Option<&mut UploadSession> {
+        self.sessions.iter_mut().find(|session| session.id == id)
+    }
+
+    pub fn remove(&mut self, id: u32) -> Option<UploadSession> {
+        let index = self.sessions.iter().position(|session| session.id == id)?;
+        Some(self.sessions.remove(index))
+    }
+}